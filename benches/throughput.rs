@@ -0,0 +1,92 @@
+//! How long a thread pool takes to finish a fixed batch of jobs, for
+//! different numbers of workers.
+//!
+//! Run with `cargo bench`.
+
+use std::{hint::black_box, thread, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_tutorial_webserver::{ThreadPool, ThreadPoolBuilder};
+
+const POOL_SIZES: [usize; 4] = [1, 2, 4, 8];
+
+// Jobs submitted per batch, the same for every pool size
+const CPU_JOBS: usize = 64;
+const SLEEP_JOBS: usize = 32;
+// Many tiny jobs, so time spent handing them out dominates
+const TINY_JOBS: usize = 10_000;
+
+// Iterations of busy work in each CPU-bound job
+const CPU_WORK: u64 = 20_000;
+// How long each sleep-bound job waits, like one blocked on I/O
+const SLEEP: Duration = Duration::from_millis(1);
+
+// Busy work the compiler can't skip
+fn spin(iterations: u64) -> u64 {
+    (0..iterations).fold(0, |acc: u64, i| {
+        black_box(acc.wrapping_mul(31).wrapping_add(i))
+    })
+}
+
+// Submit jobs copies of job to the pool, then wait for them
+// all to finish
+fn run_batch(pool: &ThreadPool, jobs: usize, job: fn()) {
+    for _ in 0..jobs {
+        pool.execute(job);
+    }
+    pool.join();
+}
+
+fn cpu_bound(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu_bound");
+    for size in POOL_SIZES {
+        let pool = ThreadPool::new(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &pool, |b, pool| {
+            b.iter(|| {
+                run_batch(pool, CPU_JOBS, || {
+                    black_box(spin(CPU_WORK));
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+fn sleep_bound(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sleep_bound");
+    // Each batch takes milliseconds, so fewer samples will do
+    group.sample_size(20);
+    for size in POOL_SIZES {
+        let pool = ThreadPool::new(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &pool, |b, pool| {
+            b.iter(|| run_batch(pool, SLEEP_JOBS, || thread::sleep(SLEEP)));
+        });
+    }
+    group.finish();
+}
+
+fn work_stealing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("work_stealing");
+    for work_stealing in [false, true] {
+        let pool = ThreadPoolBuilder::new()
+            .size(4)
+            .work_stealing(work_stealing)
+            .build()
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(work_stealing),
+            &pool,
+            |b, pool| {
+                b.iter(|| {
+                    run_batch(pool, TINY_JOBS, || {
+                        black_box(spin(1));
+                    })
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cpu_bound, sleep_bound, work_stealing);
+criterion_main!(benches);
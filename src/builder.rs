@@ -0,0 +1,410 @@
+//! Fluent configuration of a ThreadPool.
+
+use std::{num::NonZeroUsize, sync::Arc, thread, time::Duration};
+
+use crate::{
+    default_logger, IdlePolicy, JobQueue, Logger, PoolCreationError, ThreadPool, WorkerHooks,
+};
+
+// Prefix of worker thread names unless another is given
+const DEFAULT_THREAD_NAME_PREFIX: &str = "worker";
+
+/// A builder for configuring and creating a [ThreadPool].
+///
+/// Unlike [ThreadPool::new], which is always given a size, the builder starts with one
+/// worker per CPU available, or 1 if that can't be found. Every other setting left
+/// alone matches [ThreadPool::new]: an unbounded queue, threads named `worker-<id>`
+/// which never time out, and the [default_logger].
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::ThreadPoolBuilder;
+/// let my_thread_pool = ThreadPoolBuilder::new()
+///     .size(4)
+///     .queue_capacity(64)
+///     .thread_name_prefix("http")
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ThreadPoolBuilder {
+    pub(crate) size: usize,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) thread_name_prefix: String,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) logger: Logger,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) min_workers: usize,
+    pub(crate) work_stealing: bool,
+    pub(crate) max_queue_age: Option<Duration>,
+    pub(crate) job_queue: Option<Arc<dyn JobQueue>>,
+    pub(crate) hooks: WorkerHooks,
+}
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            queue_capacity: None,
+            thread_name_prefix: String::from(DEFAULT_THREAD_NAME_PREFIX),
+            stack_size: None,
+            logger: default_logger(),
+            idle_timeout: None,
+            min_workers: 1,
+            work_stealing: false,
+            max_queue_age: None,
+            job_queue: None,
+            hooks: WorkerHooks::default(),
+        }
+    }
+}
+impl ThreadPoolBuilder {
+    /// Create a new builder with the default settings.
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Set the number of threads in the pool.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.size = size;
+        self
+    }
+
+    /// Bound the job queue to `queue_capacity` waiting jobs.
+    ///
+    /// See [ThreadPool::with_capacity] for how a full queue behaves.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(queue_capacity);
+        self
+    }
+
+    /// Set the prefix of the worker threads' names, which are `<prefix>-<id>`.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> ThreadPoolBuilder {
+        self.thread_name_prefix = String::from(prefix);
+        self
+    }
+
+    /// Give each worker thread a stack of `bytes` bytes, for jobs which recurse too
+    /// deeply for the default.
+    ///
+    /// Without this, workers get the same stack as any thread spawned by the standard
+    /// library: 2 MiB unless the `RUST_MIN_STACK` environment variable says otherwise.
+    /// The OS may round the size up to a whole number of pages, and sizes below the
+    /// platform's minimum (16 KiB on Linux) are raised to it. Workers added by
+    /// [ThreadPool::resize] get the same size.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Send the pool's log messages to `logger`.
+    pub fn logger(mut self, logger: Logger) -> ThreadPoolBuilder {
+        self.logger = logger;
+        self
+    }
+
+    /// Let workers which sit idle for longer than `idle_timeout` exit, as long as at
+    /// least [ThreadPoolBuilder::min_workers] are left.
+    ///
+    /// Saves resources during quiet periods. The pool doesn't grow back by itself; use
+    /// [ThreadPool::resize] for that.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> ThreadPoolBuilder {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Set how many workers an [idle timeout](ThreadPoolBuilder::idle_timeout) leaves
+    /// behind. Defaults to 1, and is never less than 1 so jobs always get run.
+    pub fn min_workers(mut self, min_workers: usize) -> ThreadPoolBuilder {
+        self.min_workers = min_workers.max(1);
+        self
+    }
+
+    /// Give each worker its own share of the job queue, stealing from the others' when
+    /// its own runs dry.
+    ///
+    /// Workers then rarely wait on each other to take a job, which speeds up pools
+    /// running lots of short jobs. The catch is that
+    /// [priorities](ThreadPool::execute_with_priority) are only followed within each
+    /// worker's share, not across the whole pool.
+    pub fn work_stealing(mut self, work_stealing: bool) -> ThreadPoolBuilder {
+        self.work_stealing = work_stealing;
+        self
+    }
+
+    /// Drop jobs which wait in the queue for longer than `max_queue_age` instead of
+    /// running them.
+    ///
+    /// Useful when a job is only worth running soon after it's sent, like answering a
+    /// client which will have given up waiting by then. Dropped jobs are logged as
+    /// warnings and count as finished, so [ThreadPool::join] doesn't wait on them.
+    pub fn max_queue_age(mut self, max_queue_age: Duration) -> ThreadPoolBuilder {
+        self.max_queue_age = Some(max_queue_age);
+        self
+    }
+
+    /// Keep waiting jobs in `job_queue`, letting it pick the order they run in.
+    ///
+    /// See [ThreadPool::with_queue].
+    pub fn job_queue(mut self, job_queue: Arc<dyn JobQueue>) -> ThreadPoolBuilder {
+        self.job_queue = Some(job_queue);
+        self
+    }
+
+    /// Run `on_start` inside each worker's thread, passing it the worker's id, before
+    /// the worker takes any jobs.
+    ///
+    /// Useful for setting up thread-local state, such as a buffer or a connection, which
+    /// the worker's jobs then share. Workers added by [ThreadPool::resize] run it too. A
+    /// panic in the hook is logged as a warning, and the worker carries on.
+    pub fn on_worker_start(
+        mut self,
+        on_start: Box<dyn Fn(usize) + Send + Sync>,
+    ) -> ThreadPoolBuilder {
+        self.hooks.on_start = Some(Arc::from(on_start));
+        self
+    }
+
+    /// Run `on_stop` inside each worker's thread, passing it the worker's id, as the
+    /// worker leaves the pool.
+    ///
+    /// Runs whether the worker is shut down, [resized](ThreadPool::resize) away or
+    /// retires after an [idle timeout](ThreadPoolBuilder::idle_timeout).
+    pub fn on_worker_stop(
+        mut self,
+        on_stop: Box<dyn Fn(usize) + Send + Sync>,
+    ) -> ThreadPoolBuilder {
+        self.hooks.on_stop = Some(Arc::from(on_stop));
+        self
+    }
+
+    // The idle timeout settings, if there's a timeout
+    pub(crate) fn idle_policy(&self) -> Option<IdlePolicy> {
+        self.idle_timeout.map(|timeout| IdlePolicy {
+            timeout,
+            min_workers: self.min_workers,
+        })
+    }
+
+    /// Create the configured ThreadPool.
+    ///
+    /// Returns [PoolCreationError] if the size is 0 or a worker thread can't be spawned,
+    /// like [ThreadPool::build].
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.size == 0 {
+            return Err(PoolCreationError::InvalidSize { given_size: 0 });
+        }
+        ThreadPool::gen_thread_pool(self, ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogLevel;
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn build_configured() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&messages);
+        let tp = ThreadPoolBuilder::new()
+            .size(3)
+            .queue_capacity(1)
+            .thread_name_prefix("http")
+            .logger(Arc::new(move |level, message| {
+                if level == LogLevel::Info && message.starts_with("Shutting down") {
+                    captured.lock().unwrap().push(String::from(message));
+                }
+            }))
+            .build()
+            .unwrap();
+
+        assert_eq!(3, tp.idle_count());
+        let name = tp.execute_returning(|| thread::current().name().map(String::from));
+        assert!(name.recv().unwrap().unwrap().starts_with("http-"));
+
+        drop(tp);
+        assert_eq!(3, messages.lock().unwrap().len());
+    }
+
+    #[test]
+    fn build_bounded() {
+        let tp = ThreadPoolBuilder::new()
+            .size(1)
+            .queue_capacity(0)
+            .build()
+            .unwrap();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        // The only worker is busy and nothing can wait
+        assert!(tp.try_execute(|| ()).is_err());
+        drop(release_tx);
+    }
+
+    #[test]
+    fn build_defaults() {
+        let tp = ThreadPoolBuilder::new().size(1).build().unwrap();
+        let name = tp.execute_returning(|| thread::current().name().map(String::from));
+        assert_eq!(Some(String::from("worker-0")), name.recv().unwrap());
+        assert!(ThreadPoolBuilder::new().build().unwrap().idle_count() > 0);
+    }
+
+    #[test]
+    fn build_idle_timeout() {
+        let tp = ThreadPoolBuilder::new()
+            .size(4)
+            .idle_timeout(Duration::from_millis(20))
+            .min_workers(2)
+            .build()
+            .unwrap();
+        assert_eq!(4, tp.metrics().workers);
+
+        // Quiet for long enough that the surplus workers retire
+        let start = std::time::Instant::now();
+        while tp.metrics().workers > 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "workers didn't retire"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(2, tp.metrics().workers);
+        assert_eq!(2, tp.worker_stats().len());
+
+        // The workers left still run jobs
+        assert_eq!(4, tp.execute_returning(|| 2 + 2).recv().unwrap());
+    }
+
+    #[test]
+    fn build_max_queue_age() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&warnings);
+        let tp = ThreadPoolBuilder::new()
+            .size(1)
+            .max_queue_age(Duration::from_millis(50))
+            .logger(Arc::new(move |level, message| {
+                if level == LogLevel::Warn {
+                    captured.lock().unwrap().push(String::from(message));
+                }
+            }))
+            .build()
+            .unwrap();
+        let (ran_tx, ran_rx) = std::sync::mpsc::channel();
+        tp.execute(|| thread::sleep(Duration::from_millis(150)));
+        tp.execute(move || ran_tx.send(()).unwrap());
+        tp.join();
+
+        // The second job waited behind the first for too long
+        assert!(ran_rx.try_recv().is_err());
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].starts_with("Worker 0 dropped job which waited"));
+        assert_eq!(0, tp.queued_jobs());
+    }
+
+    #[test]
+    fn build_max_queue_age_job_queue() {
+        let tp = ThreadPoolBuilder::new()
+            .size(1)
+            .max_queue_age(Duration::from_millis(50))
+            .job_queue(Arc::new(crate::FifoQueue::new()))
+            .logger(Arc::new(|_, _| ()))
+            .build()
+            .unwrap();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        // The old job has waited too long by the time the worker's
+        // free, and the new one hasn't
+        let (ran_tx, ran_rx) = std::sync::mpsc::channel();
+        let old = ran_tx.clone();
+        tp.execute(move || old.send("old").unwrap());
+        thread::sleep(Duration::from_millis(100));
+        tp.execute(move || ran_tx.send("new").unwrap());
+        drop(release_tx);
+        tp.join();
+        assert_eq!(vec!["new"], ran_rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn build_worker_hooks() {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        let (start_log, stop_log) = (Arc::clone(&started), Arc::clone(&stopped));
+        let mut tp = ThreadPoolBuilder::new()
+            .size(3)
+            .on_worker_start(Box::new(move |id| start_log.lock().unwrap().push(id)))
+            .on_worker_stop(Box::new(move |id| stop_log.lock().unwrap().push(id)))
+            .build()
+            .unwrap();
+        tp.resize(4);
+        tp.shutdown_timeout(Duration::MAX).unwrap();
+
+        let mut started = started.lock().unwrap().clone();
+        started.sort();
+        assert_eq!(vec![0, 1, 2, 3], started);
+        let mut stopped = stopped.lock().unwrap().clone();
+        stopped.sort();
+        assert_eq!(vec![0, 1, 2, 3], stopped);
+    }
+
+    #[test]
+    fn build_worker_start_thread_local() {
+        thread_local! {
+            static WORKER_ID: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+        }
+        let tp = ThreadPoolBuilder::new()
+            .size(1)
+            .on_worker_start(Box::new(|id| WORKER_ID.with(|cell| cell.set(Some(id)))))
+            .build()
+            .unwrap();
+        let id = tp.execute_returning(|| WORKER_ID.with(|cell| cell.get()));
+        assert_eq!(Some(0), id.recv().unwrap());
+    }
+
+    #[test]
+    fn build_stack_size() {
+        // Uses at least 4 MiB of stack, more than the 2 MiB
+        // default, though an unoptimised build needs more
+        fn recurse(depth: usize) -> usize {
+            let frame = std::hint::black_box([1u8; 1024]);
+            match depth {
+                0 => usize::from(frame[0]),
+                _ => recurse(depth - 1) + usize::from(std::hint::black_box(frame)[1]),
+            }
+        }
+        let mut tp = ThreadPoolBuilder::new()
+            .size(1)
+            .stack_size(32 * 1024 * 1024)
+            .build()
+            .unwrap();
+        let depth = tp.execute_returning(|| recurse(4096));
+        assert_eq!(4097, depth.recv().unwrap());
+
+        // Workers added later get the same stack
+        tp.resize(2);
+        tp.resize(1);
+        let depth = tp.execute_returning(|| recurse(4096));
+        assert_eq!(4097, depth.recv().unwrap());
+    }
+
+    #[test]
+    fn build_0() {
+        match ThreadPoolBuilder::new().size(0).build() {
+            Err(pce) => assert_eq!(0, pce.given_size()),
+            Ok(_) => panic!("Should have returned error"),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Submitting jobs to a ThreadPool without owning it.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::{queue::PriorityQueue, Counters, ExecuteError, ThreadPool};
+
+/// A cheap, cloneable handle for giving jobs to a [ThreadPool] from anywhere.
+///
+/// Created with [ThreadPool::handle]. Handles can be cloned and sent to other threads
+/// freely, since they only share the pool's queue.
+///
+/// A handle doesn't keep the pool alive. The pool still shuts down when it's dropped,
+/// whether or not handles are left, and after that every job given to a handle is
+/// handed back. Drop handles once they're done with, so nothing goes on trying to use
+/// a pool which has gone.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+/// use rust_tutorial_webserver::ThreadPool;
+/// let my_thread_pool = ThreadPool::new(2);
+/// let handle = my_thread_pool.handle();
+/// thread::spawn(move || {
+///     if handle.execute(|| println!("from another thread")).is_err() {
+///         println!("the pool has shut down");
+///     }
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct PoolHandle {
+    queue: Arc<PriorityQueue>,
+    counters: Arc<Counters>,
+}
+impl PoolHandle {
+    pub(crate) fn new(queue: Arc<PriorityQueue>, counters: Arc<Counters>) -> PoolHandle {
+        PoolHandle { queue, counters }
+    }
+
+    /// Give a closure to one of the pool's workers, like [ThreadPool::execute].
+    ///
+    /// Blocks while the pool's queue is full. Hands the closure back if the pool has
+    /// shut down.
+    pub fn execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.job_submitted();
+        self.queue
+            .push_job(ThreadPool::DEFAULT_PRIORITY, f)
+            .inspect_err(|_| self.counters.job_rejected())
+    }
+
+    /// Try to give a closure to one of the pool's workers without blocking, like
+    /// [ThreadPool::try_execute_checked].
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.job_submitted();
+        self.queue
+            .try_push(ThreadPool::DEFAULT_PRIORITY, f)
+            .inspect_err(|_| self.counters.job_rejected())
+    }
+
+    /// Get the number of workers currently executing a job, like
+    /// [ThreadPool::active_count].
+    pub fn active_count(&self) -> usize {
+        self.counters.active.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of workers currently waiting for a job, like
+    /// [ThreadPool::idle_count].
+    pub fn idle_count(&self) -> usize {
+        self.counters
+            .live
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.active_count())
+    }
+
+    /// Get the number of jobs waiting in the queue for a free worker, like
+    /// [ThreadPool::queued_jobs].
+    pub fn queued_jobs(&self) -> usize {
+        self.counters.queued.load(Ordering::SeqCst)
+    }
+
+    /// Get the pool's counters in the Prometheus text exposition format, like
+    /// [ThreadPool::metrics_prometheus].
+    pub fn metrics_prometheus(&self) -> String {
+        self.counters.prometheus()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    #[test]
+    fn handle_across_threads() {
+        let tp = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        let submitters: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = tp.handle();
+                let count = Arc::clone(&count);
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let count = Arc::clone(&count);
+                        handle
+                            .execute(move || {
+                                count.fetch_add(1, Ordering::SeqCst);
+                            })
+                            .ok()
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for submitter in submitters {
+            submitter.join().unwrap();
+        }
+
+        // join() waits for jobs given to handles too
+        tp.join();
+        assert_eq!(100, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn handle_counts() {
+        let tp = ThreadPool::new(2);
+        let handle = tp.handle();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = std::sync::Mutex::new(release_rx);
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.lock().unwrap().recv();
+        });
+        started_rx.recv().unwrap();
+        assert_eq!(1, handle.active_count());
+        assert_eq!(1, handle.idle_count());
+        assert_eq!(0, handle.queued_jobs());
+        drop(release_tx);
+    }
+
+    #[test]
+    fn handle_outlives_pool() {
+        let tp = ThreadPool::new(1);
+        let handle = tp.handle();
+        // Dropping the pool doesn't wait on the handle
+        drop(tp);
+        assert!(handle.execute(|| ()).is_err());
+        assert!(matches!(
+            handle.try_execute(|| ()),
+            Err(ExecuteError::ShutDown(_))
+        ));
+    }
+}
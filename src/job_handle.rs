@@ -0,0 +1,186 @@
+//! Handles for jobs which can be cancelled and waited on.
+
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+};
+
+/// An error thrown when joining a job which didn't return a value
+pub enum JobError {
+    /// The job was cancelled before it started, so never ran
+    Cancelled,
+    /// The job panicked. Holds the value it panicked with.
+    Panicked(Box<dyn Any + Send>),
+    /// The pool shut down before the job could run
+    Dropped,
+}
+// The panic payload isn't Debug, so show its message if it has one
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Cancelled => write!(f, "Cancelled"),
+            JobError::Panicked(payload) => {
+                f.debug_tuple("Panicked").field(&message(payload)).finish()
+            }
+            JobError::Dropped => write!(f, "Dropped"),
+        }
+    }
+}
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Cancelled => write!(f, "Error joining job: Cancelled before it started"),
+            JobError::Panicked(payload) => {
+                write!(f, "Error joining job: Panicked with {:?}", message(payload))
+            }
+            JobError::Dropped => write!(f, "Error joining job: Pool shut down before it ran"),
+        }
+    }
+}
+impl Error for JobError {}
+
+// The message a job panicked with, if it was a string
+fn message(payload: &Box<dyn Any + Send>) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("..")
+}
+
+/// A job given to a [ThreadPool](crate::ThreadPool) with
+/// [ThreadPool::spawn](crate::ThreadPool::spawn), which can be cancelled and waited on.
+///
+/// Dropping the handle leaves the job to run on its own.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::Ordering;
+/// use rust_tutorial_webserver::ThreadPool;
+/// let my_thread_pool = ThreadPool::new(2);
+/// let job = my_thread_pool.spawn(|cancel| {
+///     let mut count = 0;
+///     while !cancel.load(Ordering::SeqCst) && count < 1000 {
+///         count += 1;
+///     }
+///     count
+/// });
+/// job.cancel();
+/// // Whether it stopped early depends on how far it got
+/// let _ = job.join();
+/// ```
+pub struct JobHandle<T> {
+    cancel: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    result: mpsc::Receiver<Result<T, JobError>>,
+}
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("cancelled", &self.cancel.load(Ordering::SeqCst))
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+impl<T> JobHandle<T> {
+    /// Ask the job to stop.
+    ///
+    /// Threads can't be killed, so cancellation is cooperative: the flag the job was
+    /// given is set, and it's up to the job to check it and return early. A job which
+    /// hasn't started yet never runs at all.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether the job is over: it has returned or panicked, or won't ever run.
+    ///
+    /// Once this is `true`, [JobHandle::join] returns straight away.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Block until the job is over, then return its value.
+    ///
+    /// Returns [JobError] if the job was cancelled before it started, panicked, or was
+    /// dropped without running because the pool shut down.
+    pub fn join(self) -> Result<T, JobError> {
+        self.result.recv().unwrap_or(Err(JobError::Dropped))
+    }
+}
+
+// The worker's side of a JobHandle
+pub(crate) struct JobCompletion<T> {
+    pub(crate) cancel: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    result: mpsc::Sender<Result<T, JobError>>,
+}
+impl<T> JobCompletion<T> {
+    // Hand the job's outcome to its handle
+    pub(crate) fn finish(self, result: Result<T, JobError>) {
+        // The handle may have been dropped, which is fine
+        let _ = self.result.send(result);
+    }
+}
+// Also marks jobs which are dropped without running as over,
+// once their handle will see them as Dropped
+impl<T> Drop for JobCompletion<T> {
+    fn drop(&mut self) {
+        self.finished.store(true, Ordering::SeqCst);
+    }
+}
+
+// A new handle and the completion which reports to it
+pub(crate) fn job_handle<T>() -> (JobHandle<T>, JobCompletion<T>) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let handle = JobHandle {
+        cancel: Arc::clone(&cancel),
+        finished: Arc::clone(&finished),
+        result: receiver,
+    };
+    let completion = JobCompletion {
+        cancel,
+        finished,
+        result: sender,
+    };
+    (handle, completion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::thread;
+
+    #[test]
+    fn finish_sends_result() {
+        let (handle, completion) = job_handle();
+        assert!(!handle.is_finished());
+        completion.finish(Ok(5));
+        assert!(handle.is_finished());
+        assert_eq!(5, handle.join().unwrap());
+    }
+
+    #[test]
+    fn dropped_completion() {
+        let (handle, completion) = job_handle::<()>();
+        drop(completion);
+        assert!(handle.is_finished());
+        assert!(matches!(handle.join(), Err(JobError::Dropped)));
+    }
+
+    #[test]
+    fn panicked_display() {
+        let payload = thread::spawn(|| panic!("oh no")).join().unwrap_err();
+        let e = JobError::Panicked(payload);
+        assert_eq!("Error joining job: Panicked with \"oh no\"", e.to_string());
+        assert_eq!("Panicked(\"oh no\")", format!("{e:?}"));
+    }
+}
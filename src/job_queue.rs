@@ -0,0 +1,101 @@
+//! Pluggable queues deciding the order jobs run in.
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+};
+
+/// A job waiting to run on a [ThreadPool](crate::ThreadPool)
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A queue of jobs, deciding which one a worker runs next.
+///
+/// Pass one to [ThreadPool::with_queue](crate::ThreadPool::with_queue) or
+/// [ThreadPoolBuilder::job_queue](crate::ThreadPoolBuilder::job_queue) to try out a
+/// scheduling policy of your own. The pool calls `pop` once for each job pushed, when a
+/// worker is free to run it.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Mutex;
+/// use rust_tutorial_webserver::{Job, JobQueue};
+///
+/// // Runs the newest job first
+/// #[derive(Default)]
+/// struct Lifo(Mutex<Vec<Job>>);
+/// impl JobQueue for Lifo {
+///     fn push(&self, job: Job) {
+///         self.0.lock().unwrap().push(job);
+///     }
+///     fn pop(&self) -> Option<Job> {
+///         self.0.lock().unwrap().pop()
+///     }
+/// }
+/// ```
+pub trait JobQueue: Send + Sync {
+    /// Add a job to the queue.
+    fn push(&self, job: Job);
+
+    /// Take the next job to run, or `None` if the queue is empty.
+    fn pop(&self) -> Option<Job>;
+}
+
+/// A [JobQueue] running jobs first-in, first-out.
+///
+/// The same order as the pool's own queue gives jobs of equal priority.
+#[derive(Default)]
+pub struct FifoQueue(Mutex<VecDeque<Job>>);
+impl FifoQueue {
+    /// Create a new, empty queue.
+    pub fn new() -> FifoQueue {
+        FifoQueue::default()
+    }
+}
+impl JobQueue for FifoQueue {
+    fn push(&self, job: Job) {
+        // A panic can't happen while the lock is held, so the
+        // jobs are fine even if it's poisoned
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(job);
+    }
+
+    fn pop(&self) -> Option<Job> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn fifo_queue_order() {
+        let queue = FifoQueue::new();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            queue.push(Box::new(move || tx.send(i).unwrap()));
+        }
+        while let Some(job) = queue.pop() {
+            job();
+        }
+        assert_eq!(vec![0, 1, 2], rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fifo_queue_shared() {
+        let queue: Arc<dyn JobQueue> = Arc::new(FifoQueue::new());
+        assert!(queue.pop().is_none());
+        queue.push(Box::new(|| ()));
+        assert!(queue.pop().is_some());
+        assert!(queue.pop().is_none());
+    }
+}
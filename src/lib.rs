@@ -2,40 +2,258 @@
 
 #![warn(missing_docs)]
 
+mod builder;
+mod handle;
+mod job_handle;
+mod job_queue;
+mod queue;
+mod rate;
+mod scope;
+#[cfg(feature = "server")]
+pub mod server;
+mod timer;
+
+pub use builder::ThreadPoolBuilder;
+pub use handle::PoolHandle;
+pub use job_handle::{JobError, JobHandle};
+pub use job_queue::{FifoQueue, Job, JobQueue};
+pub use rate::RateMeter;
+pub use scope::Scope;
+#[cfg(feature = "server")]
+pub use server::{serve, serve_listener, ConnectionHandler};
+
 use std::{
-    fmt,
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-/// An error thrown when an invalid size is given during creation of a new ThreadPool
+use queue::{Message, PopError, PriorityQueue};
+use timer::Timer;
+
+/// The severity of a message passed to a [Logger]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Routine events, like a worker picking up a job
+    Info,
+    /// Something went wrong, but the pool carried on
+    Warn,
+    /// Something went wrong which the pool couldn't recover from
+    Error,
+}
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A callback which receives every message the pool logs
+pub type Logger = Arc<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+/// The [Logger] used unless another is given, which writes each message to stderr.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::{default_logger, LogLevel};
+/// let logger = default_logger();
+/// logger(LogLevel::Info, "hello"); // prints "[INFO] hello"
+/// ```
+pub fn default_logger() -> Logger {
+    Arc::new(|level, message| eprintln!("[{level}] {message}"))
+}
+
+// How often shutdown_timeout and resize check whether a worker has finished
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// How long execute_with_retry waits after the first failure,
+// doubling after each one after that
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+// A worker whose jobs keep panicking, each within this long of
+// the last, is in a panic storm
+const PANIC_STORM_WINDOW: Duration = Duration::from_secs(1);
+// Consecutive panics before a worker starts backing off. It
+// sleeps for PANIC_BACKOFF after the first of them, doubling
+// after each one after that, up to MAX_PANIC_BACKOFF.
+const PANIC_BACKOFF_AFTER: usize = 5;
+const PANIC_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_PANIC_BACKOFF: Duration = Duration::from_millis(100);
+// Consecutive panics before a worker reports itself unhealthy
+const PANIC_UNHEALTHY_AFTER: usize = 10;
+
+/// An error thrown when a new ThreadPool can't be created
 #[derive(Debug)]
-pub struct PoolCreationError {
-    given_size: usize,
+pub enum PoolCreationError {
+    /// An invalid size was given
+    InvalidSize {
+        /// The size given for the ThreadPool
+        given_size: usize,
+    },
+    /// The OS failed to spawn a worker thread
+    Spawn {
+        /// The size given for the ThreadPool
+        given_size: usize,
+        /// The error returned when spawning the thread
+        source: io::Error,
+    },
 }
 impl fmt::Display for PoolCreationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Error creating ThreadPool: Invalid size. Given size: {}",
-            &self.given_size
-        ) // user output
+        match self {
+            PoolCreationError::InvalidSize { given_size } => write!(
+                f,
+                "Error creating ThreadPool: Invalid size. Given size: {}",
+                given_size
+            ), // user output
+            PoolCreationError::Spawn { given_size, source } => write!(
+                f,
+                "Error creating ThreadPool: Failed to spawn worker thread. Given size: {}. {}",
+                given_size, source
+            ),
+        }
+    }
+}
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::InvalidSize { .. } => None,
+            PoolCreationError::Spawn { source, .. } => Some(source),
+        }
+    }
+}
+impl PoolCreationError {
+    /// Get the size which was given for the ThreadPool.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// if let Err(pce) = ThreadPool::build(0) {
+    ///     assert_eq!(0, pce.given_size());
+    /// }
+    /// ```
+    pub fn given_size(&self) -> usize {
+        match self {
+            PoolCreationError::InvalidSize { given_size }
+            | PoolCreationError::Spawn { given_size, .. } => *given_size,
+        }
+    }
+}
+
+/// An error returned when a job can't be given to a [ThreadPool] without blocking.
+///
+/// Either way, the job is handed back so it isn't lost.
+pub enum ExecuteError<F> {
+    /// The pool's queue is full (see [ThreadPool::with_capacity])
+    Full(F),
+    /// The pool is shutting down, so isn't taking any more jobs
+    ShutDown(F),
+    /// No worker with the id given to [ThreadPool::execute_on] is taking jobs
+    NoSuchWorker(F),
+}
+impl<F> ExecuteError<F> {
+    /// Take back the job which couldn't be executed.
+    pub fn into_inner(self) -> F {
+        match self {
+            ExecuteError::Full(f) | ExecuteError::ShutDown(f) | ExecuteError::NoSuchWorker(f) => f,
+        }
+    }
+}
+// Closures aren't Debug, so leave the job out like mpsc's
+// TrySendError does
+impl<F> fmt::Debug for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Full(_) => write!(f, "Full(..)"),
+            ExecuteError::ShutDown(_) => write!(f, "ShutDown(..)"),
+            ExecuteError::NoSuchWorker(_) => write!(f, "NoSuchWorker(..)"),
+        }
+    }
+}
+impl<F> fmt::Display for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Full(_) => write!(f, "Error executing job: Queue is full"),
+            ExecuteError::ShutDown(_) => {
+                write!(f, "Error executing job: ThreadPool is shutting down")
+            }
+            ExecuteError::NoSuchWorker(_) => write!(f, "Error executing job: No such worker"),
+        }
     }
 }
+impl<F> Error for ExecuteError<F> {}
+
+/// A snapshot of a [ThreadPool]'s state, from [ThreadPool::metrics]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of workers in the pool
+    pub workers: usize,
+    /// Number of workers running a job
+    pub active: usize,
+    /// Number of workers waiting for a job
+    pub idle: usize,
+    /// Number of jobs waiting in the queue for a free worker
+    pub queued: usize,
+    /// Number of jobs the current workers have completed without panicking
+    pub completed: usize,
+    /// Number of jobs which have panicked since the pool was created
+    pub panicked: usize,
+}
 
 /// A list of worker threads.
-pub struct ThreadPool {
+///
+/// The pool can optionally hold a shared context of type `C`, which jobs submitted with
+/// [ThreadPool::execute_ctx] are given a reference to. See
+/// [ThreadPool::new_with_context].
+pub struct ThreadPool<C = ()> {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    queue: Arc<PriorityQueue>,
+    counters: Arc<Counters>,
+    logger: Logger,
+    thread_name_prefix: String,
+    stack_size: Option<usize>,
+    next_id: usize,
+    // Only None once shutdown_into_context has taken it, which
+    // consumes the pool
+    context: Option<Arc<C>>,
+    // Spawned by the first execute_after or
+    // execute_with_timeout. Shared with jobs which need to set
+    // their own timeouts.
+    timer: OnceLock<Arc<Timer>>,
+    idle_policy: Option<IdlePolicy>,
+    // Given to workers added by resize too
+    hooks: WorkerHooks,
 }
 impl ThreadPool {
+    /// The priority given to jobs submitted with [ThreadPool::execute], halfway between
+    /// the lowest and highest priorities.
+    pub const DEFAULT_PRIORITY: u8 = 128;
+
+    // Terminate messages go behind every job of the same or
+    // higher priority
+    const TERMINATE_PRIORITY: u8 = 0;
+
     /// Create a new ThreadPool.
     ///
     /// The size is the number of threads in the pool.
     ///
     /// # Panics
     ///
-    /// `new` panics if invalid size given; compare behaviour to [ThreadPool::build]
+    /// `new` panics if invalid size given or a worker thread can't be spawned; compare
+    /// behaviour to [ThreadPool::build]
     ///
     /// # Examples
     ///
@@ -45,14 +263,15 @@ impl ThreadPool {
     /// ```
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
-        Self::gen_thread_pool(size)
+        Self::gen_thread_pool(ThreadPoolBuilder::new().size(size), ()).unwrap()
     }
 
     /// Create a new ThreadPool.
     ///
     /// The size is the number of threads in the pool.
     ///
-    /// `build` returns [PoolCreationError] if invalid size given; compare behaviour to [ThreadPool::new]
+    /// `build` returns [PoolCreationError] if invalid size given or a worker thread can't be
+    /// spawned; compare behaviour to [ThreadPool::new]
     ///
     /// # Examples
     /// ```
@@ -70,159 +289,2956 @@ impl ThreadPool {
     /// ```
     pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
         if size > 0 {
-            Ok(Self::gen_thread_pool(size))
+            Self::gen_thread_pool(ThreadPoolBuilder::new().size(size), ())
         } else {
-            Err(PoolCreationError { given_size: size })
+            Err(PoolCreationError::InvalidSize { given_size: size })
         }
     }
 
-    fn gen_thread_pool(size: usize) -> ThreadPool {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Create a new ThreadPool with a bounded job queue.
+    ///
+    /// `threads` is the number of threads in the pool. `queue_cap` is the maximum number
+    /// of jobs which can wait in the queue for a free worker.
+    ///
+    /// Once the queue is full, the pool applies backpressure: [ThreadPool::execute] blocks
+    /// the calling thread until a worker takes a job off the queue, while
+    /// [ThreadPool::try_execute] returns immediately, handing the closure back to the
+    /// caller. A `queue_cap` of 0 means jobs are only accepted when a worker is ready to
+    /// take them straight away.
+    ///
+    /// # Panics
+    ///
+    /// `with_capacity` panics if `threads` is 0 or a worker thread can't be spawned, like
+    /// [ThreadPool::new].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::with_capacity(4, 64);
+    /// ```
+    pub fn with_capacity(threads: usize, queue_cap: usize) -> ThreadPool {
+        assert!(threads > 0);
+        let builder = ThreadPoolBuilder::new()
+            .size(threads)
+            .queue_capacity(queue_cap);
+        Self::gen_thread_pool(builder, ()).unwrap()
+    }
+
+    /// Create a new ThreadPool which sends its log messages to `logger`.
+    ///
+    /// The size is the number of threads in the pool. Pools made any other way use
+    /// [default_logger].
+    ///
+    /// # Panics
+    ///
+    /// `with_logger` panics if invalid size given or a worker thread can't be spawned,
+    /// like [ThreadPool::new].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rust_tutorial_webserver::{LogLevel, ThreadPool};
+    /// // Only show problems
+    /// let my_thread_pool = ThreadPool::with_logger(
+    ///     4,
+    ///     Arc::new(|level, message| {
+    ///         if level != LogLevel::Info {
+    ///             eprintln!("{message}");
+    ///         }
+    ///     }),
+    /// );
+    /// ```
+    pub fn with_logger(size: usize, logger: Logger) -> ThreadPool {
+        assert!(size > 0);
+        let builder = ThreadPoolBuilder::new().size(size).logger(logger);
+        Self::gen_thread_pool(builder, ()).unwrap()
+    }
+
+    /// Create a new ThreadPool which keeps its waiting jobs in `queue`, letting it pick
+    /// the order they run in.
+    ///
+    /// The size is the number of threads in the pool. Priorities given to
+    /// [ThreadPool::execute_with_priority] are ignored, since `queue` decides the
+    /// order. Everything else behaves like [ThreadPool::new].
+    ///
+    /// # Panics
+    ///
+    /// `with_queue` panics if invalid size given or a worker thread can't be spawned,
+    /// like [ThreadPool::new].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rust_tutorial_webserver::{FifoQueue, ThreadPool};
+    /// let my_thread_pool = ThreadPool::with_queue(4, Arc::new(FifoQueue::new()));
+    /// my_thread_pool.execute(|| println!("first in, first out"));
+    /// ```
+    pub fn with_queue(size: usize, queue: Arc<dyn JobQueue>) -> ThreadPool {
+        assert!(size > 0);
+        let builder = ThreadPoolBuilder::new().size(size).job_queue(queue);
+        Self::gen_thread_pool(builder, ()).unwrap()
+    }
+}
+impl<C> ThreadPool<C> {
+    /// Create a new ThreadPool holding a context shared by all of its jobs.
+    ///
+    /// The size is the number of threads in the pool. Jobs submitted with
+    /// [ThreadPool::execute_ctx] are passed a reference to `context`, so they don't need
+    /// to capture their own copy of shared state such as configuration.
+    ///
+    /// # Panics
+    ///
+    /// `new_with_context` panics if invalid size given or a worker thread can't be
+    /// spawned, like [ThreadPool::new].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new_with_context(4, String::from("config"));
+    /// my_thread_pool.execute_ctx(|config| println!("{config}"));
+    /// ```
+    pub fn new_with_context(size: usize, context: C) -> ThreadPool<C> {
+        assert!(size > 0);
+        Self::gen_thread_pool(ThreadPoolBuilder::new().size(size), context).unwrap()
+    }
+
+    // Size must already have been checked
+    fn gen_thread_pool(
+        builder: ThreadPoolBuilder,
+        context: C,
+    ) -> Result<ThreadPool<C>, PoolCreationError> {
+        let idle_policy = builder.idle_policy();
+        let ThreadPoolBuilder {
+            size,
+            queue_capacity,
+            thread_name_prefix,
+            stack_size,
+            logger,
+            work_stealing,
+            max_queue_age,
+            job_queue,
+            hooks,
+            ..
+        } = builder;
+        // Work stealing gives each worker a shard of the queue
+        let shards = if work_stealing { size } else { 1 };
+        let queue = Arc::new(
+            PriorityQueue::new(queue_capacity, shards, Arc::clone(&logger))
+                .expire_after(max_queue_age)
+                .job_queue(job_queue),
+        );
+        let counters = Arc::new(Counters::default());
 
         // Preallocating vector space is more efficient than Vec::new
         let mut workers = Vec::with_capacity(size);
 
         for n in 0..size {
             // Create some threads and store them in the vector.
-            // Arc type allows multiple workers to own the
-            // receiver
-            // Mutex ensures only one worker gets a job from
-            // the receiver at a time
-            workers.push(Worker::new(n, Arc::clone(&receiver)));
+            // Arc type allows multiple workers to own the queue
+            let worker = Worker::new(
+                n,
+                worker_thread(&thread_name_prefix, n, stack_size),
+                Arc::clone(&queue),
+                Arc::clone(&counters),
+                Arc::clone(&logger),
+                idle_policy,
+                hooks.clone(),
+            )
+            .map_err(|source| PoolCreationError::Spawn {
+                given_size: size,
+                source,
+            })
+            .inspect_err(|_| queue.close())?; // so workers made so far exit
+            workers.push(worker);
         }
 
-        ThreadPool {
+        Ok(ThreadPool {
             workers,
-            sender: Some(sender),
-        }
+            queue,
+            counters,
+            logger,
+            thread_name_prefix,
+            stack_size,
+            next_id: size,
+            context: Some(Arc::new(context)),
+            timer: OnceLock::new(),
+            idle_policy,
+            hooks,
+        })
     }
 
-    /// Select a worker and execute a given closure.
-    // use FnOnce as trait bound on F; eventually pass argument
-    // received in execute to spawn. additionally, a thread
-    // running a request will only execute that request's
-    // closure once.
-    pub fn execute<F>(&self, f: F)
-    // F has trait bounds FnOnce & Send and has static lifetime
+    /// Get a reference to the pool's shared context.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new_with_context(2, 5);
+    /// assert_eq!(5, *my_thread_pool.context());
+    /// ```
+    pub fn context(&self) -> &C {
+        self.shared_context()
+    }
 
-    // FnOnce() = closure that takes no params and returns unit
-    // type ().
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        // Create new Job instance using the provided closure
-        // and send that job down the sending end of the channel.
-        // unwrap is used because failure case won't happen.
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    fn shared_context(&self) -> &Arc<C> {
+        self.context
+            .as_ref()
+            .expect("context is only taken when the pool is consumed")
     }
-}
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+    /// Grow or shrink the pool to `new_size` workers.
+    ///
+    /// Growing spawns new workers which share the existing job queue. Shrinking queues
+    /// one terminate message per surplus worker behind any jobs already waiting, so no
+    /// queued job is dropped, then blocks until that many workers have exited. Any
+    /// worker may pick up a terminate message, so the surviving workers' ids aren't
+    /// necessarily the lowest ones.
+    ///
+    /// # Panics
+    ///
+    /// `resize` panics if `new_size` is 0 or a new worker thread can't be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let mut my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.resize(8);
+    /// my_thread_pool.resize(1);
+    /// ```
+    pub fn resize(&mut self, new_size: usize) {
+        assert!(new_size > 0);
+        // Workers which retired after an idle timeout are gone
+        // already; only count the live ones
+        self.reap_retired();
+        let old_size = self.counters.live.load(Ordering::SeqCst);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        for _ in old_size..new_size {
+            let worker = Worker::new(
+                self.next_id,
+                worker_thread(&self.thread_name_prefix, self.next_id, self.stack_size),
+                Arc::clone(&self.queue),
+                Arc::clone(&self.counters),
+                Arc::clone(&self.logger),
+                self.idle_policy,
+                self.hooks.clone(),
+            )
+            .unwrap();
+            self.next_id += 1;
+            self.workers.push(worker);
+        }
+
+        if new_size >= old_size {
+            return;
+        }
+
+        let surplus = old_size - new_size;
+        if self.is_paused() {
+            // Paused workers take nothing from the queue, so
+            // tell them to leave directly. The jobs stay queued.
+            self.queue.dismiss(surplus);
+        } else {
+            for _ in 0..surplus {
+                // Skip the capacity check; the message shouldn't
+                // wait behind a full queue
+                self.queue
+                    .push_unbounded(ThreadPool::TERMINATE_PRIORITY, Message::Terminate)
+                    .ok()
+                    .unwrap();
             }
         }
-    }
-}
 
-// Type alias for a trait object that holds the type of closure
-// that execute receives
-type Job = Box<dyn FnOnce() + Send + 'static>;
+        // Whichever workers received Terminate will exit, so wait
+        // for that many to drop out of the live count, then join
+        // them.
+        while self.counters.live.load(Ordering::SeqCst) > new_size {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        self.reap_retired();
+    }
 
-/// A worker with a given id which can be assigned tasks to do
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        // Closure loops forever, asking receiving end of
-        // channel for a job and running the job when it
-        // gets one.
-        let thread = thread::spawn(move || loop {
-            // Call lock() on receiver to acquire mutex
-            // Call unwrap() to panic on any errors, such
-            // as poisoned mutex state wherein another
-            // thread panics whilst holding the lock.
-            // Call recv() to receive a Job from the channel.
-            // recv() call blocks, so will wait for next job.
-            // Mutex<T> ensures only one Worker thread at a
-            // time is trying to request a job.
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got job; executing.");
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
-                }
+    // Join and remove the workers which have left the pool
+    fn reap_retired(&mut self) {
+        let (retired, running) = self
+            .workers
+            .drain(..)
+            .partition(|worker| worker.retired.load(Ordering::SeqCst));
+        self.workers = running;
+        for mut worker in retired {
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
             }
-        });
-        Worker {
-            id,
-            thread: Some(thread),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pretty_assertions::assert_eq;
+    /// Get the number of workers currently executing a job.
+    ///
+    /// The value is a snapshot; workers may pick up or finish jobs right after it's read.
+    pub fn active_count(&self) -> usize {
+        self.counters.active.load(Ordering::SeqCst)
+    }
 
-    #[test]
-    fn new_ok() {
-        ThreadPool::new(2);
+    /// Get the number of workers currently waiting for a job.
+    ///
+    /// Like [ThreadPool::active_count], the value is only a snapshot.
+    pub fn idle_count(&self) -> usize {
+        self.size().saturating_sub(self.active_count())
     }
 
-    #[test]
-    fn new_4() {
-        let tp = ThreadPool::new(4);
-        assert_eq!(4, tp.workers.len());
+    /// Get the number of workers in the pool.
+    ///
+    /// Starts out as the size the pool was created with and follows
+    /// [ThreadPool::resize]. Workers which retire after an
+    /// [idle timeout](ThreadPoolBuilder::idle_timeout) aren't counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// assert_eq!(4, my_thread_pool.size());
+    /// ```
+    pub fn size(&self) -> usize {
+        self.counters.live.load(Ordering::SeqCst)
     }
 
-    #[test]
-    #[should_panic]
-    fn new_0() {
-        ThreadPool::new(0);
+    /// Get the number of jobs each worker has completed without panicking.
+    ///
+    /// Returns `(id, jobs_completed)` pairs, one per worker currently in the pool.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// assert_eq!(vec![(0, 0), (1, 0)], my_thread_pool.worker_stats());
+    /// ```
+    pub fn worker_stats(&self) -> Vec<(usize, usize)> {
+        self.live_workers()
+            .map(|worker| (worker.id, worker.completed.load(Ordering::SeqCst)))
+            .collect()
     }
 
-    #[test]
-    fn build_ok() {
-        ThreadPool::build(4).unwrap();
+    /// Get each worker's id and whether its thread is still running and healthy.
+    ///
+    /// Returns `(id, healthy)` pairs, one per worker the pool holds. Workers which
+    /// retired after an [idle timeout](ThreadPoolBuilder::idle_timeout) report `false`
+    /// until the pool next [resizes](ThreadPool::resize), which clears them out.
+    ///
+    /// A worker whose jobs keep panicking one after another backs off, sleeping for a
+    /// little longer after each panic so a systemic bug can't spin the CPU and flood
+    /// the logs. If the panics carry on, it reports `false` here too, until it next
+    /// completes a job.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// assert_eq!(vec![(0, true), (1, true)], my_thread_pool.worker_status());
+    /// ```
+    pub fn worker_status(&self) -> Vec<(usize, bool)> {
+        self.workers
+            .iter()
+            .map(|worker| {
+                let healthy = worker.healthy.load(Ordering::SeqCst);
+                (worker.id, worker.is_alive() && healthy)
+            })
+            .collect()
     }
 
-    #[test]
-    fn build_2() {
-        let tp = ThreadPool::build(2).unwrap();
-        assert_eq!(2, tp.workers.len());
+    // Workers which haven't retired
+    fn live_workers(&self) -> impl Iterator<Item = &Worker> {
+        self.workers
+            .iter()
+            .filter(|worker| !worker.retired.load(Ordering::SeqCst))
     }
 
-    #[test]
-    #[should_panic]
-    fn build_0() {
-        ThreadPool::build(0).unwrap();
+    /// Get the number of jobs waiting in the queue for a free worker.
+    ///
+    /// Useful for shedding load before the pool gets too backed up. The value is a
+    /// snapshot which may already be stale by the time it's read, so treat it as an
+    /// approximation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// if my_thread_pool.queued_jobs() < 100 {
+    ///     my_thread_pool.execute(|| println!("not too busy"));
+    /// }
+    /// ```
+    pub fn queued_jobs(&self) -> usize {
+        self.counters.queued.load(Ordering::SeqCst)
     }
 
-    #[test]
-    fn pool_creation_error_display() {
-        match ThreadPool::build(0) {
-            Err(pce) => {
-                assert_eq!(
-                    "Error creating ThreadPool: Invalid size. Given size: 0",
-                    pce.to_string()
-                )
-            }
-            _ => panic!("Should have returned error"),
+    /// Get a snapshot of all of the pool's counters at once.
+    ///
+    /// The counters keep changing while they're read, so like the individual getters
+    /// the snapshot is approximate, but `active + idle == workers` always holds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// let metrics = my_thread_pool.metrics();
+    /// assert_eq!(4, metrics.workers);
+    /// println!("{metrics:?}");
+    /// ```
+    pub fn metrics(&self) -> PoolMetrics {
+        let workers = self.size();
+        // A worker may pick up a job after the count is taken,
+        // so don't let active outgrow it
+        let active = self.active_count().min(workers);
+        PoolMetrics {
+            workers,
+            active,
+            idle: workers - active,
+            queued: self.queued_jobs(),
+            completed: self
+                .live_workers()
+                .map(|worker| worker.completed.load(Ordering::SeqCst))
+                .sum(),
+            panicked: self.panic_count(),
         }
     }
+
+    /// Get the number of jobs which have panicked since the pool was created.
+    ///
+    /// A panicking job doesn't take its worker down with it; the worker logs the panic
+    /// and moves on to the next job.
+    pub fn panic_count(&self) -> usize {
+        self.counters.panicked.load(Ordering::SeqCst)
+    }
+
+    /// Get the pool's counters in the Prometheus text exposition format, for scraping.
+    ///
+    /// Gives gauges for the workers, active and idle workers and queued jobs, and
+    /// counters for the jobs completed and panicked since the pool was created. Unlike
+    /// [PoolMetrics::completed], the completed count includes jobs run by workers which
+    /// have since retired, so it never goes down. The server's `prometheus_metrics`
+    /// handler serves it over HTTP.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// let text = my_thread_pool.metrics_prometheus();
+    /// assert!(text.contains("# TYPE threadpool_workers gauge\nthreadpool_workers 4\n"));
+    /// ```
+    pub fn metrics_prometheus(&self) -> String {
+        self.counters.prometheus()
+    }
+
+    /// Get the number of jobs the pool has finished per second, averaged over the last
+    /// 10 seconds.
+    ///
+    /// Jobs which panicked count too, since they still took up a worker. When serving,
+    /// each connection is one job, however many requests are sent on it, so this is the
+    /// rate connections are handled at rather than requests. For the request rate,
+    /// [record](RateMeter::record) each request on a [RateMeter] from a `Middleware`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// for _ in 0..20 {
+    ///     my_thread_pool.execute(|| ());
+    /// }
+    /// my_thread_pool.join();
+    /// assert_eq!(2.0, my_thread_pool.jobs_per_second());
+    /// ```
+    pub fn jobs_per_second(&self) -> f64 {
+        self.counters.finish_rate.per_second()
+    }
+
+    /// Get a handle for giving jobs to the pool from elsewhere, without owning it.
+    ///
+    /// See [PoolHandle] for how handles behave once the pool has shut down.
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle::new(Arc::clone(&self.queue), Arc::clone(&self.counters))
+    }
+
+    /// Select a worker and execute a given closure.
+    ///
+    /// The job is given [ThreadPool::DEFAULT_PRIORITY]. If the pool was created with
+    /// [ThreadPool::with_capacity] and its queue is full, `execute` blocks until there
+    /// is room.
+    ///
+    /// If the pool has begun shutting down, e.g. after [ThreadPool::shutdown_timeout],
+    /// the closure is dropped without running and a warning logged. Use
+    /// [ThreadPool::try_execute_checked] to get it back instead.
+    // use FnOnce as trait bound on F; eventually pass argument
+    // received in execute to spawn. additionally, a thread
+    // running a request will only execute that request's
+    // closure once.
+    pub fn execute<F>(&self, f: F)
+    // F has trait bounds FnOnce & Send and has static lifetime
+
+    // FnOnce() = closure that takes no params and returns unit
+    // type ().
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(ThreadPool::DEFAULT_PRIORITY, f);
+    }
+
+    /// Select a worker and execute a given closure ahead of any lower-priority jobs.
+    ///
+    /// Waiting jobs are run highest `priority` first. Jobs with equal priority are run
+    /// in the order they were submitted. A job which has already started is never
+    /// interrupted. Blocks like [ThreadPool::execute] if the queue is full.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_with_priority(255, || println!("urgent"));
+    /// my_thread_pool.execute_with_priority(0, || println!("whenever"));
+    /// ```
+    pub fn execute_with_priority<F>(&self, priority: u8, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Create new Job instance using the provided closure
+        // and push that job onto the queue.
+        // The job counts as outstanding before it's queued, so
+        // join() can't miss it.
+        let job = Box::new(f);
+        self.counters.job_submitted();
+        if self.queue.push(priority, Message::NewJob(job)).is_err() {
+            self.counters.job_rejected();
+            self.log_dropped(1);
+        }
+    }
+
+    fn log_dropped(&self, count: usize) {
+        log_dropped(&self.logger, count);
+    }
+
+    /// Execute a given closure on the worker with id `worker_id`, as listed by
+    /// [ThreadPool::worker_stats].
+    ///
+    /// Useful for keeping related jobs on one thread, e.g. so they find each other's
+    /// data still in its CPU's cache. The worker runs jobs given to it this way in the
+    /// order they were submitted, before any others. If it leaves the pool first, after
+    /// a [resize](ThreadPool::resize) or an idle timeout, any still waiting are run by
+    /// whichever worker is free instead. Blocks like [ThreadPool::execute] if the queue
+    /// is full.
+    ///
+    /// Returns [ExecuteError::NoSuchWorker], handing the closure back, if there's no
+    /// worker with that id in the pool, or [ExecuteError::ShutDown] if the pool is
+    /// shutting down.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_on(1, || println!("on worker 1")).unwrap();
+    /// assert!(my_thread_pool.execute_on(2, || ()).is_err());
+    /// ```
+    pub fn execute_on<F>(&self, worker_id: usize, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.job_submitted();
+        self.queue
+            .push_to(worker_id, f)
+            .inspect_err(|_| self.counters.job_rejected())
+    }
+
+    /// Select a worker and execute a given closure, calling `on_panic` with the panic
+    /// payload if it panics.
+    ///
+    /// Useful for cleaning up after a particular job without touching the global panic
+    /// hook. The panic still counts towards [ThreadPool::panic_count] and is logged as
+    /// usual. Otherwise behaves like [ThreadPool::execute].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_with_panic_handler(
+    ///     || panic!("oh no"),
+    ///     |payload| {
+    ///         if let Some(message) = payload.downcast_ref::<&str>() {
+    ///             eprintln!("job failed: {message}");
+    ///         }
+    ///     },
+    /// );
+    /// ```
+    pub fn execute_with_panic_handler<F, H>(&self, f: F, on_panic: H)
+    where
+        F: FnOnce() + Send + 'static,
+        H: FnOnce(Box<dyn Any + Send>) + Send + 'static,
+    {
+        self.execute(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                on_panic(payload);
+                // Carry on unwinding so the worker still counts
+                // and logs the panic. resume_unwind skips the
+                // panic hook, so nothing is printed twice.
+                panic::resume_unwind(Box::new("job panicked; handled by its panic handler"));
+            }
+        });
+    }
+
+    /// Select a worker and execute a given closure, then call `on_done` with whether it
+    /// succeeded.
+    ///
+    /// `on_done` runs on the same worker straight after `f`, and is passed `false` if `f`
+    /// panicked. Useful for being told a job has finished without blocking on a
+    /// receiver. A panic is still counted and logged like any other afterwards.
+    /// Otherwise behaves like [ThreadPool::execute].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_then(
+    ///     || println!("working"),
+    ///     |succeeded| println!("finished, succeeded: {succeeded}"),
+    /// );
+    /// ```
+    pub fn execute_then<F, D>(&self, f: F, on_done: D)
+    where
+        F: FnOnce() + Send + 'static,
+        D: FnOnce(bool) + Send + 'static,
+    {
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            on_done(result.is_ok());
+            if let Err(payload) = result {
+                // Keep unwinding with the original payload, so
+                // the worker counts and logs it as usual
+                panic::resume_unwind(payload);
+            }
+        });
+    }
+
+    /// Select a worker and execute a given closure, running it again if it returns an
+    /// error, up to `attempts` times in all.
+    ///
+    /// Each failure is logged as a warning, then the worker waits a little before the
+    /// next attempt, twice as long each time, starting from 10ms. The job stops at the
+    /// first `Ok`. The worker is kept busy while it waits, so this suits occasional
+    /// flaky work rather than anything expected to fail often. At least one attempt is
+    /// always made. Otherwise behaves like [ThreadPool::execute].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_with_retry(3, || std::fs::read("flaky.txt").map(|_| ()));
+    /// ```
+    pub fn execute_with_retry<F, E>(&self, attempts: usize, f: F)
+    where
+        F: Fn() -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+    {
+        let logger = Arc::clone(&self.logger);
+        let attempts = attempts.max(1);
+        self.execute(move || {
+            let mut backoff = RETRY_BACKOFF;
+            for attempt in 1..=attempts {
+                let Err(e) = f() else {
+                    return;
+                };
+                logger(
+                    LogLevel::Warn,
+                    &format!("Job failed on attempt {attempt} of {attempts}: {e}"),
+                );
+                if attempt < attempts {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+        });
+    }
+
+    /// Execute a given closure once `delay` has passed.
+    ///
+    /// The closure waits on a timer thread, spawned the first time this is called, and
+    /// is queued like [ThreadPool::execute] once it's due. It then waits its turn behind
+    /// any jobs already queued, so may start a little after `delay`. [ThreadPool::join]
+    /// doesn't wait for closures which aren't due yet, and any still waiting when the
+    /// pool shuts down are dropped without running.
+    ///
+    /// # Panics
+    ///
+    /// `execute_after` panics if the timer thread can't be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_after(Duration::from_millis(10), || println!("later"));
+    /// ```
+    pub fn execute_after<F>(&self, delay: Duration, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // A delay too long to represent never comes due
+        if let Some(due) = Instant::now().checked_add(delay) {
+            let _ = self.timer().schedule(due, Box::new(f));
+        }
+    }
+
+    /// Execute a given closure, asking it to stop if it's still running after `timeout`.
+    ///
+    /// Threads can't be killed, so cancellation is cooperative: the closure is passed a
+    /// flag which is set once `timeout` has passed since it started, and should check it
+    /// now and then and return early once it's set. A closure which never checks just
+    /// runs to completion. A warning is logged when the flag is set.
+    ///
+    /// The timeout is kept by the same timer thread as [ThreadPool::execute_after], which
+    /// carries on while a shutting down pool waits for its jobs, so the flag is still set
+    /// for jobs which are holding up shutdown.
+    ///
+    /// # Panics
+    ///
+    /// `execute_with_timeout` panics if the timer thread can't be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::{sync::atomic::Ordering, time::Duration};
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute_with_timeout(
+    ///     |cancel| {
+    ///         while !cancel.load(Ordering::SeqCst) {
+    ///             // Do a little work at a time...
+    ///         }
+    ///     },
+    ///     Duration::from_millis(10),
+    /// );
+    /// ```
+    pub fn execute_with_timeout<F>(&self, f: F, timeout: Duration)
+    where
+        F: FnOnce(Arc<AtomicBool>) + Send + 'static,
+    {
+        let timer = Arc::clone(self.timer());
+        let logger = Arc::clone(&self.logger);
+        self.execute(move || {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let done = Arc::new(AtomicBool::new(false));
+            // The clock starts when the job does, not while it's
+            // waiting in the queue. The check runs on the timer
+            // thread, so it isn't held up by busy workers.
+            if let Some(due) = Instant::now().checked_add(timeout) {
+                let cancel = Arc::clone(&cancel);
+                let done = Arc::clone(&done);
+                let _ = timer.schedule_inline(
+                    due,
+                    Box::new(move || {
+                        if !done.load(Ordering::SeqCst) {
+                            cancel.store(true, Ordering::SeqCst);
+                            logger(
+                                LogLevel::Warn,
+                                &format!("Job still running after {timeout:?}; asking it to stop."),
+                            );
+                        }
+                    }),
+                );
+            }
+            f(cancel);
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+
+    // The timer thread, spawned the first time it's needed
+    fn timer(&self) -> &Arc<Timer> {
+        self.timer.get_or_init(|| {
+            let queue = Arc::clone(&self.queue);
+            let counters = Arc::clone(&self.counters);
+            // Due jobs go through the same queue as everything
+            // else. If the pool is shutting down, the job is
+            // just dropped.
+            let timer = Timer::new(format!("{}-timer", self.thread_name_prefix), move |job| {
+                counters.job_submitted();
+                let _ = queue
+                    .push(ThreadPool::DEFAULT_PRIORITY, Message::NewJob(job))
+                    .inspect_err(|_| counters.job_rejected());
+            })
+            .expect("failed to spawn timer thread");
+            Arc::new(timer)
+        })
+    }
+
+    /// Give a whole batch of closures to the workers at once.
+    ///
+    /// Cheaper than calling [ThreadPool::execute] for each one, since the queue is only
+    /// locked once, unless it fills up part way through. Like `execute`, blocks while
+    /// the queue is full. The closures are run in order, as if submitted one at a time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// my_thread_pool.execute_batch((0..10).map(|n| move || println!("job {n}")));
+    /// ```
+    pub fn execute_batch<F, I>(&self, jobs: I)
+    where
+        F: FnOnce() + Send + 'static,
+        I: IntoIterator<Item = F>,
+    {
+        // Box every job up front so the queue's lock isn't held
+        // while allocating
+        let messages: Vec<Message> = jobs
+            .into_iter()
+            .map(|f| Message::NewJob(Box::new(f)))
+            .collect();
+        self.counters.jobs_submitted(messages.len());
+        if let Err(rejected) = self
+            .queue
+            .push_batch(ThreadPool::DEFAULT_PRIORITY, messages)
+        {
+            self.counters.jobs_rejected(rejected.len());
+            self.log_dropped(rejected.len());
+        }
+    }
+
+    /// Select a worker and execute a given closure, passing it a reference to the pool's
+    /// shared context.
+    ///
+    /// Otherwise behaves like [ThreadPool::execute].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new_with_context(2, AtomicUsize::new(0));
+    /// my_thread_pool.execute_ctx(|count| {
+    ///     count.fetch_add(1, Ordering::SeqCst);
+    /// });
+    /// ```
+    pub fn execute_ctx<F>(&self, f: F)
+    where
+        F: FnOnce(&C) + Send + 'static,
+        C: Send + Sync + 'static,
+    {
+        let context = Arc::clone(self.shared_context());
+        self.execute(move || f(&context));
+    }
+
+    /// Try to give a closure to a worker without blocking.
+    ///
+    /// Returns the closure back to the caller if the pool's queue is full (see
+    /// [ThreadPool::with_capacity]) or the pool is shutting down. Pools with an unbounded
+    /// queue always accept the closure.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::with_capacity(1, 1);
+    /// if let Err(f) = my_thread_pool.try_execute(|| println!("hello")) {
+    ///     // Queue is full; run it here instead
+    ///     f();
+    /// }
+    /// ```
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.job_submitted();
+        self.queue
+            .try_push(ThreadPool::DEFAULT_PRIORITY, f)
+            .map_err(|e| {
+                self.counters.job_rejected();
+                e.into_inner()
+            })
+    }
+
+    /// Try to give a closure to a worker without blocking, saying why if it can't be.
+    ///
+    /// Like [ThreadPool::try_execute], but the [ExecuteError] tells a full queue apart
+    /// from a pool which is shutting down, e.g. after [ThreadPool::shutdown_timeout].
+    /// Either way the closure is handed back intact, whereas [ThreadPool::execute] drops
+    /// it once shutdown has begun.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use rust_tutorial_webserver::{ExecuteError, ThreadPool};
+    /// let mut my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.shutdown_timeout(Duration::from_secs(1)).unwrap();
+    /// match my_thread_pool.try_execute_checked(|| println!("hello")) {
+    ///     Err(ExecuteError::ShutDown(f)) => f(),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn try_execute_checked<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.counters.job_submitted();
+        self.queue
+            .try_push(ThreadPool::DEFAULT_PRIORITY, f)
+            .inspect_err(|_| self.counters.job_rejected())
+    }
+
+    /// Run `f` with a [Scope] for giving the pool jobs which borrow local data, then
+    /// block until all of those jobs have finished.
+    ///
+    /// Like [std::thread::scope], the jobs can borrow anything which outlives the call,
+    /// since none of them can still be running once it returns. Returns whatever `f`
+    /// returns. If `f` or any of the scope's jobs panics, `scope` waits for the rest of
+    /// the jobs, then panics too.
+    ///
+    /// Calling `scope` from inside one of the pool's own jobs can deadlock, like
+    /// [ThreadPool::join], if the scope's jobs are left waiting for a free worker.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// let mut totals = [0; 4];
+    /// let numbers: Vec<u64> = (1..=100).collect();
+    /// my_thread_pool.scope(|s| {
+    ///     for (total, chunk) in totals.iter_mut().zip(numbers.chunks(25)) {
+    ///         s.execute(move || *total = chunk.iter().sum());
+    ///     }
+    /// });
+    /// assert_eq!(5050, totals.iter().sum::<u64>());
+    /// ```
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope::new(&self.queue, &self.counters, &self.logger);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Whatever happened, nothing borrowed by the jobs may be
+        // touched again until they're done
+        let job_panicked = scope.wait();
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if job_panicked => panic!("a scoped job panicked"),
+            Ok(result) => result,
+        }
+    }
+
+    /// Block until every job submitted so far has finished running.
+    ///
+    /// Unlike [ThreadPool::shutdown_timeout], the pool keeps running afterwards and can
+    /// be given more jobs. Jobs submitted by other threads while `join` is waiting are
+    /// waited for too. Calling `join` from inside one of the pool's own jobs deadlocks,
+    /// since that job can't finish until `join` returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(4);
+    /// let count = Arc::new(AtomicUsize::new(0));
+    /// for _ in 0..8 {
+    ///     let count = Arc::clone(&count);
+    ///     my_thread_pool.execute(move || {
+    ///         count.fetch_add(1, Ordering::SeqCst);
+    ///     });
+    /// }
+    /// my_thread_pool.join();
+    /// assert_eq!(8, count.load(Ordering::SeqCst));
+    /// ```
+    pub fn join(&self) {
+        self.counters.wait_all_done();
+    }
+
+    /// Stop taking new jobs, then block until every job already queued has finished.
+    ///
+    /// Unlike [ThreadPool::shutdown_timeout], the pool itself is left in place, so its
+    /// [metrics](ThreadPool::metrics) and [stats](ThreadPool::worker_stats) can still
+    /// be read. Jobs submitted afterwards are rejected: the `try_execute` methods hand
+    /// them back with [ExecuteError::ShutDown], and [ThreadPool::execute] drops them.
+    /// Delayed jobs which aren't due yet are dropped too. The workers exit once the
+    /// queue is empty, and are joined when the pool is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// my_thread_pool.execute(|| println!("finished before drain returns"));
+    /// my_thread_pool.drain();
+    /// assert!(my_thread_pool.try_execute(|| ()).is_err());
+    /// ```
+    pub fn drain(&self) {
+        self.queue.close();
+        // Nothing queued could finish otherwise
+        self.resume();
+        self.join();
+    }
+
+    /// Stop workers from starting any more jobs until [ThreadPool::resume] is called.
+    ///
+    /// Jobs already running carry on to the end, then their workers wait. Jobs can still
+    /// be submitted while the pool is paused; they stay in the queue, in priority order,
+    /// and run once it's resumed. Since nothing finishes in the meantime,
+    /// [ThreadPool::join] blocks until another thread resumes the pool. The pool can
+    /// still be [resized](ThreadPool::resize) while paused. Shutting it down, with
+    /// [ThreadPool::drain], [ThreadPool::shutdown_timeout] or by dropping it, resumes it
+    /// first so the queue can empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// let count = Arc::new(AtomicUsize::new(0));
+    /// my_thread_pool.pause();
+    /// for _ in 0..4 {
+    ///     let count = Arc::clone(&count);
+    ///     my_thread_pool.execute(move || {
+    ///         count.fetch_add(1, Ordering::SeqCst);
+    ///     });
+    /// }
+    /// // Nothing runs during maintenance
+    /// assert_eq!(0, count.load(Ordering::SeqCst));
+    /// my_thread_pool.resume();
+    /// my_thread_pool.join();
+    /// assert_eq!(4, count.load(Ordering::SeqCst));
+    /// ```
+    pub fn pause(&self) {
+        self.queue.pause();
+    }
+
+    /// Let workers start jobs again after [ThreadPool::pause], beginning with those which
+    /// queued up meanwhile. Does nothing if the pool isn't paused.
+    pub fn resume(&self) {
+        self.queue.resume();
+    }
+
+    /// Check whether the pool is [paused](ThreadPool::pause).
+    pub fn is_paused(&self) -> bool {
+        self.queue.is_paused()
+    }
+
+    /// Select a worker and execute a given closure, sending its return value back to the
+    /// caller.
+    ///
+    /// Returns the receiving end of a single-use channel. Use `recv()` to block until
+    /// the result is ready or `try_recv()` to poll for it. If the closure panics, the
+    /// channel is disconnected without a value being sent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// let result = my_thread_pool.execute_returning(|| 2 + 2);
+    /// assert_eq!(4, result.recv().unwrap());
+    /// ```
+    pub fn execute_returning<F, T>(&self, f: F) -> mpsc::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        // If f panics, sender is dropped during unwinding, which
+        // disconnects the receiver instead of leaving it hanging.
+        // The caller may have dropped the receiver, so ignore
+        // send errors.
+        self.execute(move || {
+            let _ = sender.send(f());
+        });
+        receiver
+    }
+
+    /// Select a worker and execute a given closure, returning a [JobHandle] which can
+    /// cancel the job and wait for its return value.
+    ///
+    /// The closure is given a flag which is set once [JobHandle::cancel] is called, so
+    /// long-running jobs can check it and return early. A job cancelled before it
+    /// starts never runs. If the closure panics, the panic is still counted and logged
+    /// by the worker, and [JobHandle::join] returns [JobError::Panicked].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new(2);
+    /// let job = my_thread_pool.spawn(|_cancel| 2 + 2);
+    /// assert_eq!(4, job.join().unwrap());
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(Arc<AtomicBool>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (handle, completion) = job_handle::job_handle();
+        // If the pool drops the job without running it, the
+        // completion is dropped too and the handle sees Dropped
+        self.execute(move || {
+            if completion.cancel.load(Ordering::SeqCst) {
+                completion.finish(Err(JobError::Cancelled));
+                return;
+            }
+            let cancel = Arc::clone(&completion.cancel);
+            match panic::catch_unwind(AssertUnwindSafe(|| f(cancel))) {
+                Ok(value) => completion.finish(Ok(value)),
+                Err(payload) => {
+                    completion.finish(Err(JobError::Panicked(payload)));
+                    // The payload went to the handle, so carry on
+                    // unwinding with a stand-in for the worker to
+                    // count and log
+                    panic::resume_unwind(Box::new("job panicked; handed to its JobHandle"));
+                }
+            }
+        });
+        handle
+    }
+
+    /// Shut down the pool, waiting at most `dur` for the workers to finish.
+    ///
+    /// Closes the job queue so that workers exit once it's empty, then joins
+    /// each worker. Returns the ids of the workers which didn't finish in time. Those
+    /// workers are left running and are joined again when the [ThreadPool] is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let mut my_thread_pool = ThreadPool::new(2);
+    /// assert!(my_thread_pool.shutdown_timeout(Duration::from_secs(1)).is_ok());
+    /// ```
+    pub fn shutdown_timeout(&mut self, dur: Duration) -> Result<(), Vec<usize>> {
+        // Workers which retired while idle have nothing to shut
+        // down, and paused ones would never get through the queue
+        self.reap_retired();
+        self.resume();
+        // With the queue closed, the timer can't block pushing
+        // to it. Delayed jobs which come due from here on are
+        // dropped.
+        self.queue.close();
+
+        // No deadline if dur is too large to represent
+        let deadline = Instant::now().checked_add(dur);
+        let mut unfinished = Vec::new();
+
+        for worker in &mut self.workers {
+            (self.logger)(
+                LogLevel::Info,
+                &format!("Shutting down worker {}", worker.id),
+            );
+
+            // take() the handle so a worker is never joined twice
+            if let Some(thread) = worker.thread.take() {
+                let Some(deadline) = deadline else {
+                    thread.join().unwrap();
+                    continue;
+                };
+
+                while !thread.is_finished() && Instant::now() < deadline {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+
+                if thread.is_finished() {
+                    thread.join().unwrap();
+                } else {
+                    // Put the handle back so Drop can join it later
+                    worker.thread = Some(thread);
+                    unfinished.push(worker.id);
+                }
+            }
+        }
+
+        if !unfinished.is_empty() {
+            return Err(unfinished);
+        }
+        // The timer outlives the workers so jobs still running
+        // can be told they've timed out. Delayed jobs which
+        // aren't due yet are dropped.
+        if let Some(timer) = self.timer.get() {
+            timer.shutdown();
+        }
+        Ok(())
+    }
+
+    /// Shut down the pool, waiting for every worker to finish, then hand back its shared
+    /// context.
+    ///
+    /// Lets state which was given to the pool with [ThreadPool::new_with_context] be
+    /// taken back and used once the pool has stopped. Jobs only borrow the context, so
+    /// once they've all finished the pool holds the only reference to it. Returns `None`
+    /// if some other reference is somehow still alive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Mutex;
+    /// use rust_tutorial_webserver::ThreadPool;
+    /// let my_thread_pool = ThreadPool::new_with_context(2, Mutex::new(Vec::new()));
+    /// for n in 0..4 {
+    ///     my_thread_pool.execute_ctx(move |results| results.lock().unwrap().push(n));
+    /// }
+    /// let results = my_thread_pool.shutdown_into_context().unwrap();
+    /// assert_eq!(4, results.into_inner().unwrap().len());
+    /// ```
+    pub fn shutdown_into_context(mut self) -> Option<C> {
+        // Duration::MAX never times out, so every worker is
+        // joined, and every job holding the context is dropped
+        let _ = self.shutdown_timeout(Duration::MAX);
+        let context = self.context.take()?;
+        Arc::try_unwrap(context).ok()
+    }
+}
+impl<C> Drop for ThreadPool<C> {
+    fn drop(&mut self) {
+        // Duration::MAX never times out, so every worker is joined
+        let _ = self.shutdown_timeout(Duration::MAX);
+    }
+}
+
+// Warn that jobs were dropped because the queue has closed.
+// Panicking instead would take down whichever thread happened
+// to submit them.
+fn log_dropped(logger: &Logger, count: usize) {
+    logger(
+        LogLevel::Warn,
+        &format!("ThreadPool is shutting down; dropped {count} job(s) without running."),
+    );
+}
+
+/// Counters shared between the pool and its workers
+#[derive(Default)]
+struct Counters {
+    // Workers which haven't retired
+    live: AtomicUsize,
+    active: AtomicUsize,
+    panicked: AtomicUsize,
+    // Jobs which ran without panicking, by any worker, retired
+    // or not
+    completed: AtomicUsize,
+    // Jobs waiting in the queue for a worker
+    queued: AtomicUsize,
+    // Jobs finished running, whether or not they panicked
+    finish_rate: RateMeter,
+    // Jobs submitted but not yet finished, whether queued or
+    // running. A Mutex rather than an atomic so join() can wait
+    // on the Condvar for it to reach 0.
+    outstanding: Mutex<usize>,
+    all_done: Condvar,
+}
+impl Counters {
+    fn job_submitted(&self) {
+        self.jobs_submitted(1);
+    }
+
+    fn jobs_submitted(&self, count: usize) {
+        *self.outstanding.lock().unwrap() += count;
+        self.queued.fetch_add(count, Ordering::SeqCst);
+    }
+
+    // Called when a job is submitted but turned away by the queue
+    fn job_rejected(&self) {
+        self.jobs_rejected(1);
+    }
+
+    fn jobs_rejected(&self, count: usize) {
+        self.queued.fetch_sub(count, Ordering::SeqCst);
+        self.jobs_finished(count);
+    }
+
+    // Called when a worker takes a job off the queue
+    fn job_started(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Called when a job finishes running
+    fn job_finished(&self) {
+        self.finish_rate.record();
+        self.jobs_finished(1);
+    }
+
+    fn jobs_finished(&self, count: usize) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        *outstanding -= count;
+        if *outstanding == 0 {
+            self.all_done.notify_all();
+        }
+    }
+
+    // Take an idle worker out of the live count, unless that
+    // would leave fewer than min_workers. Returns whether it can
+    // retire.
+    fn retire_idle(&self, min_workers: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > min_workers).then(|| live - 1)
+            })
+            .is_ok()
+    }
+
+    // The counters in the Prometheus text exposition format
+    fn prometheus(&self) -> String {
+        let workers = self.live.load(Ordering::SeqCst);
+        // A worker may pick up a job after the count is taken,
+        // so don't let active outgrow it
+        let active = self.active.load(Ordering::SeqCst).min(workers);
+        let metrics = [
+            (
+                "threadpool_workers",
+                "gauge",
+                "Number of workers in the pool.",
+                workers,
+            ),
+            (
+                "threadpool_active_workers",
+                "gauge",
+                "Number of workers running a job.",
+                active,
+            ),
+            (
+                "threadpool_idle_workers",
+                "gauge",
+                "Number of workers waiting for a job.",
+                workers - active,
+            ),
+            (
+                "threadpool_queued_jobs",
+                "gauge",
+                "Number of jobs waiting in the queue for a free worker.",
+                self.queued.load(Ordering::SeqCst),
+            ),
+            (
+                "threadpool_jobs_completed_total",
+                "counter",
+                "Number of jobs which have completed without panicking.",
+                self.completed.load(Ordering::SeqCst),
+            ),
+            (
+                "threadpool_jobs_panicked_total",
+                "counter",
+                "Number of jobs which have panicked.",
+                self.panicked.load(Ordering::SeqCst),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            text.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        }
+        text
+    }
+
+    fn wait_all_done(&self) {
+        let outstanding = self.outstanding.lock().unwrap();
+        let _outstanding = self
+            .all_done
+            .wait_while(outstanding, |outstanding| *outstanding > 0)
+            .unwrap();
+    }
+}
+
+/// Marks a worker as active for as long as it's alive
+struct ActiveGuard<'a>(&'a AtomicUsize);
+impl<'a> ActiveGuard<'a> {
+    fn new(active: &'a AtomicUsize) -> ActiveGuard<'a> {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(active)
+    }
+}
+impl Drop for ActiveGuard<'_> {
+    // Runs even if the job panics, so the count never drifts upwards
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// When idle workers exit, from [ThreadPoolBuilder::idle_timeout]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IdlePolicy {
+    pub timeout: Duration,
+    pub min_workers: usize,
+}
+
+/// A function run inside a worker's thread, given the worker's id
+pub(crate) type WorkerHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Functions run inside each worker's thread as it starts and stops
+#[derive(Clone, Default)]
+pub(crate) struct WorkerHooks {
+    pub on_start: Option<WorkerHook>,
+    pub on_stop: Option<WorkerHook>,
+}
+impl WorkerHooks {
+    // Run the start hook, before the worker takes any jobs
+    fn run_start(&self, id: usize, logger: &Logger) {
+        Self::run(self.on_start.as_ref(), "start", id, logger);
+    }
+
+    // Run the stop hook, once the worker has taken its last job
+    fn run_stop(&self, id: usize, logger: &Logger) {
+        Self::run(self.on_stop.as_ref(), "stop", id, logger);
+    }
+
+    // A panicking hook is logged rather than taking the worker
+    // down, same as a panicking job
+    fn run(hook: Option<&WorkerHook>, name: &str, id: usize, logger: &Logger) {
+        let Some(hook) = hook else {
+            return;
+        };
+        if panic::catch_unwind(AssertUnwindSafe(|| hook(id))).is_err() {
+            logger(
+                LogLevel::Warn,
+                &format!("Worker {id} {name} hook panicked; continuing."),
+            );
+        }
+    }
+}
+
+/// A run of panicking jobs in one worker, each soon after the last
+#[derive(Debug, Default)]
+struct PanicStreak {
+    count: usize,
+    last: Option<Instant>,
+}
+impl PanicStreak {
+    // Count a job panicking at now. Returns how long the worker
+    // should back off for, if the streak is long enough to.
+    fn panicked(&mut self, now: Instant) -> Option<Duration> {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) > PANIC_STORM_WINDOW)
+        {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+        let doublings = self.count.checked_sub(PANIC_BACKOFF_AFTER)?;
+        // Capped well before the multiplication could overflow
+        let factor = 1u32 << doublings.min(16);
+        Some((PANIC_BACKOFF * factor).min(MAX_PANIC_BACKOFF))
+    }
+
+    fn is_unhealthy(&self) -> bool {
+        self.count >= PANIC_UNHEALTHY_AFTER
+    }
+}
+
+// The settings for a worker's thread. Named threads are easier
+// to spot in profilers and crash dumps.
+fn worker_thread(prefix: &str, id: usize, stack_size: Option<usize>) -> thread::Builder {
+    let builder = thread::Builder::new().name(format!("{prefix}-{id}"));
+    match stack_size {
+        Some(bytes) => builder.stack_size(bytes),
+        None => builder,
+    }
+}
+
+/// A worker with a given id which can be assigned tasks to do
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+    // Shared with the worker's thread, which counts each job
+    // it finishes without panicking
+    completed: Arc<AtomicUsize>,
+    // Set by the worker's thread when it leaves the pool before
+    // shutdown, because of resize or an idle timeout
+    retired: Arc<AtomicBool>,
+    // Cleared by the worker's thread during a panic storm, and
+    // set again once a job completes
+    healthy: Arc<AtomicBool>,
+}
+impl Worker {
+    pub fn new(
+        id: usize,
+        builder: thread::Builder,
+        queue: Arc<PriorityQueue>,
+        counters: Arc<Counters>,
+        logger: Logger,
+        idle_policy: Option<IdlePolicy>,
+        hooks: WorkerHooks,
+    ) -> io::Result<Worker> {
+        // Closure loops forever, asking receiving end of
+        // channel for a job and running the job when it
+        // gets one.
+        let completed = Arc::new(AtomicUsize::new(0));
+        let thread_completed = Arc::clone(&completed);
+        let retired = Arc::new(AtomicBool::new(false));
+        let thread_retired = Arc::clone(&retired);
+        let healthy = Arc::new(AtomicBool::new(true));
+        let thread_healthy = Arc::clone(&healthy);
+        // Counted before spawning, since the thread may retire
+        // straight away
+        counters.live.fetch_add(1, Ordering::SeqCst);
+        let thread_counters = Arc::clone(&counters);
+        // Registered before spawning, so jobs can be pinned to
+        // the worker straight away
+        queue.register(id);
+        let thread_queue = Arc::clone(&queue);
+        let spawned = builder.spawn(move || {
+            hooks.run_start(id, &logger);
+            let mut panics = PanicStreak::default();
+            loop {
+                let counters = &thread_counters;
+                let queue = &thread_queue;
+                // pop() blocks, so will wait for next job.
+                // The queue's Mutexes ensure only one Worker thread
+                // takes each message, and it returns None once the
+                // pool has shut down and the queue is empty. With
+                // work stealing, the worker's id picks its shard.
+                // With an idle timeout, the worker may retire
+                // instead if nothing turns up in time.
+                let message = match idle_policy {
+                    None => queue.pop(id),
+                    Some(policy) => match queue.pop_timeout(id, policy.timeout) {
+                        Ok(message) => Some(message),
+                        Err(PopError::Closed) => None,
+                        Err(PopError::Timeout) => {
+                            if counters.retire_idle(policy.min_workers) {
+                                thread_retired.store(true, Ordering::SeqCst);
+                                queue.retire(id);
+                                logger(
+                                    LogLevel::Info,
+                                    &format!("Worker {id} idle; shutting down."),
+                                );
+                                break;
+                            }
+                            continue;
+                        }
+                    },
+                };
+                match message {
+                    Some(Message::NewJob(job)) => {
+                        counters.job_started();
+                        logger(LogLevel::Info, &format!("Worker {id} got job; executing."));
+                        let guard = ActiveGuard::new(&counters.active);
+                        // Catch panics so the worker survives a bad job.
+                        // AssertUnwindSafe is fine here: the job is
+                        // consumed, so nothing observes its broken state.
+                        let backoff = match panic::catch_unwind(AssertUnwindSafe(job)) {
+                            Ok(()) => {
+                                thread_completed.fetch_add(1, Ordering::SeqCst);
+                                counters.completed.fetch_add(1, Ordering::SeqCst);
+                                panics = PanicStreak::default();
+                                thread_healthy.store(true, Ordering::SeqCst);
+                                None
+                            }
+                            Err(_) => {
+                                counters.panicked.fetch_add(1, Ordering::SeqCst);
+                                logger(
+                                    LogLevel::Warn,
+                                    &format!("Worker {id} job panicked; continuing."),
+                                );
+                                panics.panicked(Instant::now())
+                            }
+                        };
+                        // Reported before join() can see the job
+                        // finish
+                        if let Some(backoff) = backoff {
+                            let count = panics.count;
+                            if panics.is_unhealthy() {
+                                thread_healthy.store(false, Ordering::SeqCst);
+                                logger(
+                                    LogLevel::Error,
+                                    &format!(
+                                        "Worker {id} unhealthy after {count} panics in a row; \
+                                        backing off for {backoff:?}."
+                                    ),
+                                );
+                            } else {
+                                logger(
+                                    LogLevel::Warn,
+                                    &format!(
+                                        "Worker {id} had {count} panics in a row; \
+                                        backing off for {backoff:?}."
+                                    ),
+                                );
+                            }
+                        }
+                        // No longer active by the time join() sees the
+                        // job finish
+                        drop(guard);
+                        counters.job_finished();
+                        // Slow down rather than spin through a queue
+                        // of jobs which all panic
+                        if let Some(backoff) = backoff {
+                            thread::sleep(backoff);
+                        }
+                    }
+                    Some(Message::Expired(job, waited)) => {
+                        // Counted as started and finished so join()
+                        // and the queue counts stay balanced
+                        counters.job_started();
+                        logger(
+                            LogLevel::Warn,
+                            &format!(
+                                "Worker {id} dropped job which waited {waited:?} in the queue."
+                            ),
+                        );
+                        drop(job);
+                        counters.jobs_finished(1);
+                    }
+                    Some(Message::Terminate) => {
+                        // Flagged before leaving the live count, so
+                        // resize() finds every worker it's waiting on
+                        thread_retired.store(true, Ordering::SeqCst);
+                        queue.retire(id);
+                        counters.live.fetch_sub(1, Ordering::SeqCst);
+                        logger(
+                            LogLevel::Info,
+                            &format!("Worker {id} told to terminate; shutting down."),
+                        );
+                        break;
+                    }
+                    None => {
+                        logger(
+                            LogLevel::Info,
+                            &format!("Worker {id} disconnected; shutting down."),
+                        );
+                        break;
+                    }
+                }
+            }
+            hooks.run_stop(id, &logger);
+        });
+        let thread = spawned.inspect_err(|_| {
+            counters.live.fetch_sub(1, Ordering::SeqCst);
+            queue.retire(id);
+        })?;
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+            completed,
+            retired,
+            healthy,
+        })
+    }
+
+    // Whether the thread hasn't left the pool or been joined
+    fn is_alive(&self) -> bool {
+        !self.retired.load(Ordering::SeqCst)
+            && self
+                .thread
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::Barrier;
+
+    #[test]
+    fn new_ok() {
+        ThreadPool::new(2);
+    }
+
+    #[test]
+    fn new_4() {
+        let tp = ThreadPool::new(4);
+        assert_eq!(4, tp.workers.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_0() {
+        ThreadPool::new(0);
+    }
+
+    #[test]
+    fn build_ok() {
+        ThreadPool::build(4).unwrap();
+    }
+
+    #[test]
+    fn build_2() {
+        let tp = ThreadPool::build(2).unwrap();
+        assert_eq!(2, tp.workers.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn build_0() {
+        ThreadPool::build(0).unwrap();
+    }
+
+    #[test]
+    fn pool_creation_error_display() {
+        match ThreadPool::build(0) {
+            Err(pce) => {
+                assert_eq!(
+                    "Error creating ThreadPool: Invalid size. Given size: 0",
+                    pce.to_string()
+                )
+            }
+            _ => panic!("Should have returned error"),
+        }
+    }
+
+    #[test]
+    fn pool_creation_error_spawn_display() {
+        let pce = PoolCreationError::Spawn {
+            given_size: 2,
+            source: io::Error::other("no threads left"),
+        };
+        assert_eq!(
+            "Error creating ThreadPool: Failed to spawn worker thread. Given size: 2. no threads left",
+            pce.to_string()
+        );
+    }
+
+    #[test]
+    fn pool_creation_error_boxed() {
+        fn try_build() -> Result<ThreadPool, Box<dyn Error>> {
+            Ok(ThreadPool::build(0)?)
+        }
+        let e = match try_build() {
+            Err(e) => e,
+            Ok(_) => panic!("Should have returned error"),
+        };
+        let pce = e.downcast_ref::<PoolCreationError>().unwrap();
+        assert_eq!(0, pce.given_size());
+        assert!(e.source().is_none());
+    }
+
+    #[test]
+    fn pool_creation_error_source() {
+        let pce = PoolCreationError::Spawn {
+            given_size: 3,
+            source: io::Error::other("no threads left"),
+        };
+        assert_eq!(3, pce.given_size());
+        assert_eq!("no threads left", pce.source().unwrap().to_string());
+    }
+
+    #[test]
+    fn worker_thread_name() {
+        let tp = ThreadPool::new(1);
+        let name = tp.execute_returning(|| thread::current().name().map(String::from));
+        assert_eq!(Some(String::from("worker-0")), name.recv().unwrap());
+    }
+
+    // A logger which records every message it's given
+    fn capture_logger() -> (Logger, Arc<Mutex<Vec<String>>>) {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&messages);
+        let logger: Logger = Arc::new(move |level, message| {
+            captured
+                .lock()
+                .unwrap()
+                .push(format!("[{level}] {message}"));
+        });
+        (logger, messages)
+    }
+
+    #[test]
+    fn with_logger_shutdown() {
+        let (logger, messages) = capture_logger();
+        drop(ThreadPool::with_logger(3, logger));
+
+        let messages = messages.lock().unwrap();
+        for id in 0..3 {
+            let shutdown = format!("[INFO] Shutting down worker {id}");
+            assert_eq!(1, messages.iter().filter(|m| **m == shutdown).count());
+        }
+    }
+
+    #[test]
+    fn with_logger_panic() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(1, logger);
+        tp.execute(|| panic!("oh no"));
+        tp.execute_returning(|| ()).recv().unwrap();
+        assert!(messages
+            .lock()
+            .unwrap()
+            .contains(&String::from("[WARN] Worker 0 job panicked; continuing.")));
+    }
+
+    #[test]
+    fn log_level_display() {
+        assert_eq!("INFO", LogLevel::Info.to_string());
+        assert_eq!("WARN", LogLevel::Warn.to_string());
+        assert_eq!("ERROR", LogLevel::Error.to_string());
+    }
+
+    #[test]
+    fn active_idle_count() {
+        let tp = ThreadPool::new(3);
+        assert_eq!(0, tp.active_count());
+        assert_eq!(3, tp.idle_count());
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        assert_eq!(1, tp.active_count());
+        assert_eq!(2, tp.idle_count());
+
+        drop(release_tx);
+        while tp.active_count() != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(3, tp.idle_count());
+    }
+
+    #[test]
+    fn execute_returning_recv() {
+        let tp = ThreadPool::new(2);
+        let result = tp.execute_returning(|| String::from("done"));
+        assert_eq!("done", result.recv().unwrap());
+    }
+
+    #[test]
+    fn execute_returning_try_recv() {
+        let tp = ThreadPool::new(1);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let result = tp.execute_returning(move || {
+            let _ = release_rx.recv();
+            5
+        });
+        assert_eq!(Err(mpsc::TryRecvError::Empty), result.try_recv());
+        drop(release_tx);
+        assert_eq!(Ok(5), result.recv());
+    }
+
+    #[test]
+    fn execute_returning_panic() {
+        let tp = ThreadPool::new(1);
+        let result = tp.execute_returning(|| -> u8 { panic!("oh no") });
+        assert_eq!(Err(mpsc::RecvError), result.recv());
+    }
+
+    #[test]
+    fn execute_with_priority_order() {
+        let mut tp = ThreadPool::new(1);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        // Keep the only worker busy with a slow low-priority job
+        // while the rest queue up
+        tp.execute_with_priority(0, move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        for (priority, name) in [(0, "low 1"), (255, "high 1"), (0, "low 2"), (255, "high 2")] {
+            let log = Arc::clone(&log);
+            tp.execute_with_priority(priority, move || log.lock().unwrap().push(name));
+        }
+        {
+            let log = Arc::clone(&log);
+            tp.execute(move || log.lock().unwrap().push("default"));
+        }
+        drop(release_tx);
+        // Shutting down waits for every queued job to finish
+        tp.shutdown_timeout(Duration::MAX).unwrap();
+
+        assert_eq!(
+            vec!["high 1", "high 2", "default", "low 1", "low 2"],
+            *log.lock().unwrap()
+        );
+    }
+
+    // Runs the newest job first
+    #[derive(Default)]
+    struct LifoQueue(Mutex<Vec<Job>>);
+    impl JobQueue for LifoQueue {
+        fn push(&self, job: Job) {
+            self.0.lock().unwrap().push(job);
+        }
+
+        fn pop(&self) -> Option<Job> {
+            self.0.lock().unwrap().pop()
+        }
+    }
+
+    #[test]
+    fn with_queue_lifo() {
+        let tp = ThreadPool::with_queue(1, Arc::new(LifoQueue::default()));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        // Queued while the only worker is busy
+        let (tx, rx) = mpsc::channel();
+        for i in 0..4 {
+            let tx = tx.clone();
+            tp.execute(move || tx.send(i).unwrap());
+        }
+        drop(release_tx);
+        tp.join();
+        assert_eq!(vec![3, 2, 1, 0], rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn with_queue_fifo() {
+        let tp = ThreadPool::with_queue(1, Arc::new(FifoQueue::new()));
+        let (tx, rx) = mpsc::channel();
+        for i in 0..4 {
+            let tx = tx.clone();
+            tp.execute(move || tx.send(i).unwrap());
+        }
+        tp.join();
+        assert_eq!(vec![0, 1, 2, 3], rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn with_capacity_4() {
+        let tp = ThreadPool::with_capacity(4, 8);
+        assert_eq!(4, tp.workers.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn with_capacity_0() {
+        ThreadPool::with_capacity(0, 8);
+    }
+
+    #[test]
+    fn try_execute_unbounded() {
+        let tp = ThreadPool::new(1);
+        for _ in 0..100 {
+            assert!(tp.try_execute(|| ()).is_ok());
+        }
+    }
+
+    #[test]
+    fn try_execute_full() {
+        let tp = ThreadPool::with_capacity(1, 1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        // Occupy the only worker, then fill the queue
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        assert!(tp.try_execute(|| ()).is_ok());
+
+        let (ran_tx, ran_rx) = mpsc::channel();
+        let rejected = tp
+            .try_execute(move || ran_tx.send("ran").unwrap())
+            .unwrap_err();
+
+        // The closure handed back is intact and still callable
+        rejected();
+        assert_eq!("ran", ran_rx.recv().unwrap());
+        drop(release_tx);
+    }
+
+    #[test]
+    fn panic_count() {
+        let tp = ThreadPool::new(1);
+        assert_eq!(0, tp.panic_count());
+
+        tp.execute(|| panic!("oh no"));
+        // The only worker survived the panic and runs the next job
+        let result = tp.execute_returning(|| "still alive");
+        assert_eq!("still alive", result.recv().unwrap());
+        assert_eq!(1, tp.panic_count());
+    }
+
+    #[test]
+    fn poisoned_queue() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(2, logger);
+        tp.queue.poison();
+
+        let result = tp.execute_returning(|| "still alive");
+        assert_eq!("still alive", result.recv().unwrap());
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.contains("poisoned")));
+    }
+
+    #[test]
+    fn resize_grow_shrink() {
+        let mut tp = ThreadPool::new(2);
+        tp.resize(4);
+        assert_eq!(4, tp.workers.len());
+        assert_eq!(4, tp.idle_count());
+
+        tp.resize(1);
+        assert_eq!(1, tp.workers.len());
+        let result = tp.execute_returning(|| "still working");
+        assert_eq!("still working", result.recv().unwrap());
+    }
+
+    #[test]
+    fn size_follows_resize() {
+        let mut tp = ThreadPool::new(3);
+        assert_eq!(3, tp.size());
+        assert_eq!(5, ThreadPool::build(5).unwrap().size());
+        tp.resize(6);
+        assert_eq!(6, tp.size());
+        tp.resize(2);
+        assert_eq!(2, tp.size());
+    }
+
+    #[test]
+    fn resize_unique_ids() {
+        let mut tp = ThreadPool::new(2);
+        tp.resize(1);
+        tp.resize(3);
+        let mut ids: Vec<usize> = tp.workers.iter().map(|w| w.id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(3, ids.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn resize_0() {
+        ThreadPool::new(2).resize(0);
+    }
+
+    #[test]
+    fn resize_keeps_queued_jobs() {
+        let mut tp = ThreadPool::new(4);
+        let (tx, rx) = mpsc::channel();
+        for n in 0..20 {
+            let tx = tx.clone();
+            tp.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                tx.send(n).unwrap();
+            });
+        }
+        drop(tx);
+
+        tp.resize(1);
+        assert_eq!(20, rx.iter().count());
+    }
+
+    #[test]
+    fn worker_stats_total() {
+        let mut tp = ThreadPool::new(4);
+        for _ in 0..100 {
+            tp.execute(|| ());
+        }
+        // Shutting down waits for every queued job to finish
+        tp.shutdown_timeout(Duration::MAX).unwrap();
+
+        let stats = tp.worker_stats();
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            stats.iter().map(|s| s.0).collect::<Vec<_>>()
+        );
+        assert_eq!(100, stats.iter().map(|s| s.1).sum::<usize>());
+    }
+
+    #[test]
+    fn worker_stats_skip_panics() {
+        let mut tp = ThreadPool::new(1);
+        tp.execute(|| ());
+        tp.execute(|| panic!("oh no"));
+        tp.shutdown_timeout(Duration::MAX).unwrap();
+        assert_eq!(vec![(0, 1)], tp.worker_stats());
+    }
+
+    #[test]
+    fn worker_status_after_shrinking() {
+        let mut tp = ThreadPoolBuilder::new()
+            .size(3)
+            .idle_timeout(Duration::from_millis(20))
+            .min_workers(1)
+            .build()
+            .unwrap();
+        assert_eq!(vec![(0, true), (1, true), (2, true)], tp.worker_status());
+
+        // Wait for the idle workers to retire
+        let start = Instant::now();
+        while tp.metrics().workers > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let status = tp.worker_status();
+        assert_eq!(3, status.len());
+        assert_eq!(1, status.iter().filter(|(_, alive)| *alive).count());
+
+        // Shutting down joins the rest
+        tp.shutdown_timeout(Duration::MAX).unwrap();
+        assert!(tp.worker_status().iter().all(|(_, alive)| !alive));
+    }
+
+    #[test]
+    fn drain_finishes_queued() {
+        let tp = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let count = Arc::clone(&count);
+            tp.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        tp.drain();
+        assert_eq!(10, count.load(Ordering::SeqCst));
+
+        // Still readable, but rejects new jobs
+        assert_eq!(10, tp.metrics().completed);
+        assert!(matches!(
+            tp.try_execute_checked(|| ()),
+            Err(ExecuteError::ShutDown(_))
+        ));
+        let ran = Arc::new(AtomicBool::new(false));
+        let job_ran = Arc::clone(&ran);
+        tp.execute(move || job_ran.store(true, Ordering::SeqCst));
+        tp.join();
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn join_waits() {
+        let tp = ThreadPool::new(4);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..50 {
+            let count = Arc::clone(&count);
+            tp.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        tp.join();
+        assert_eq!(50, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn join_reusable() {
+        let tp = ThreadPool::new(2);
+        // Nothing to wait for
+        tp.join();
+
+        tp.execute(|| panic!("oh no"));
+        tp.join();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let job_count = Arc::clone(&count);
+        tp.execute(move || {
+            job_count.fetch_add(1, Ordering::SeqCst);
+        });
+        tp.join();
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn join_after_rejected() {
+        let tp = ThreadPool::with_capacity(1, 0);
+        // Nobody's guaranteed to be waiting, so this may be
+        // rejected; either way join() mustn't hang
+        let _ = tp.try_execute(|| ());
+        tp.join();
+    }
+
+    #[test]
+    fn execute_ctx_shared() {
+        let tp = ThreadPool::new_with_context(4, AtomicUsize::new(0));
+        for _ in 0..20 {
+            tp.execute_ctx(|count| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        tp.join();
+        assert_eq!(20, tp.context().load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_with_context_0() {
+        ThreadPool::new_with_context(0, ());
+    }
+
+    #[test]
+    fn shutdown_into_context_recovered() {
+        let tp = ThreadPool::new_with_context(4, Mutex::new(Vec::new()));
+        for n in 0..20 {
+            tp.execute_ctx(move |numbers| {
+                thread::sleep(Duration::from_millis(1));
+                numbers.lock().unwrap().push(n);
+            });
+        }
+        // Every queued job runs before the context comes back
+        let mut numbers = tp.shutdown_into_context().unwrap().into_inner().unwrap();
+        numbers.sort();
+        assert_eq!((0..20).collect::<Vec<_>>(), numbers);
+    }
+
+    #[test]
+    fn jobs_per_second_ballpark() {
+        let tp = ThreadPool::new(4);
+        assert_eq!(0.0, tp.jobs_per_second());
+        for _ in 0..100 {
+            tp.execute(|| ());
+        }
+        tp.execute(|| panic!("still counted"));
+        tp.join();
+        // 101 jobs in the last 10 seconds
+        let rate = tp.jobs_per_second();
+        assert!((10.0..=10.2).contains(&rate), "{rate}");
+    }
+
+    #[test]
+    fn queued_jobs_count() {
+        let tp = ThreadPool::new(2);
+        assert_eq!(0, tp.queued_jobs());
+
+        // Hold both workers until the test says otherwise
+        let started = Arc::new(Barrier::new(3));
+        let release = Arc::new(Barrier::new(3));
+        for _ in 0..2 {
+            let started = Arc::clone(&started);
+            let release = Arc::clone(&release);
+            tp.execute(move || {
+                started.wait();
+                release.wait();
+            });
+        }
+        started.wait();
+
+        for _ in 0..5 {
+            tp.execute(|| ());
+        }
+        assert_eq!(5, tp.queued_jobs());
+
+        release.wait();
+        tp.join();
+        assert_eq!(0, tp.queued_jobs());
+    }
+
+    #[test]
+    fn queued_jobs_rejected() {
+        let tp = ThreadPool::with_capacity(1, 0);
+        let _ = tp.try_execute(|| ());
+        tp.join();
+        assert_eq!(0, tp.queued_jobs());
+    }
+
+    #[test]
+    fn shutdown_timeout_ok() {
+        let mut tp = ThreadPool::new(2);
+        assert_eq!(Ok(()), tp.shutdown_timeout(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn shutdown_timeout_unfinished() {
+        let mut tp = ThreadPool::new(1);
+        tp.execute(|| thread::sleep(Duration::from_millis(200)));
+        assert_eq!(Err(vec![0]), tp.shutdown_timeout(Duration::from_millis(10)));
+        // Drop joins the remaining worker without panicking
+    }
+
+    #[test]
+    fn shutdown_timeout_twice() {
+        let mut tp = ThreadPool::new(1);
+        tp.execute(|| thread::sleep(Duration::from_millis(100)));
+        assert_eq!(Err(vec![0]), tp.shutdown_timeout(Duration::ZERO));
+        assert_eq!(Ok(()), tp.shutdown_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn execute_after_delay() {
+        let tp = ThreadPool::new(2);
+        let start = Instant::now();
+        let (tx, rx) = mpsc::channel();
+        tp.execute_after(Duration::from_millis(50), move || {
+            tx.send(Instant::now()).unwrap();
+        });
+
+        // Not run early...
+        assert!(rx.recv_timeout(Duration::from_millis(20)).is_err());
+        // ...but run once the delay has passed
+        let ran_at = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran_at.duration_since(start) >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn execute_after_dropped_on_shutdown() {
+        let tp = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        tp.execute_after(Duration::from_secs(3600), move || tx.send(()).unwrap());
+
+        let start = Instant::now();
+        drop(tp);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // The job was dropped, along with its sender, without running
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn execute_with_timeout_cancels() {
+        let tp = ThreadPool::with_logger(1, Arc::new(|_, _| ()));
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        tp.execute_with_timeout(
+            move |cancel| {
+                // Would run for an hour if not told to stop
+                while !cancel.load(Ordering::SeqCst) && start.elapsed() < Duration::from_secs(3600)
+                {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                tx.send(start.elapsed()).unwrap();
+            },
+            Duration::from_millis(20),
+        );
+
+        let elapsed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(elapsed >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn execute_with_timeout_not_cancelled() {
+        let tp = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        tp.execute_with_timeout(
+            move |cancel| tx.send(cancel).unwrap(),
+            Duration::from_millis(10),
+        );
+
+        // Finished in time, so the flag stays unset
+        let cancel = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cancel.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn work_stealing_busy_worker() {
+        let tp = ThreadPoolBuilder::new()
+            .size(2)
+            .work_stealing(true)
+            .build()
+            .unwrap();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        tp.execute(move || {
+            let _ = release_rx.recv();
+        });
+        let (tx, rx) = mpsc::channel();
+        for n in 0..10 {
+            let tx = tx.clone();
+            tp.execute(move || tx.send(n).unwrap());
+        }
+
+        // Jobs in the busy worker's shard are stolen by the
+        // other, so they all run while it's still busy
+        let mut done: Vec<i32> = (0..10)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!((0..10).collect::<Vec<_>>(), done);
+        drop(release_tx);
+    }
+
+    #[test]
+    fn work_stealing_many_jobs() {
+        // How long these take is compared in the work_stealing
+        // benchmark instead
+        for work_stealing in [false, true] {
+            let tp = ThreadPoolBuilder::new()
+                .size(4)
+                .work_stealing(work_stealing)
+                .build()
+                .unwrap();
+            let count = Arc::new(AtomicUsize::new(0));
+            for _ in 0..100_000 {
+                let count = Arc::clone(&count);
+                tp.execute(move || {
+                    count.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            tp.join();
+            assert_eq!(100_000, count.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn metrics_consistent() {
+        let tp = ThreadPool::new(2);
+        let start = Arc::new(Barrier::new(3));
+        let release = Arc::new(Barrier::new(3));
+        for _ in 0..2 {
+            let start = Arc::clone(&start);
+            let release = Arc::clone(&release);
+            tp.execute(move || {
+                start.wait();
+                release.wait();
+            });
+        }
+        tp.execute(|| ());
+        tp.execute(|| panic!("oh no"));
+
+        // Both workers are busy and two jobs are waiting
+        start.wait();
+        let busy = tp.metrics();
+        assert_eq!(2, busy.workers);
+        assert_eq!(busy.workers, busy.active + busy.idle);
+        assert_eq!(2, busy.active);
+        assert_eq!(2, busy.queued);
+
+        release.wait();
+        tp.join();
+        let done = tp.metrics();
+        assert_eq!(
+            PoolMetrics {
+                workers: 2,
+                active: 0,
+                idle: 2,
+                queued: 0,
+                completed: 3,
+                panicked: 1,
+            },
+            done
+        );
+    }
+
+    #[test]
+    fn metrics_prometheus_format() {
+        let tp = ThreadPool::with_logger(2, capture_logger().0);
+        for _ in 0..3 {
+            tp.execute(|| ());
+        }
+        tp.execute(|| panic!("oh no"));
+        tp.join();
+
+        let text = tp.metrics_prometheus();
+        for expected in [
+            "# TYPE threadpool_workers gauge\nthreadpool_workers 2\n",
+            "# TYPE threadpool_active_workers gauge\nthreadpool_active_workers 0\n",
+            "# TYPE threadpool_idle_workers gauge\nthreadpool_idle_workers 2\n",
+            "# TYPE threadpool_queued_jobs gauge\nthreadpool_queued_jobs 0\n",
+            "# TYPE threadpool_jobs_completed_total counter\nthreadpool_jobs_completed_total 3\n",
+            "# TYPE threadpool_jobs_panicked_total counter\nthreadpool_jobs_panicked_total 1\n",
+        ] {
+            assert!(text.contains(expected), "{text}");
+        }
+        // Every line is a comment or a sample, and every sample
+        // has a valid name, HELP and TYPE
+        let valid_name = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        assert!(text.ends_with('\n'));
+        for line in text.lines() {
+            if line.starts_with('#') {
+                assert!(
+                    line.starts_with("# HELP ") || line.starts_with("# TYPE "),
+                    "{line}"
+                );
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(valid_name(name), "{line}");
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+            assert!(text.contains(&format!("# HELP {name} ")), "{name}");
+            assert!(text.contains(&format!("# TYPE {name} ")), "{name}");
+        }
+    }
+
+    #[test]
+    fn execute_with_panic_handler_called() {
+        let tp = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        tp.execute_with_panic_handler(
+            || panic!("oh no"),
+            move |payload| {
+                let message = payload.downcast_ref::<&str>().map(|s| s.to_string());
+                tx.send(message).unwrap();
+            },
+        );
+
+        assert_eq!(Some(String::from("oh no")), rx.recv().unwrap());
+        tp.join();
+        assert_eq!(1, tp.panic_count());
+        assert_eq!(vec![(0, 0)], tp.worker_stats());
+    }
+
+    #[test]
+    fn execute_with_panic_handler_not_called() {
+        let tp = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        tp.execute_with_panic_handler(|| (), move |_| tx.send(()).unwrap());
+
+        tp.join();
+        // The handler was dropped without being called
+        assert!(rx.recv().is_err());
+        assert_eq!(0, tp.panic_count());
+    }
+
+    #[test]
+    fn execute_then_after_job() {
+        let tp = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let job_tx = tx.clone();
+        tp.execute_then(
+            move || job_tx.send("job").unwrap(),
+            move |succeeded| tx.send(if succeeded { "ok" } else { "failed" }).unwrap(),
+        );
+
+        tp.join();
+        assert_eq!(vec!["job", "ok"], rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(0, tp.panic_count());
+    }
+
+    #[test]
+    fn execute_then_after_panic() {
+        let tp = ThreadPool::with_logger(1, capture_logger().0);
+        let (tx, rx) = mpsc::channel();
+        tp.execute_then(
+            || panic!("oh no"),
+            move |succeeded| tx.send(succeeded).unwrap(),
+        );
+
+        assert!(!rx.recv().unwrap());
+        tp.join();
+        assert_eq!(1, tp.panic_count());
+        // The worker survives to run the next job
+        let (tx, rx) = mpsc::channel();
+        tp.execute_then(|| (), move |succeeded| tx.send(succeeded).unwrap());
+        assert!(rx.recv().unwrap());
+    }
+
+    #[test]
+    fn panic_streak_backoff() {
+        let mut streak = PanicStreak::default();
+        let now = Instant::now();
+        for _ in 1..PANIC_BACKOFF_AFTER {
+            assert_eq!(None, streak.panicked(now));
+        }
+        assert_eq!(Some(PANIC_BACKOFF), streak.panicked(now));
+        assert_eq!(Some(PANIC_BACKOFF * 2), streak.panicked(now));
+        assert_eq!(Some(PANIC_BACKOFF * 4), streak.panicked(now));
+        for _ in 0..100 {
+            streak.panicked(now);
+        }
+        assert_eq!(Some(MAX_PANIC_BACKOFF), streak.panicked(now));
+        assert!(streak.is_unhealthy());
+    }
+
+    #[test]
+    fn panic_streak_window() {
+        let mut streak = PanicStreak::default();
+        let start = Instant::now();
+        for _ in 1..PANIC_BACKOFF_AFTER {
+            streak.panicked(start);
+        }
+        // Too long after the last panic to carry the streak on
+        let later = start + PANIC_STORM_WINDOW * 2;
+        assert_eq!(None, streak.panicked(later));
+        assert_eq!(1, streak.count);
+    }
+
+    #[test]
+    fn panic_storm_backs_off() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(1, logger);
+        let start = Instant::now();
+        for _ in 0..PANIC_BACKOFF_AFTER + 2 {
+            tp.execute(|| panic!("oh no"));
+        }
+        tp.join();
+        // Let the worker finish backing off from the last panic
+        tp.execute_returning(|| ()).recv().unwrap();
+
+        // 1ms, 2ms and 4ms
+        assert!(start.elapsed() >= PANIC_BACKOFF * 7);
+        let messages = messages.lock().unwrap();
+        let backoffs: Vec<_> = messages
+            .iter()
+            .filter(|message| message.contains("backing off"))
+            .collect();
+        assert_eq!(3, backoffs.len(), "{messages:?}");
+        assert!(backoffs[0].starts_with("[WARN] Worker 0 had 5 panics in a row"));
+        assert_eq!(vec![(0, true)], tp.worker_status());
+    }
+
+    #[test]
+    fn panic_storm_unhealthy() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(1, logger);
+        for _ in 0..PANIC_UNHEALTHY_AFTER {
+            tp.execute(|| panic!("oh no"));
+        }
+        tp.join();
+        assert_eq!(vec![(0, false)], tp.worker_status());
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.starts_with("[ERROR] Worker 0 unhealthy after 10 panics")));
+
+        // Healthy again once a job gets through
+        tp.execute_returning(|| ()).recv().unwrap();
+        tp.join();
+        assert_eq!(vec![(0, true)], tp.worker_status());
+    }
+
+    #[test]
+    fn pause_resume() {
+        let tp = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        tp.pause();
+        assert!(tp.is_paused());
+        for _ in 0..5 {
+            let count = Arc::clone(&count);
+            tp.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(0, count.load(Ordering::SeqCst));
+        assert_eq!(5, tp.queued_jobs());
+
+        tp.resume();
+        assert!(!tp.is_paused());
+        tp.join();
+        assert_eq!(5, count.load(Ordering::SeqCst));
+        assert_eq!(0, tp.queued_jobs());
+    }
+
+    #[test]
+    fn pause_finishes_running_job() {
+        let tp = ThreadPool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let running = tp.execute_returning(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        tp.pause();
+        let queued = tp.execute_returning(|| ());
+        drop(release_tx);
+        running.recv().unwrap();
+        assert!(queued.recv_timeout(Duration::from_millis(50)).is_err());
+
+        tp.resume();
+        queued.recv().unwrap();
+    }
+
+    #[test]
+    fn pause_keeps_queue_order() {
+        let tp = ThreadPool::new(1);
+        tp.pause();
+        let (tx, rx) = mpsc::channel();
+        for priority in [1, 2, 3] {
+            let tx = tx.clone();
+            tp.execute_with_priority(priority, move || tx.send(priority).unwrap());
+        }
+        // Jobs sent later still jump ahead of lower priorities
+        thread::sleep(Duration::from_millis(20));
+        let late = tx.clone();
+        tp.execute_with_priority(255, move || late.send(255).unwrap());
+        tp.resume();
+        tp.join();
+        drop(tx);
+        assert_eq!(vec![255, 3, 2, 1], rx.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pause_resize() {
+        let mut tp = ThreadPool::new(4);
+        let count = Arc::new(AtomicUsize::new(0));
+        tp.pause();
+        for _ in 0..8 {
+            let count = Arc::clone(&count);
+            tp.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        tp.resize(2);
+        assert_eq!(2, tp.size());
+        assert!(tp.is_paused());
+        assert_eq!(0, count.load(Ordering::SeqCst));
+        assert_eq!(8, tp.queued_jobs());
+
+        tp.resize(3);
+        tp.resume();
+        tp.join();
+        assert_eq!(8, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn pause_drop() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let tp = ThreadPool::new(2);
+        tp.pause();
+        for _ in 0..3 {
+            let count = Arc::clone(&count);
+            tp.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // Resumed to empty the queue rather than hanging
+        drop(tp);
+        assert_eq!(3, count.load(Ordering::SeqCst));
+
+        let tp = ThreadPool::new(1);
+        tp.pause();
+        tp.execute(|| ());
+        tp.drain();
+        assert!(!tp.is_paused());
+        assert_eq!(0, tp.queued_jobs());
+    }
+
+    #[test]
+    fn spawn_join() {
+        let tp = ThreadPool::new(2);
+        let job = tp.spawn(|_| String::from("done"));
+        assert_eq!("done", job.join().unwrap());
+    }
+
+    #[test]
+    fn spawn_cancel_running() {
+        let tp = ThreadPool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let job = tp.spawn(move |cancel| {
+            started_tx.send(()).unwrap();
+            let mut polls = 0;
+            while !cancel.load(Ordering::SeqCst) {
+                polls += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            polls
+        });
+        started_rx.recv().unwrap();
+        assert!(!job.is_finished());
+        job.cancel();
+        // The job saw the flag and returned early
+        assert!(job.join().is_ok());
+    }
+
+    #[test]
+    fn spawn_cancel_before_start() {
+        let tp = ThreadPool::new(1);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        tp.execute(move || {
+            let _ = release_rx.recv();
+        });
+        let ran = Arc::new(AtomicBool::new(false));
+        let job = {
+            let ran = Arc::clone(&ran);
+            tp.spawn(move |_| ran.store(true, Ordering::SeqCst))
+        };
+        job.cancel();
+        drop(release_tx);
+
+        assert!(matches!(job.join(), Err(JobError::Cancelled)));
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spawn_panic() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(1, logger);
+        let job = tp.spawn(|_| -> u8 { panic!("oh no") });
+
+        let Err(JobError::Panicked(payload)) = job.join() else {
+            panic!("job should have panicked");
+        };
+        assert_eq!(Some(&"oh no"), payload.downcast_ref::<&str>());
+        tp.join();
+        // The worker still counts and logs it
+        assert_eq!(1, tp.panic_count());
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.contains("job panicked")));
+    }
+
+    #[test]
+    fn spawn_after_drain() {
+        let tp = ThreadPool::new(1);
+        tp.drain();
+        let job = tp.spawn(|_| 5);
+        assert!(job.is_finished());
+        assert!(matches!(job.join(), Err(JobError::Dropped)));
+    }
+
+    #[test]
+    fn execute_on_pinned() {
+        let tp = ThreadPool::new(4);
+        for _ in 0..20 {
+            tp.execute_on(0, || thread::sleep(Duration::from_millis(1)))
+                .unwrap();
+        }
+        tp.join();
+        let stats = tp.worker_stats();
+        assert_eq!((0, 20), stats[0]);
+        assert!(stats[1..].iter().all(|(_, completed)| *completed == 0));
+    }
+
+    #[test]
+    fn execute_on_unknown_worker() {
+        let mut tp = ThreadPool::new(2);
+        let e = tp.execute_on(2, || ()).unwrap_err();
+        assert!(matches!(e, ExecuteError::NoSuchWorker(_)));
+        assert_eq!("Error executing job: No such worker", e.to_string());
+        assert_eq!(0, tp.queued_jobs());
+
+        // Nor once the worker has left the pool
+        tp.resize(1);
+        let gone = if tp.execute_on(0, || ()).is_ok() {
+            1
+        } else {
+            0
+        };
+        assert!(tp.execute_on(gone, || ()).is_err());
+        tp.join();
+    }
+
+    #[test]
+    fn execute_with_retry_until_ok() {
+        let (logger, messages) = capture_logger();
+        let tp = ThreadPool::with_logger(1, logger);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let job_runs = Arc::clone(&runs);
+        tp.execute_with_retry(5, move || match job_runs.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Err("not yet"),
+            _ => Ok(()),
+        });
+        tp.join();
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        let failures = messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.starts_with("[WARN] Job failed"))
+            .count();
+        assert_eq!(2, failures);
+    }
+
+    #[test]
+    fn execute_with_retry_gives_up() {
+        let tp = ThreadPool::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let job_runs = Arc::clone(&runs);
+        tp.execute_with_retry(2, move || {
+            job_runs.fetch_add(1, Ordering::SeqCst);
+            Err("never")
+        });
+        tp.join();
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn execute_batch_all_run() {
+        let tp = ThreadPool::new(4);
+        let count = Arc::new(AtomicUsize::new(0));
+        tp.execute_batch((0..1000).map(|_| {
+            let count = Arc::clone(&count);
+            move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+
+        tp.join();
+        assert_eq!(1000, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn execute_batch_bounded() {
+        let tp = ThreadPool::with_capacity(2, 1);
+        let (tx, rx) = mpsc::channel();
+        tp.execute_batch((0..50).map(|n| {
+            let tx = tx.clone();
+            move || tx.send(n).unwrap()
+        }));
+
+        tp.join();
+        let mut results: Vec<i32> = rx.try_iter().collect();
+        results.sort();
+        assert_eq!((0..50).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn try_execute_checked_shut_down() {
+        let mut tp = ThreadPool::new(2);
+        tp.shutdown_timeout(Duration::from_secs(5)).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let e = tp
+            .try_execute_checked(move || tx.send(7).unwrap())
+            .unwrap_err();
+        assert!(matches!(e, ExecuteError::ShutDown(_)));
+        assert_eq!(
+            "Error executing job: ThreadPool is shutting down",
+            e.to_string()
+        );
+        // The closure came back intact and still works
+        e.into_inner()();
+        assert_eq!(7, rx.recv().unwrap());
+        tp.join();
+    }
+
+    #[test]
+    fn execute_after_shutdown_dropped() {
+        let (logger, messages) = capture_logger();
+        let mut tp = ThreadPool::with_logger(2, logger);
+        tp.shutdown_timeout(Duration::from_secs(5)).unwrap();
+
+        // Neither panics
+        let (tx, rx) = mpsc::channel::<()>();
+        let batch_tx = tx.clone();
+        tp.execute(move || tx.send(()).unwrap());
+        tp.execute_batch((0..3).map(|_| {
+            let tx = batch_tx.clone();
+            move || tx.send(()).unwrap()
+        }));
+        drop(batch_tx);
+        assert!(rx.recv().is_err());
+        tp.join();
+
+        let messages = messages.lock().unwrap();
+        assert!(messages.contains(&String::from(
+            "[WARN] ThreadPool is shutting down; dropped 1 job(s) without running."
+        )));
+        assert!(messages.contains(&String::from(
+            "[WARN] ThreadPool is shutting down; dropped 3 job(s) without running."
+        )));
+    }
+
+    #[test]
+    fn try_execute_checked_full() {
+        let tp = ThreadPool::with_capacity(1, 0);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        tp.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        let e = tp.try_execute_checked(|| ()).unwrap_err();
+        assert_eq!("Full(..)", format!("{e:?}"));
+        drop(release_tx);
+    }
 }
@@ -1,87 +1,319 @@
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    env,
+    error::Error,
+    io,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     thread,
-    time::Duration,
 };
 
-use rust_tutorial_webserver::ThreadPool;
+use signal_hook::consts::SIGTERM;
 
-const STATUS_LINE_200: &str = "HTTP/1.1 200 OK";
-const STATUS_LINE_404: &str = "HTTP/1.1 404 NOT FOUND";
+use rust_tutorial_webserver::{
+    default_logger, serve_listener,
+    server::{
+        bind_reusable, default_router, handle_connection, health_check, prometheus_metrics,
+        ServeOptions,
+    },
+    ConnectionHandler, ThreadPool,
+};
 
-const MAIN_PAGE: &str = "welcome.html";
-const PAGE_404: &str = "404.html";
+// Where the server listens unless told otherwise on the
+// command line
+const DEFAULT_ADDR: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 7878;
+const USAGE: &str = "Usage: rust-tutorial-webserver [--addr ADDR] [--port PORT] [ADDR:PORT]";
 
-const REQUEST_LINE_MAIN: &str = "GET / HTTP/1.1";
-const REQUEST_LINE_SLEEP: &str = "GET /sleep HTTP/1.1";
+const THREAD_POOL_SIZE_VAR: &str = "WEBSERVER_THREADS";
+const DEFAULT_THREAD_POOL_SIZE: usize = 4;
 
-const THREAD_POOL_SIZE: usize = 4;
+// Where the pool's load is reported, for orchestrators
+const HEALTH_PATH: &str = "/healthz";
+// Where the pool's metrics are scraped by Prometheus
+const METRICS_PATH: &str = "/metrics";
 
 const REQS_BEFORE_SHUTDOWN: usize = 16;
 
-fn main() {
-    // Listen at local address '127.0.0.1:7878' for incoming
-    // TCP streams
-
-    // Bind to ports. unwrap() stops program if errors happen.
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+// Connections handled at once before new ones are refused
+const MAX_CONNECTIONS: usize = 256;
+// Connections waiting to be accepted before the OS refuses more
+const LISTEN_BACKLOG: i32 = 128;
 
+fn main() {
     // Thread pool: Group of spawned threads that are waiting
     // and ready to handle a task.
 
     // Must limit pool size to avoid DoS attacks
 
-    // Create a new thread pool with THREAD_POOL_SIZE threads
-    let t_pool = ThreadPool::new(THREAD_POOL_SIZE);
+    let addr = match listen_addr(env::args().skip(1)) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Error: {e}\n{USAGE}");
+            return;
+        }
+    };
 
-    // incoming() returns iterator that gives sequence of
-    // streams.
-    // stream = open connection between client & server.
+    // Create a new thread pool, sized from the environment
+    let t_pool = ThreadPool::new(pool_size_from_env());
 
-    // Process each connection & produce a series of streams
-    // to handle
-    for stream in listener.incoming().take(REQS_BEFORE_SHUTDOWN) {
-        let stream = stream.unwrap();
+    // Routes are shared read-only between all the workers.
+    // The health check and metrics report on the pool through
+    // handles, since serving takes ownership of the pool itself.
+    let mut router = default_router();
+    router.insert_handler("GET", HEALTH_PATH, health_check(t_pool.handle()));
+    router.insert_handler("GET", METRICS_PATH, prometheus_metrics(t_pool.handle()));
+    let router = Arc::new(router);
+    let logger = default_logger();
 
-        // pool.execute takes a closure and gives it to a thread
-        // in the pool to run
-        t_pool.execute(|| handle_connection(stream));
+    // Set once enough connections have been handled, or on
+    // SIGTERM, which stops the server. Connections already
+    // accepted are drained before it exits.
+    let stop = Arc::new(AtomicBool::new(false));
+    let handled = Arc::new(AtomicUsize::new(0));
+    if let Err(e) = stop_on_sigterm(&stop) {
+        eprintln!("Error installing SIGTERM handler: {e}");
     }
-    println!("{REQS_BEFORE_SHUTDOWN} requests received. Shutting down.");
-}
 
-fn handle_connection(mut stream: TcpStream) {
-    // Create new BufReader instance that wraps a mutable
-    // reference to the stream. BufReader adds buffering by
-    // managing calls to the std::io::Read trait methods
-    let buf_reader = BufReader::new(&mut stream);
-
-    // Read first line of HTTP request
-    // Call next() to get first item from iterator
-    // First unwrap handles Option, stops if no items
-    // Second unwrap handles Result, stops if invalid request
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
-
-    let (status_line, filename) = match &request_line[..] {
-        REQUEST_LINE_MAIN => (STATUS_LINE_200, MAIN_PAGE),
-        // Simulated slow response
-        REQUEST_LINE_SLEEP => {
-            thread::sleep(Duration::from_secs(5));
-            (STATUS_LINE_200, MAIN_PAGE)
+    // Each connection is given to a thread in the pool to
+    // run this
+    let handler_stop = Arc::clone(&stop);
+    let handler_handled = Arc::clone(&handled);
+    let handler: ConnectionHandler = Arc::new(move |stream| {
+        let peer = stream.peer();
+        if let Err(e) = handle_connection(stream, &router, &logger) {
+            eprintln!("Error handling connection from {peer}: {}", causes(&e));
+        }
+        if handler_handled.fetch_add(1, Ordering::SeqCst) + 1 >= REQS_BEFORE_SHUTDOWN {
+            handler_stop.store(true, Ordering::SeqCst);
+        }
+    });
+
+    // Listen at the address from the command line, by default
+    // local address '127.0.0.1:7878', for incoming TCP
+    // streams. The address can be reused straight away, so the
+    // server can be restarted without waiting.
+    let listener = match bind_reusable(&addr, LISTEN_BACKLOG) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error binding {addr}: {e}");
+            return;
         }
-        _ => (STATUS_LINE_404, PAGE_404),
     };
+    // Serve until stopped
+    if let Err(e) = serve_listener(
+        listener,
+        t_pool,
+        handler,
+        stop,
+        ServeOptions::new().max_connections(MAX_CONNECTIONS),
+    ) {
+        eprintln!("Error serving {addr}: {}", causes(&e));
+        return;
+    }
+    if handled.load(Ordering::SeqCst) >= REQS_BEFORE_SHUTDOWN {
+        println!("{REQS_BEFORE_SHUTDOWN} requests received. Shut down.");
+    } else {
+        println!("Terminated. Shut down.");
+    }
+}
+
+// Set stop when the process is sent SIGTERM, as orchestrators
+// do before killing a container, instead of exiting straight
+// away
+fn stop_on_sigterm(stop: &Arc<AtomicBool>) -> io::Result<()> {
+    signal_hook::flag::register(SIGTERM, Arc::clone(stop)).map(|_| ())
+}
+
+// Work out the address to listen at from the command line
+// arguments, not counting the program name. Takes `--addr` and
+// `--port`, or both at once as `ADDR:PORT`, later arguments
+// overriding earlier ones. Anything missing is the default.
+fn listen_addr(args: impl IntoIterator<Item = String>) -> Result<String, String> {
+    let mut addr = String::from(DEFAULT_ADDR);
+    let mut port = DEFAULT_PORT;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = args.next().ok_or("--addr needs a value")?,
+            "--port" => port = parse_port(&args.next().ok_or("--port needs a value")?)?,
+            option if option.starts_with('-') => {
+                return Err(format!("Unknown option {option}"));
+            }
+            both => {
+                let (both_addr, both_port) = both
+                    .rsplit_once(':')
+                    .filter(|(both_addr, _)| !both_addr.is_empty())
+                    .ok_or_else(|| format!("Expected ADDR:PORT, got {both}"))?;
+                addr = both_addr.to_string();
+                port = parse_port(both_port)?;
+            }
+        }
+    }
+    if addr.is_empty() {
+        return Err(String::from("--addr can't be empty"));
+    }
+    // IPv6 addresses need brackets to tell them from the port
+    if addr.contains(':') && !addr.starts_with('[') {
+        addr = format!("[{addr}]");
+    }
+    Ok(format!("{addr}:{port}"))
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse()
+        .map_err(|_| format!("Invalid port {port}; expected a number from 0 to 65535"))
+}
+
+// Pick the thread pool size from WEBSERVER_THREADS, falling
+// back to the available parallelism, then to a fixed default.
+fn pool_size_from_env() -> usize {
+    let size = parse_pool_size(env::var(THREAD_POOL_SIZE_VAR).ok())
+        .unwrap_or_else(|| default_pool_size(thread::available_parallelism()));
+    println!("Using thread pool size {size}.");
+    size
+}
+
+// The pool size to use when none is given: the available
+// parallelism, if it can be found. It can't always be, e.g. in
+// some containers, so fall back to a fixed default.
+fn default_pool_size(parallelism: io::Result<NonZeroUsize>) -> usize {
+    match parallelism {
+        Ok(parallelism) => parallelism.get(),
+        Err(e) => {
+            eprintln!(
+                "Warning: couldn't get available parallelism ({e}); \
+                 using thread pool size {DEFAULT_THREAD_POOL_SIZE}."
+            );
+            DEFAULT_THREAD_POOL_SIZE
+        }
+    }
+}
+
+// An error's message followed by those of everything which
+// caused it, e.g. "Server I/O error: connection reset"
+fn causes(e: &(dyn Error + 'static)) -> String {
+    std::iter::successors(Some(e), |&e| e.source())
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+// Parse a thread pool size, clamping it to at least 1.
+// Returns None if the value is missing or not a number.
+fn parse_pool_size(value: Option<String>) -> Option<usize> {
+    let size: usize = value?.trim().parse().ok()?;
+    Some(size.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_tutorial_webserver::server::ServerError;
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_on_sigterm_sets_flag() {
+        let stop = Arc::new(AtomicBool::new(false));
+        stop_on_sigterm(&stop).unwrap();
+        // The handler runs before raise returns
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert!(stop.load(Ordering::SeqCst));
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn listen_addr_default() {
+        assert_eq!(Ok(String::from("127.0.0.1:7878")), listen_addr(args(&[])));
+    }
+
+    #[test]
+    fn listen_addr_options() {
+        assert_eq!(
+            Ok(String::from("0.0.0.0:8080")),
+            listen_addr(args(&["--addr", "0.0.0.0", "--port", "8080"]))
+        );
+        assert_eq!(
+            Ok(String::from("127.0.0.1:3000")),
+            listen_addr(args(&["--port", "3000"]))
+        );
+        assert_eq!(
+            Ok(String::from("[::1]:7878")),
+            listen_addr(args(&["--addr", "::1"]))
+        );
+    }
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+    #[test]
+    fn listen_addr_positional() {
+        assert_eq!(
+            Ok(String::from("localhost:8000")),
+            listen_addr(args(&["localhost:8000"]))
+        );
+        assert_eq!(Ok(String::from("[::]:80")), listen_addr(args(&["[::]:80"])));
+        // Later arguments win
+        assert_eq!(
+            Ok(String::from("localhost:9000")),
+            listen_addr(args(&["localhost:8000", "--port", "9000"]))
+        );
+    }
+
+    #[test]
+    fn listen_addr_malformed() {
+        for bad in [
+            &["--port"][..],
+            &["--port", "http"],
+            &["--port", "70000"],
+            &["--addr"],
+            &["--addr", ""],
+            &["--verbose"],
+            &["localhost"],
+            &[":8080"],
+            &["localhost:"],
+        ] {
+            assert!(listen_addr(args(bad)).is_err(), "{bad:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parse_pool_size_ok() {
+        assert_eq!(Some(8), parse_pool_size(Some(String::from("8"))));
+        assert_eq!(Some(3), parse_pool_size(Some(String::from(" 3 "))));
+    }
+
+    #[test]
+    fn parse_pool_size_clamped() {
+        assert_eq!(Some(1), parse_pool_size(Some(String::from("0"))));
+    }
+
+    #[test]
+    fn default_pool_size_parallelism() {
+        assert_eq!(6, default_pool_size(Ok(NonZeroUsize::new(6).unwrap())));
+        assert_eq!(1, default_pool_size(Ok(NonZeroUsize::MIN)));
+    }
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    #[test]
+    fn default_pool_size_error() {
+        let e = io::Error::new(io::ErrorKind::Unsupported, "no cgroup");
+        assert_eq!(DEFAULT_THREAD_POOL_SIZE, default_pool_size(Err(e)));
+    }
 
-    // write_all() takes &[u8] & sends those bytes directly down
-    // the connection
-    // write_all() can fail, so using unwrap() for simplicity.
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    #[test]
+    fn causes_chain() {
+        let e = ServerError::from(io::Error::other("connection reset"));
+        assert_eq!("Server I/O error: connection reset", causes(&e));
+    }
+
+    #[test]
+    fn parse_pool_size_invalid() {
+        assert_eq!(None, parse_pool_size(None));
+        assert_eq!(None, parse_pool_size(Some(String::new())));
+        assert_eq!(None, parse_pool_size(Some(String::from("-2"))));
+        assert_eq!(None, parse_pool_size(Some(String::from("lots"))));
+    }
 }
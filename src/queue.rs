@@ -0,0 +1,934 @@
+//! The queue of jobs shared between a ThreadPool and its workers.
+
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Condvar, LockResult, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{ExecuteError, Job, JobQueue, LogLevel, Logger, ThreadPool};
+
+/// A message sent down the job queue to the workers
+pub(crate) enum Message {
+    /// A job for a worker to run
+    NewJob(Job),
+    /// Tells whichever worker receives it to exit
+    Terminate,
+    /// A job which waited in the queue for longer than it's allowed to, along with how
+    /// long it waited. The worker should drop it without running it.
+    Expired(Job, Duration),
+}
+
+/// Why [PriorityQueue::pop_timeout] didn't return a message
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PopError {
+    /// Nothing arrived in time
+    Timeout,
+    /// The queue is closed and empty
+    Closed,
+}
+
+/// A message waiting in the queue
+struct Prioritized {
+    priority: u8,
+    // Order the message was pushed in, so messages with equal
+    // priority come out first-in, first-out
+    seq: u64,
+    message: Message,
+    queued_at: Instant,
+}
+impl Ord for Prioritized {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap: higher priority first, then
+        // lower (earlier) seq first
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+impl PartialOrd for Prioritized {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Prioritized {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Prioritized {}
+
+#[derive(Default)]
+struct State {
+    next_seq: u64,
+    // Number of workers blocked in pop()
+    waiting: usize,
+    // Once closed, nothing more can be pushed and workers exit
+    // when the queue is empty
+    closed: bool,
+    // Terminate messages to hand out ahead of anything else,
+    // even while paused
+    dismissed: usize,
+}
+
+/// One worker's share of the queued messages
+#[derive(Default)]
+struct Shard {
+    heap: Mutex<BinaryHeap<Prioritized>>,
+    // Messages in heap, readable without taking its lock
+    len: AtomicUsize,
+}
+
+/// A queue which hands out the highest-priority message first.
+///
+/// Optionally bounded, in which case pushing waits for room.
+///
+/// The messages can be split between several shards, each with its own lock. Pushes go
+/// to the shortest shard, and poppers look in their home shard first, then steal from
+/// the others, so workers with work waiting rarely contend on the same lock. Priority
+/// order then only holds within each shard. With one shard, it holds for the whole queue.
+pub(crate) struct PriorityQueue {
+    // Held while pushing, and by poppers about to wait, so no
+    // push goes unnoticed
+    state: Mutex<State>,
+    shards: Vec<Shard>,
+    // Messages in all the shards
+    len: AtomicUsize,
+    // Signalled when a message is pushed or the queue is closed
+    available: Condvar,
+    // Signalled when a message is popped, for bounded queues
+    space: Condvar,
+    capacity: Option<usize>,
+    // Jobs which wait longer than this are handed out as
+    // Message::Expired
+    max_age: Option<Duration>,
+    // Jobs pinned to a particular worker, first-in, first-out,
+    // keyed by worker id. Only workers which can still take jobs
+    // have an entry.
+    pinned: Mutex<HashMap<usize, VecDeque<Prioritized>>>,
+    // Messages in pinned, readable without taking its lock
+    pinned_len: AtomicUsize,
+    // While set, nothing is taken from the shards or pinned,
+    // so poppers wait on available as if the queue was empty.
+    // Only cleared with the state lock held, so no popper
+    // misses the wakeup.
+    paused: AtomicBool,
+    // If set, jobs wait here instead, and the heaps hold a
+    // stand-in for each. Taking a stand-in takes whichever job
+    // this hands out, with how long that job has waited.
+    jobs: Option<Arc<dyn JobQueue>>,
+    logger: Logger,
+}
+impl PriorityQueue {
+    /// Create a new queue holding at most `capacity` waiting messages, or any number
+    /// if `None`, split between `shards` shards (at least 1).
+    ///
+    /// `logger` is warned if one of the queue's locks is ever found poisoned.
+    pub fn new(capacity: Option<usize>, shards: usize, logger: Logger) -> PriorityQueue {
+        PriorityQueue {
+            state: Mutex::new(State::default()),
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+            len: AtomicUsize::new(0),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity,
+            max_age: None,
+            pinned: Mutex::new(HashMap::new()),
+            pinned_len: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+            jobs: None,
+            logger,
+        }
+    }
+
+    /// Hand out jobs which have waited in the queue for longer than `max_age` as
+    /// [Message::Expired], so they can be dropped rather than run. `None` means jobs can
+    /// wait for as long as it takes.
+    pub fn expire_after(mut self, max_age: Option<Duration>) -> PriorityQueue {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Keep jobs in `jobs`, letting it choose the order they run in, rather than in
+    /// priority order. Control messages still go through this queue.
+    pub fn job_queue(mut self, jobs: Option<Arc<dyn JobQueue>>) -> PriorityQueue {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Push a message, blocking while the queue is full.
+    ///
+    /// Hands the message back if the queue has been closed.
+    pub fn push(&self, priority: u8, message: Message) -> Result<(), Message> {
+        let mut state = self.lock();
+        while !state.closed && !self.has_room(&state) {
+            state = self.recover(self.space.wait(state));
+        }
+        if state.closed {
+            return Err(message);
+        }
+        self.push_locked(&mut state, priority, message);
+        Ok(())
+    }
+
+    /// Push a job, blocking while the queue is full.
+    ///
+    /// Like [PriorityQueue::push], but hands `f` back exactly as it was passed in if the
+    /// queue has been closed.
+    pub fn push_job<F>(&self, priority: u8, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+        while !state.closed && !self.has_room(&state) {
+            state = self.recover(self.space.wait(state));
+        }
+        if state.closed {
+            return Err(f);
+        }
+        self.push_locked(&mut state, priority, Message::NewJob(Box::new(f)));
+        Ok(())
+    }
+
+    /// Push several messages with the same priority, taking the lock once unless the
+    /// queue fills up part way through.
+    ///
+    /// Blocks while the queue is full, like [PriorityQueue::push]. Hands back the
+    /// messages which weren't pushed if the queue is closed.
+    pub fn push_batch(
+        &self,
+        priority: u8,
+        messages: impl IntoIterator<Item = Message>,
+    ) -> Result<(), Vec<Message>> {
+        let mut messages = messages.into_iter();
+        let mut state = self.lock();
+        while let Some(message) = messages.next() {
+            while !state.closed && !self.has_room(&state) {
+                state = self.recover(self.space.wait(state));
+            }
+            if state.closed {
+                return Err(std::iter::once(message).chain(messages).collect());
+            }
+            self.push_locked(&mut state, priority, message);
+        }
+        Ok(())
+    }
+
+    /// Push a job without blocking.
+    ///
+    /// Hands `f` back, saying why, if the queue is full or closed. The check happens
+    /// before `f` is boxed, so the caller gets back exactly what it passed in.
+    pub fn try_push<F>(&self, priority: u8, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(ExecuteError::ShutDown(f));
+        }
+        if !self.has_room(&state) {
+            return Err(ExecuteError::Full(f));
+        }
+        self.push_locked(&mut state, priority, Message::NewJob(Box::new(f)));
+        Ok(())
+    }
+
+    /// Push a job which only the worker with id `worker` may take, blocking while the
+    /// queue is full.
+    ///
+    /// Hands `f` back, saying why, if the queue has been closed or there's no such
+    /// worker taking jobs. Pinned jobs run in the order they're pushed, ahead of any
+    /// others for that worker.
+    pub fn push_to<F>(&self, worker: usize, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+        while !state.closed && !self.has_room(&state) {
+            state = self.recover(self.space.wait(state));
+        }
+        if state.closed {
+            return Err(ExecuteError::ShutDown(f));
+        }
+        let mut pinned = self.lock_pinned();
+        let Some(jobs) = pinned.get_mut(&worker) else {
+            return Err(ExecuteError::NoSuchWorker(f));
+        };
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        jobs.push_back(Prioritized {
+            priority: 0,
+            seq,
+            message: Message::NewJob(Box::new(f)),
+            queued_at: Instant::now(),
+        });
+        self.pinned_len.fetch_add(1, AtomicOrdering::SeqCst);
+        self.len.fetch_add(1, AtomicOrdering::SeqCst);
+        // Only one worker can take it, and notify_one might
+        // wake a different one
+        self.available.notify_all();
+        Ok(())
+    }
+
+    /// Let jobs be pinned to the worker with id `worker`.
+    pub fn register(&self, worker: usize) {
+        self.lock_pinned().entry(worker).or_default();
+    }
+
+    /// Stop jobs being pinned to the worker with id `worker`, as it's leaving, moving
+    /// any still waiting for it back into the queue for any worker to take.
+    pub fn retire(&self, worker: usize) {
+        let Some(jobs) = self.lock_pinned().remove(&worker) else {
+            return;
+        };
+        if jobs.is_empty() {
+            return;
+        }
+        let mut state = self.lock();
+        for prioritized in jobs {
+            self.pinned_len.fetch_sub(1, AtomicOrdering::SeqCst);
+            self.len.fetch_sub(1, AtomicOrdering::SeqCst);
+            // Pushed even if the queue has closed since, as
+            // they were accepted before it did
+            self.push_locked(
+                &mut state,
+                ThreadPool::DEFAULT_PRIORITY,
+                prioritized.message,
+            );
+        }
+    }
+
+    /// Push a message regardless of capacity, as long as the queue is open.
+    ///
+    /// Used for control messages, which shouldn't wait behind a full queue.
+    pub fn push_unbounded(&self, priority: u8, message: Message) -> Result<(), Message> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(message);
+        }
+        self.push_locked(&mut state, priority, message);
+        Ok(())
+    }
+
+    /// Take the highest-priority message, blocking until one is available.
+    ///
+    /// Looks in shard `home` first. Returns `None` once the queue is closed and empty.
+    pub fn pop(&self, home: usize) -> Option<Message> {
+        // Most of the time there's something waiting, and only
+        // a shard's lock is needed to take it
+        if let Some(message) = self.take(home) {
+            self.made_room();
+            return Some(message);
+        }
+        let mut state = self.lock();
+        loop {
+            if let Some(message) = self.dismiss_one(&mut state) {
+                return Some(message);
+            }
+            // Pushes hold the state lock, so nothing can slip in
+            // between this last look and waiting
+            if let Some(message) = self.take(home) {
+                self.space.notify_one();
+                return Some(message);
+            }
+            if self.finished(&state) {
+                return None;
+            }
+            state.waiting += 1;
+            // A waiting worker makes room in a bounded queue
+            self.space.notify_one();
+            state = self.recover(self.available.wait(state));
+            state.waiting -= 1;
+        }
+    }
+
+    /// Like [PriorityQueue::pop], but gives up once `timeout` has passed without a
+    /// message arriving.
+    pub fn pop_timeout(&self, home: usize, timeout: Duration) -> Result<Message, PopError> {
+        // No deadline if timeout is too large to represent
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return self.pop(home).ok_or(PopError::Closed);
+        };
+        if let Some(message) = self.take(home) {
+            self.made_room();
+            return Ok(message);
+        }
+        let mut state = self.lock();
+        loop {
+            if let Some(message) = self.dismiss_one(&mut state) {
+                return Ok(message);
+            }
+            if let Some(message) = self.take(home) {
+                self.space.notify_one();
+                return Ok(message);
+            }
+            if self.finished(&state) {
+                return Err(PopError::Closed);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PopError::Timeout);
+            }
+            state.waiting += 1;
+            self.space.notify_one();
+            let result = self.available.wait_timeout(state, deadline - now);
+            state = self.recover(
+                result
+                    .map(|(state, _)| state)
+                    .map_err(|poisoned| PoisonError::new(poisoned.into_inner().0)),
+            );
+            state.waiting -= 1;
+        }
+    }
+
+    /// Stop handing out messages until [PriorityQueue::resume] is called, leaving them
+    /// queued in order.
+    ///
+    /// Once this returns, nothing more is taken; a message taken before then is the
+    /// popper's to handle. Messages can still be pushed, up to the capacity.
+    pub fn pause(&self) {
+        self.paused.store(true, AtomicOrdering::SeqCst);
+        // take() checks the flag with a lock held. Passing
+        // through each lock waits out any take which saw it
+        // unset.
+        drop(self.lock_pinned());
+        for shard in &self.shards {
+            drop(self.lock_shard(shard));
+        }
+    }
+
+    /// Hand out messages again after [PriorityQueue::pause].
+    pub fn resume(&self) {
+        let _state = self.lock();
+        self.paused.store(false, AtomicOrdering::SeqCst);
+        self.available.notify_all();
+    }
+
+    /// Check whether the queue is [paused](PriorityQueue::pause).
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Hand out `count` [Message::Terminate]s ahead of everything queued, even while
+    /// paused, so that many poppers leave straight away.
+    pub fn dismiss(&self, count: usize) {
+        self.lock().dismissed += count;
+        self.available.notify_all();
+    }
+
+    /// Stop accepting messages and wake everyone up.
+    ///
+    /// Messages already queued can still be popped.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.recover(self.state.lock())
+    }
+
+    fn recover<'a>(&self, result: LockResult<MutexGuard<'a, State>>) -> MutexGuard<'a, State> {
+        self.recover_lock(&self.state, result)
+    }
+
+    fn lock_shard<'a>(&self, shard: &'a Shard) -> MutexGuard<'a, BinaryHeap<Prioritized>> {
+        self.recover_lock(&shard.heap, shard.heap.lock())
+    }
+
+    fn lock_pinned(&self) -> MutexGuard<'_, HashMap<usize, VecDeque<Prioritized>>> {
+        self.recover_lock(&self.pinned, self.pinned.lock())
+    }
+
+    // A thread panicking while holding a lock poisons it. The
+    // state is only changed in small steps which can't panic
+    // halfway, so it's still fine to use: take the guard back
+    // instead of taking every worker down with it.
+    fn recover_lock<'a, T>(
+        &self,
+        mutex: &Mutex<T>,
+        result: LockResult<MutexGuard<'a, T>>,
+    ) -> MutexGuard<'a, T> {
+        result.unwrap_or_else(|poisoned: PoisonError<_>| {
+            (self.logger)(LogLevel::Warn, "Job queue lock was poisoned; recovering.");
+            // Only warn once per poisoning
+            mutex.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// Poison the queue's lock by panicking while holding it.
+    #[cfg(test)]
+    pub fn poison(&self) {
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _state = self.state.lock();
+            panic!("poisoning the job queue");
+        }));
+    }
+
+    // Like sync_channel, a bounded queue accepts one extra
+    // message for every worker already waiting to take one,
+    // unless they're only waiting because it's paused
+    fn has_room(&self, state: &State) -> bool {
+        let waiting = if self.is_paused() { 0 } else { state.waiting };
+        match self.capacity {
+            Some(capacity) => self.len.load(AtomicOrdering::SeqCst) < capacity + waiting,
+            None => true,
+        }
+    }
+
+    fn dismiss_one(&self, state: &mut State) -> Option<Message> {
+        if state.dismissed == 0 {
+            return None;
+        }
+        state.dismissed -= 1;
+        Some(Message::Terminate)
+    }
+
+    // Whether poppers should give up: the queue's closed, and
+    // nothing's left which resuming could hand out
+    fn finished(&self, state: &State) -> bool {
+        state.closed && (!self.is_paused() || self.len.load(AtomicOrdering::SeqCst) == 0)
+    }
+
+    fn push_locked(&self, state: &mut State, priority: u8, message: Message) {
+        let message = match (message, &self.jobs) {
+            (Message::NewJob(job), Some(jobs)) => {
+                jobs.push(stamp(job));
+                Message::NewJob(Box::new(|| ()))
+            }
+            (message, _) => message,
+        };
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        // The shortest shard, so work spreads out between
+        // workers
+        let shard = self
+            .shards
+            .iter()
+            .min_by_key(|shard| shard.len.load(AtomicOrdering::SeqCst))
+            .unwrap();
+        self.lock_shard(shard).push(Prioritized {
+            priority,
+            seq,
+            message,
+            queued_at: Instant::now(),
+        });
+        shard.len.fetch_add(1, AtomicOrdering::SeqCst);
+        self.len.fetch_add(1, AtomicOrdering::SeqCst);
+        self.available.notify_one();
+    }
+
+    // Take the highest-priority message from shard home, or
+    // failing that, steal one from another shard
+    fn take(&self, home: usize) -> Option<Message> {
+        // Jobs pinned to the worker come first
+        if self.pinned_len.load(AtomicOrdering::SeqCst) > 0 {
+            let mut pinned = self.lock_pinned();
+            if self.is_paused() {
+                return None;
+            }
+            let pinned = pinned.get_mut(&home).and_then(VecDeque::pop_front);
+            if let Some(prioritized) = pinned {
+                self.pinned_len.fetch_sub(1, AtomicOrdering::SeqCst);
+                self.len.fetch_sub(1, AtomicOrdering::SeqCst);
+                return Some(self.expire(prioritized));
+            }
+        }
+        let count = self.shards.len();
+        for i in 0..count {
+            let shard = &self.shards[(home + i) % count];
+            // Skip empty shards without taking their locks
+            if shard.len.load(AtomicOrdering::SeqCst) == 0 {
+                continue;
+            }
+            let mut heap = self.lock_shard(shard);
+            if self.is_paused() {
+                return None;
+            }
+            if let Some(prioritized) = heap.pop() {
+                shard.len.fetch_sub(1, AtomicOrdering::SeqCst);
+                self.len.fetch_sub(1, AtomicOrdering::SeqCst);
+                return Some(self.expire(self.stood_in_for(prioritized)));
+            }
+        }
+        None
+    }
+
+    // Swap a stand-in for the job the JobQueue picks to run
+    // next, so its age is the one checked. Pinned jobs are never
+    // stand-ins, so only come from the heaps.
+    fn stood_in_for(&self, mut prioritized: Prioritized) -> Prioritized {
+        let (Message::NewJob(_), Some(jobs)) = (&prioritized.message, &self.jobs) else {
+            return prioritized;
+        };
+        if let Some((queued_at, job)) = jobs.pop().and_then(unstamp) {
+            prioritized.message = Message::NewJob(job);
+            prioritized.queued_at = queued_at;
+        }
+        prioritized
+    }
+
+    // Turn a job which has waited too long into an expired one.
+    // Control messages never expire.
+    fn expire(&self, prioritized: Prioritized) -> Message {
+        let waited = prioritized.queued_at.elapsed();
+        match (prioritized.message, self.max_age) {
+            (Message::NewJob(job), Some(max_age)) if waited > max_age => {
+                Message::Expired(job, waited)
+            }
+            (message, _) => message,
+        }
+    }
+
+    // Wake a pusher waiting for room, after popping without the
+    // state lock. Taking the lock first means the pusher is
+    // either already waiting or yet to check for room.
+    fn made_room(&self) {
+        if self.capacity.is_some() {
+            drop(self.lock());
+            self.space.notify_one();
+        }
+    }
+}
+
+thread_local! {
+    // Where a stamped job leaves itself when called, for unstamp
+    static UNSTAMPED: RefCell<Option<(Instant, Job)>> = const { RefCell::new(None) };
+}
+
+// Wrap a job going into a JobQueue so the time it was queued
+// comes back out with it. A JobQueue only holds closures, so
+// calling the wrapper hands over the job rather than running it.
+fn stamp(job: Job) -> Job {
+    let queued_at = Instant::now();
+    Box::new(move || UNSTAMPED.with(|slot| *slot.borrow_mut() = Some((queued_at, job))))
+}
+
+// Get back a job wrapped by stamp, and when it was queued
+fn unstamp(stamped: Job) -> Option<(Instant, Job)> {
+    stamped();
+    UNSTAMPED.with(|slot| slot.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::{mpsc, Arc};
+
+    fn quiet_queue(capacity: Option<usize>) -> PriorityQueue {
+        PriorityQueue::new(capacity, 1, Arc::new(|_, _| ()))
+    }
+
+    // Pop a job, run it and return what it sent
+    fn pop_value(queue: &PriorityQueue, rx: &mpsc::Receiver<u8>) -> u8 {
+        match queue.pop(0) {
+            Some(Message::NewJob(job)) => job(),
+            _ => panic!("expected a job"),
+        }
+        rx.recv().unwrap()
+    }
+
+    fn push_value(queue: &PriorityQueue, priority: u8, tx: &mpsc::Sender<u8>, value: u8) {
+        let tx = tx.clone();
+        queue
+            .push(
+                priority,
+                Message::NewJob(Box::new(move || tx.send(value).unwrap())),
+            )
+            .ok()
+            .unwrap();
+    }
+
+    #[test]
+    fn push_to_pinned() {
+        let queue = quiet_queue(None);
+        queue.register(0);
+        queue.register(1);
+        let (tx, rx) = mpsc::channel();
+        push_value(&queue, 255, &tx, 1);
+        let pinned_tx = tx.clone();
+        queue
+            .push_to(1, move || pinned_tx.send(2).unwrap())
+            .ok()
+            .unwrap();
+
+        // Worker 0 can't see worker 1's job, and worker 1 takes
+        // it ahead of a higher-priority one
+        assert_eq!(1, pop_value(&queue, &rx));
+        push_value(&queue, 255, &tx, 3);
+        match queue.pop(1) {
+            Some(Message::NewJob(job)) => job(),
+            _ => panic!("expected a job"),
+        }
+        assert_eq!(2, rx.recv().unwrap());
+        assert_eq!(3, pop_value(&queue, &rx));
+    }
+
+    #[test]
+    fn pause_holds_messages() {
+        let queue = Arc::new(quiet_queue(None));
+        let (tx, _rx) = mpsc::channel();
+        queue.pause();
+        assert!(queue.is_paused());
+        push_value(&queue, 1, &tx, 1);
+        assert_eq!(
+            Some(PopError::Timeout),
+            queue.pop_timeout(0, Duration::from_millis(10)).err()
+        );
+
+        // Dismissals still get through
+        queue.dismiss(1);
+        assert!(matches!(queue.pop(0), Some(Message::Terminate)));
+
+        // A waiting popper is woken by resume
+        let popper = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || matches!(queue.pop(0), Some(Message::NewJob(_))))
+        };
+        std::thread::sleep(Duration::from_millis(10));
+        queue.resume();
+        assert!(popper.join().unwrap());
+    }
+
+    #[test]
+    fn pause_closed() {
+        let queue = quiet_queue(None);
+        let (tx, rx) = mpsc::channel();
+        push_value(&queue, 1, &tx, 1);
+        queue.pause();
+        queue.close();
+        // Still waits for the queued job while paused
+        assert_eq!(
+            Some(PopError::Timeout),
+            queue.pop_timeout(0, Duration::from_millis(10)).err()
+        );
+        queue.resume();
+        assert_eq!(1, pop_value(&queue, &rx));
+        assert!(queue.pop(0).is_none());
+    }
+
+    #[test]
+    fn push_to_unknown_worker() {
+        let queue = quiet_queue(None);
+        queue.register(0);
+        assert!(matches!(
+            queue.push_to(1, || ()),
+            Err(ExecuteError::NoSuchWorker(_))
+        ));
+        queue.close();
+        assert!(matches!(
+            queue.push_to(0, || ()),
+            Err(ExecuteError::ShutDown(_))
+        ));
+    }
+
+    #[test]
+    fn retire_moves_pinned() {
+        let queue = quiet_queue(None);
+        queue.register(1);
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            queue.push_to(1, move || tx.send(i).unwrap()).ok().unwrap();
+        }
+        queue.retire(1);
+        assert!(matches!(
+            queue.push_to(1, || ()),
+            Err(ExecuteError::NoSuchWorker(_))
+        ));
+        // Any worker can take them now, still in order
+        for i in 0..3 {
+            assert_eq!(i, pop_value(&queue, &rx));
+        }
+        assert_eq!(
+            Some(PopError::Timeout),
+            queue.pop_timeout(0, Duration::from_millis(10)).err()
+        );
+    }
+
+    #[test]
+    fn pop_expired() {
+        let queue = quiet_queue(None).expire_after(Some(Duration::from_millis(10)));
+        let (tx, rx) = mpsc::channel();
+        push_value(&queue, 1, &tx, 1);
+        queue.push(1, Message::Terminate).ok().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        push_value(&queue, 1, &tx, 2);
+
+        // Only the job which waited too long expires
+        match queue.pop(0) {
+            Some(Message::Expired(_, waited)) => assert!(waited > Duration::from_millis(10)),
+            _ => panic!("expected an expired job"),
+        }
+        assert!(matches!(queue.pop(0), Some(Message::Terminate)));
+        assert_eq!(2, pop_value(&queue, &rx));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn pop_priority_order() {
+        let queue = quiet_queue(None);
+        let (tx, rx) = mpsc::channel();
+        push_value(&queue, 1, &tx, 1);
+        push_value(&queue, 200, &tx, 2);
+        push_value(&queue, 1, &tx, 3);
+        push_value(&queue, 200, &tx, 4);
+
+        let order: Vec<u8> = (0..4).map(|_| pop_value(&queue, &rx)).collect();
+        assert_eq!(vec![2, 4, 1, 3], order);
+    }
+
+    #[test]
+    fn closed_drains_then_ends() {
+        let queue = quiet_queue(None);
+        let (tx, rx) = mpsc::channel();
+        push_value(&queue, 1, &tx, 7);
+        queue.close();
+
+        assert!(queue.push(1, Message::Terminate).is_err());
+        assert_eq!(7, pop_value(&queue, &rx));
+        assert!(queue.pop(0).is_none());
+    }
+
+    #[test]
+    fn try_push_full() {
+        let queue = quiet_queue(Some(1));
+        assert!(queue.try_push(1, || ()).is_ok());
+        assert!(matches!(
+            queue.try_push(1, || ()),
+            Err(ExecuteError::Full(_))
+        ));
+        // Control messages ignore capacity
+        assert!(queue.push_unbounded(1, Message::Terminate).is_ok());
+    }
+
+    #[test]
+    fn pop_wakes_on_push() {
+        let queue = Arc::new(quiet_queue(None));
+        let popper = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || matches!(queue.pop(0), Some(Message::Terminate)))
+        };
+        queue.push(1, Message::Terminate).ok().unwrap();
+        assert!(popper.join().unwrap());
+    }
+
+    #[test]
+    fn poisoned_recovers() {
+        let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = Arc::clone(&warnings);
+        let queue = PriorityQueue::new(
+            None,
+            1,
+            Arc::new(move |level, message: &str| {
+                captured
+                    .lock()
+                    .unwrap()
+                    .push((level, String::from(message)));
+            }),
+        );
+        queue.poison();
+
+        queue.push(1, Message::Terminate).ok().unwrap();
+        assert!(matches!(queue.pop(0), Some(Message::Terminate)));
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(1, warnings.len());
+        assert_eq!(LogLevel::Warn, warnings[0].0);
+    }
+
+    #[test]
+    fn pop_timeout_times_out() {
+        let queue = quiet_queue(None);
+        let start = std::time::Instant::now();
+        assert_eq!(
+            Some(PopError::Timeout),
+            queue.pop_timeout(0, Duration::from_millis(20)).err()
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn pop_timeout_message_or_closed() {
+        let queue = quiet_queue(None);
+        queue.push(1, Message::Terminate).ok().unwrap();
+        assert!(matches!(
+            queue.pop_timeout(0, Duration::from_secs(5)),
+            Ok(Message::Terminate)
+        ));
+        queue.close();
+        assert_eq!(
+            Some(PopError::Closed),
+            queue.pop_timeout(0, Duration::from_secs(5)).err()
+        );
+    }
+
+    #[test]
+    fn push_batch_in_order() {
+        let queue = quiet_queue(None);
+        let (tx, rx) = mpsc::channel();
+        let jobs = (0..3).map(|value| {
+            let tx = tx.clone();
+            Message::NewJob(Box::new(move || tx.send(value).unwrap()))
+        });
+        queue.push_batch(1, jobs).ok().unwrap();
+
+        let order: Vec<u8> = (0..3).map(|_| pop_value(&queue, &rx)).collect();
+        assert_eq!(vec![0, 1, 2], order);
+    }
+
+    #[test]
+    fn push_batch_waits_for_room() {
+        let queue = Arc::new(quiet_queue(Some(1)));
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || {
+                let messages = (0..3).map(|_| Message::Terminate);
+                queue.push_batch(1, messages).is_ok()
+            })
+        };
+        // Each pop makes room for the next message
+        for _ in 0..3 {
+            assert!(matches!(queue.pop(0), Some(Message::Terminate)));
+        }
+        assert!(pusher.join().unwrap());
+    }
+
+    #[test]
+    fn push_batch_closed() {
+        let queue = quiet_queue(None);
+        queue.close();
+        let rejected = queue
+            .push_batch(1, (0..2).map(|_| Message::Terminate))
+            .err()
+            .unwrap();
+        assert_eq!(2, rejected.len());
+    }
+
+    #[test]
+    fn push_spreads_over_shards() {
+        let queue = PriorityQueue::new(None, 2, Arc::new(|_, _| ()));
+        for _ in 0..4 {
+            queue.push(1, Message::Terminate).ok().unwrap();
+        }
+        for shard in &queue.shards {
+            assert_eq!(2, shard.len.load(AtomicOrdering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn pop_steals() {
+        let queue = PriorityQueue::new(None, 3, Arc::new(|_, _| ()));
+        queue.push(1, Message::Terminate).ok().unwrap();
+        // Shard 2 is empty, so takes from shard 0
+        assert!(matches!(queue.pop(2), Some(Message::Terminate)));
+        assert_eq!(0, queue.len.load(AtomicOrdering::SeqCst));
+    }
+}
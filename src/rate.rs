@@ -0,0 +1,148 @@
+//! Measuring how often something happens.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How many parts the window is split into. Events drop out of
+// the window a part at a time.
+const BUCKETS: usize = 10;
+
+// The window used unless another is given
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Counts events, such as requests, and reports how many happened per second over a
+/// rolling window.
+///
+/// The window is split into ten buckets, each counting the events in its slice of
+/// time, so recording an event is just bumping a count and memory use stays the same
+/// however busy things get. The oldest bucket drops out of the window as each new one
+/// starts, so the rate lags by up to a tenth of the window.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use rust_tutorial_webserver::RateMeter;
+/// let meter = RateMeter::new(Duration::from_secs(1));
+/// for _ in 0..5 {
+///     meter.record();
+/// }
+/// assert_eq!(5.0, meter.per_second());
+/// ```
+#[derive(Debug)]
+pub struct RateMeter {
+    start: Instant,
+    window: Duration,
+    // Each bucket holds the number of the slice of time it's
+    // counting, from start, and its count
+    buckets: Mutex<[(u64, u64); BUCKETS]>,
+}
+impl Default for RateMeter {
+    /// A meter with a 10 second window.
+    fn default() -> RateMeter {
+        RateMeter::new(DEFAULT_WINDOW)
+    }
+}
+impl RateMeter {
+    /// Create a meter averaging over the last `window`.
+    ///
+    /// # Panics
+    ///
+    /// `new` panics if `window` is zero.
+    pub fn new(window: Duration) -> RateMeter {
+        assert!(!window.is_zero());
+        RateMeter {
+            start: Instant::now(),
+            window,
+            buckets: Mutex::new([(0, 0); BUCKETS]),
+        }
+    }
+
+    /// Count one event, now.
+    pub fn record(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Taken with the lock held, so a bucket is never ahead
+        // of the slice per_second reads it at
+        let slice = self.slice();
+        let bucket = &mut buckets[slice as usize % BUCKETS];
+        // Left over from a slice which has dropped out of the
+        // window
+        if bucket.0 != slice {
+            *bucket = (slice, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    /// Get the average number of events per second over the window.
+    pub fn per_second(&self) -> f64 {
+        let buckets = self.buckets.lock().unwrap();
+        let slice = self.slice();
+        let events: u64 = buckets
+            .iter()
+            .filter(|(bucket_slice, _)| slice - bucket_slice < BUCKETS as u64)
+            .map(|(_, count)| count)
+            .sum();
+        events as f64 / self.window.as_secs_f64()
+    }
+
+    // The number of the slice of time it is now
+    fn slice(&self) -> u64 {
+        let slice_length = self.window.as_nanos() / BUCKETS as u128;
+        (self.start.elapsed().as_nanos() / slice_length.max(1)) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn per_second_counts() {
+        let meter = RateMeter::new(Duration::from_secs(2));
+        assert_eq!(0.0, meter.per_second());
+        for _ in 0..100 {
+            meter.record();
+        }
+        assert_eq!(50.0, meter.per_second());
+    }
+
+    #[test]
+    fn per_second_across_threads() {
+        let meter = Arc::new(RateMeter::new(Duration::from_secs(5)));
+        let recorders: Vec<_> = (0..4)
+            .map(|_| {
+                let meter = Arc::clone(&meter);
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        meter.record();
+                    }
+                })
+            })
+            .collect();
+        for recorder in recorders {
+            recorder.join().unwrap();
+        }
+        assert_eq!(200.0, meter.per_second());
+    }
+
+    #[test]
+    fn per_second_window_passes() {
+        let meter = RateMeter::new(Duration::from_millis(50));
+        for _ in 0..10 {
+            meter.record();
+        }
+        assert!(meter.per_second() > 0.0);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(0.0, meter.per_second());
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_zero() {
+        RateMeter::new(Duration::ZERO);
+    }
+}
@@ -0,0 +1,212 @@
+//! Jobs which can borrow from the stack, because they're waited for before the stack
+//! frame they borrow from goes away.
+
+use std::{
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use crate::{
+    log_dropped,
+    queue::{Message, PriorityQueue},
+    Counters, Job, Logger, ThreadPool,
+};
+
+/// A scope for running jobs which borrow non-`'static` data, created by
+/// [ThreadPool::scope].
+pub struct Scope<'scope, 'env: 'scope> {
+    queue: &'scope PriorityQueue,
+    counters: &'scope Counters,
+    logger: &'scope Logger,
+    state: Arc<ScopeState>,
+    // Invariant over 'scope, like std::thread::Scope, so it
+    // can't be shrunk to let a job outlive what it borrows
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Tracks a scope's jobs which haven't finished yet
+#[derive(Default)]
+struct ScopeState {
+    pending: Mutex<usize>,
+    // Signalled when pending reaches 0
+    all_done: Condvar,
+    panicked: AtomicBool,
+}
+impl ScopeState {
+    fn wait_all_done(&self) {
+        let pending = self.pending.lock().unwrap();
+        let _pending = self
+            .all_done
+            .wait_while(pending, |pending| *pending > 0)
+            .unwrap();
+    }
+}
+
+/// One of a scope's jobs, counted as pending until dropped
+struct Pending(Arc<ScopeState>);
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.all_done.notify_all();
+        }
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    pub(crate) fn new(
+        queue: &'scope PriorityQueue,
+        counters: &'scope Counters,
+        logger: &'scope Logger,
+    ) -> Self {
+        Scope {
+            queue,
+            counters,
+            logger,
+            state: Arc::new(ScopeState::default()),
+            scope: PhantomData,
+            env: PhantomData,
+        }
+    }
+
+    /// Select a worker and execute a given closure, which may borrow anything which
+    /// outlives the scope.
+    ///
+    /// Blocks like [ThreadPool::execute] if the pool's queue is full. If the pool has
+    /// been [drained](ThreadPool::drain) meanwhile, the job is dropped without running
+    /// and a warning logged, also like [ThreadPool::execute].
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let pending = Pending(Arc::clone(&self.state));
+        let state = Arc::clone(&self.state);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            // Dropped last, once f and everything it borrows
+            // are gone, even if f panics
+            let _pending = pending;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                state.panicked.store(true, Ordering::SeqCst);
+                // Carry on unwinding so the worker still counts
+                // and logs the panic
+                panic::resume_unwind(payload);
+            }
+        });
+        // SAFETY: the queue only takes 'static jobs, but this
+        // one can't outlive 'scope. ThreadPool::scope doesn't
+        // return until every Pending is dropped, which only
+        // happens once its job has run or been dropped unrun.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.counters.job_submitted();
+        // The pool can't be dropped while the scope borrows it,
+        // but it can still be drained. Dropping the job unrun
+        // drops its Pending too, so the scope isn't left waiting.
+        if self
+            .queue
+            .push(ThreadPool::DEFAULT_PRIORITY, Message::NewJob(job))
+            .is_err()
+        {
+            self.counters.job_rejected();
+            log_dropped(self.logger, 1);
+        }
+    }
+
+    // Block until every job given to the scope has finished.
+    // Returns whether any of them panicked.
+    pub(crate) fn wait(&self) -> bool {
+        self.state.wait_all_done();
+        self.state.panicked.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPool;
+    use pretty_assertions::assert_eq;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    #[test]
+    fn scope_mutates_slices() {
+        let tp = ThreadPool::new(3);
+        let mut data = [0; 8];
+        tp.scope(|s| {
+            for (i, chunk) in data.chunks_mut(2).enumerate() {
+                s.execute(move || chunk.fill(i));
+            }
+        });
+        assert_eq!([0, 0, 1, 1, 2, 2, 3, 3], data);
+    }
+
+    #[test]
+    fn scope_returns_value() {
+        let tp = ThreadPool::new(2);
+        let count = AtomicUsize::new(0);
+        let returned = tp.scope(|s| {
+            for _ in 0..10 {
+                s.execute(|| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            "done"
+        });
+        assert_eq!("done", returned);
+        assert_eq!(10, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scope_job_panics() {
+        let tp = ThreadPool::with_logger(2, Arc::new(|_, _| ()));
+        let count = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            tp.scope(|s| {
+                s.execute(|| panic!("oh no"));
+                s.execute(|| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        }));
+        // The scope waited for the job which didn't panic, then
+        // passed the panic on
+        assert!(result.is_err());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scope_after_drain() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let logged = Arc::clone(&warnings);
+        let tp = ThreadPool::with_logger(
+            2,
+            Arc::new(move |_, message| logged.lock().unwrap().push(String::from(message))),
+        );
+        let count = AtomicUsize::new(0);
+        tp.scope(|s| {
+            s.execute(|| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+            tp.drain();
+            // Dropped rather than panicking
+            s.execute(|| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        assert!(warnings.lock().unwrap().contains(&String::from(
+            "ThreadPool is shutting down; dropped 1 job(s) without running."
+        )));
+    }
+}
@@ -0,0 +1,202 @@
+//! HTTP Basic authentication, for simple password-protected areas.
+
+use std::fmt;
+
+/// Parse the value of an `Authorization` header using the Basic scheme into a username
+/// and password.
+///
+/// The credentials are `username:password`, base64-encoded. The password may itself
+/// contain colons, but the username can't. Returns `None` if the header uses another
+/// scheme, isn't valid base64 or UTF-8, or has no colon.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::server::parse_basic_auth;
+/// assert_eq!(
+///     Some((String::from("Aladdin"), String::from("open sesame"))),
+///     parse_basic_auth("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")
+/// );
+/// assert_eq!(None, parse_basic_auth("Bearer QWxhZGRpbjpvcGVuIHNlc2FtZQ=="));
+/// ```
+pub fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(credentials.trim())?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((String::from(username), String::from(password)))
+}
+
+/// An area of the site only open to requests with one of its credentials
+pub(super) struct AuthArea {
+    /// The path the area starts at
+    pub(super) path: String,
+    /// Shown to the user when they're asked for credentials
+    pub(super) realm: String,
+    /// Username and password pairs
+    pub(super) credentials: Vec<(String, String)>,
+}
+// Passwords are left out, so they don't end up in logs
+impl fmt::Debug for AuthArea {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let usernames: Vec<&str> = self
+            .credentials
+            .iter()
+            .map(|(user, _)| user.as_str())
+            .collect();
+        f.debug_struct("AuthArea")
+            .field("path", &self.path)
+            .field("realm", &self.realm)
+            .field("usernames", &usernames)
+            .finish()
+    }
+}
+impl AuthArea {
+    // Whether path is the area's path or anywhere under it
+    pub(super) fn covers(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => rest.is_empty() || self.path.ends_with('/') || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    // Whether an Authorization header holds credentials for
+    // the area
+    pub(super) fn allows(&self, authorization: Option<&str>) -> bool {
+        authorization
+            .and_then(parse_basic_auth)
+            .is_some_and(|given| self.credentials.contains(&given))
+    }
+
+    // The WWW-Authenticate header asking for credentials
+    pub(super) fn challenge(&self) -> String {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("Basic realm=\"{realm}\"")
+    }
+}
+
+// Decode standard base64, with or without padding. Returns
+// None for anything else.
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let unpadded = encoded.trim_end_matches('=');
+    let padding = encoded.len() - unpadded.len();
+    if padding > 2 || (padding > 0 && !encoded.len().is_multiple_of(4)) || unpadded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(unpadded.len() * 3 / 4);
+    // Bits decoded but not yet output
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in unpadded.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn area() -> AuthArea {
+        AuthArea {
+            path: String::from("/admin"),
+            realm: String::from("Staff \"only\""),
+            credentials: vec![(String::from("alice"), String::from("s3cret"))],
+        }
+    }
+
+    #[test]
+    fn parse_basic_auth_valid() {
+        // alice:s3cret
+        assert_eq!(
+            Some((String::from("alice"), String::from("s3cret"))),
+            parse_basic_auth("Basic YWxpY2U6czNjcmV0")
+        );
+        // Passwords may hold colons: bob:a:b
+        assert_eq!(
+            Some((String::from("bob"), String::from("a:b"))),
+            parse_basic_auth("basic  Ym9iOmE6Yg==")
+        );
+        // An empty password: c:
+        assert_eq!(
+            Some((String::from("c"), String::new())),
+            parse_basic_auth("Basic Yzo=")
+        );
+    }
+
+    #[test]
+    fn parse_basic_auth_malformed() {
+        for header in [
+            "",
+            "Basic",
+            "Bearer YWxpY2U6czNjcmV0",
+            "Basic YWxpY2U6czNjcmV0!",
+            "Basic YWxpY2U6czNjcmV0===",
+            "Basic Y",
+            // No colon: alice
+            "Basic YWxpY2U=",
+            // Not UTF-8
+            "Basic //79",
+        ] {
+            assert_eq!(None, parse_basic_auth(header), "{header:?}");
+        }
+    }
+
+    #[test]
+    fn base64_decode_lengths() {
+        assert_eq!(Some(b"".to_vec()), base64_decode(""));
+        assert_eq!(Some(b"f".to_vec()), base64_decode("Zg=="));
+        assert_eq!(Some(b"fo".to_vec()), base64_decode("Zm8="));
+        assert_eq!(Some(b"foo".to_vec()), base64_decode("Zm9v"));
+        assert_eq!(Some(b"fo".to_vec()), base64_decode("Zm8"));
+        assert_eq!(None, base64_decode("Zm8=="));
+    }
+
+    #[test]
+    fn covers_paths() {
+        let area = area();
+        assert!(area.covers("/admin"));
+        assert!(area.covers("/admin/users"));
+        assert!(!area.covers("/administrator"));
+        assert!(!area.covers("/"));
+    }
+
+    #[test]
+    fn allows_credentials() {
+        let area = area();
+        assert!(area.allows(Some("Basic YWxpY2U6czNjcmV0")));
+        // alice:wrong
+        assert!(!area.allows(Some("Basic YWxpY2U6d3Jvbmc=")));
+        assert!(!area.allows(Some("Basic !!")));
+        assert!(!area.allows(None));
+    }
+
+    #[test]
+    fn debug_hides_passwords() {
+        let debug = format!("{:?}", area());
+        assert!(debug.contains("alice"));
+        assert!(!debug.contains("s3cret"));
+    }
+
+    #[test]
+    fn challenge_escapes_realm() {
+        assert_eq!(r#"Basic realm="Staff \"only\"""#, area().challenge());
+    }
+}
@@ -0,0 +1,436 @@
+//! Reading request bodies as they arrive, rather than all at once.
+
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+};
+
+use super::{
+    range::number,
+    request::{invalid_data, read_line, Headers, ParseError},
+};
+
+// The longest chunk size line, and the most bytes of trailer
+// lines after the last chunk, which a chunked body may have
+const MAX_CHUNK_LINE: usize = 1024;
+const MAX_TRAILERS: usize = 8 * 1024;
+
+/// How a request says where its body ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Framing {
+    /// The body is this many bytes long
+    Length(u64),
+    /// The body is sent in chunks, each prefixed with its size
+    Chunked,
+}
+
+// Find out from the headers how the body is framed. A request
+// with neither header has no body. A proxy in front of the
+// server might read headers sent more than once differently, so
+// don't guess between them.
+pub(super) fn framing(headers: &Headers) -> Result<Framing, ParseError> {
+    let mut lengths = headers.get_all("Content-Length");
+    let length = lengths.next();
+    if lengths.any(|other| Some(other) != length) {
+        return Err(ParseError::DuplicateContentLength);
+    }
+    let mut codings = headers.get_all("Transfer-Encoding");
+    let coding = codings.next();
+    if codings.next().is_some() {
+        return Err(ParseError::DuplicateTransferEncoding);
+    }
+    let Some(coding) = coding else {
+        return match length {
+            Some(value) => number(value)
+                .map(Framing::Length)
+                .ok_or_else(|| ParseError::InvalidContentLength(String::from(value))),
+            None => Ok(Framing::Length(0)),
+        };
+    };
+    // Nor between the two headers
+    if length.is_some() {
+        return Err(ParseError::ConflictingLength);
+    }
+    if !coding.eq_ignore_ascii_case("chunked") {
+        return Err(ParseError::UnsupportedTransferEncoding(String::from(
+            coding,
+        )));
+    }
+    Ok(Framing::Chunked)
+}
+
+/// Where a [BodyReader] is up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// This many bytes of a Content-Length body are left
+    Length(u64),
+    /// Waiting for the next chunk size line
+    ChunkSize,
+    /// This many bytes of the current chunk are left
+    ChunkData(u64),
+    /// Waiting for the CRLF after a chunk
+    ChunkEnd,
+    /// The whole body has been read
+    Done,
+}
+
+/// A request body read straight from the connection, so it never has to fit in memory.
+///
+/// Yields exactly the body's bytes: `Content-Length` of them, or the decoded contents
+/// of a `Transfer-Encoding: chunked` body, then end of file. Reading past the end never
+/// touches the rest of the connection, so the next request can be read from it. Given
+/// to a [BodyHandler](super::BodyHandler), which the server drains of anything left
+/// unread once it's done.
+///
+/// Returns an error of kind [io::ErrorKind::InvalidData] if a chunked body is
+/// malformed, and [io::ErrorKind::UnexpectedEof] if the connection closes before the
+/// body ends.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Read;
+/// use rust_tutorial_webserver::server::{request::Headers, BodyReader};
+/// let mut headers = Headers::new();
+/// headers.push("Transfer-Encoding", "chunked");
+/// let mut stream = "5\r\nhello\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n".as_bytes();
+/// let mut body = String::new();
+/// BodyReader::new(&mut stream, &headers)
+///     .unwrap()
+///     .read_to_string(&mut body)
+///     .unwrap();
+/// assert_eq!("hello", body);
+/// assert_eq!(b"GET / HTTP/1.1\r\n\r\n", stream);
+/// ```
+pub struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead,
+    state: State,
+}
+// The connection isn't Debug, so leave it out
+impl fmt::Debug for BodyReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BodyReader")
+            .field("state", &self.state)
+            .finish()
+    }
+}
+impl<'a> BodyReader<'a> {
+    /// Create a reader for the body following a request with the given headers, which
+    /// have already been read from `reader`.
+    ///
+    /// Returns an error of kind [io::ErrorKind::InvalidData] wrapping a [ParseError] if
+    /// the headers don't say how long the body is in a way the server understands.
+    pub fn new(reader: &'a mut dyn BufRead, headers: &Headers) -> io::Result<BodyReader<'a>> {
+        let state = match framing(headers).map_err(invalid_data)? {
+            Framing::Length(0) => State::Done,
+            Framing::Length(length) => State::Length(length),
+            Framing::Chunked => State::ChunkSize,
+        };
+        Ok(BodyReader { reader, state })
+    }
+
+    /// Check whether the whole body has been read.
+    ///
+    /// A chunked body only counts as read once the empty chunk which ends it has been,
+    /// which takes one more read after the last of the data.
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Read and throw away the rest of the body, returning how many bytes of it there
+    /// were.
+    pub fn drain(&mut self) -> io::Result<u64> {
+        io::copy(self, &mut io::sink())
+    }
+
+    // Like drain, but giving up after max bytes. Returns whether
+    // the whole body was read.
+    pub(super) fn drain_up_to(&mut self, max: u64) -> io::Result<bool> {
+        io::copy(&mut self.by_ref().take(max), &mut io::sink())?;
+        // The last chunk may be all that's left, which takes
+        // one more read to get through
+        Ok(self.is_finished() || self.read(&mut [0])? == 0)
+    }
+
+    // Read up to remaining bytes of data into buf
+    fn read_data(&mut self, buf: &mut [u8], remaining: u64) -> io::Result<usize> {
+        let max = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..max])?;
+        if read == 0 && max > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed mid-body",
+            ));
+        }
+        Ok(read)
+    }
+
+    // Read the line giving the next chunk's size, ignoring any
+    // extensions after it
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let too_long = || {
+            let line = format!("size line longer than {MAX_CHUNK_LINE} bytes");
+            invalid_data(ParseError::MalformedChunk(line))
+        };
+        let line = read_line(&mut self.reader, MAX_CHUNK_LINE)?.ok_or_else(too_long)?;
+        let size = line.text.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid_data(ParseError::MalformedChunk(line.text)));
+        }
+        u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(ParseError::MalformedChunk(line.text)))
+    }
+
+    // Skip the trailer lines after the last chunk, up to the
+    // blank line which ends the body
+    fn skip_trailers(&mut self) -> io::Result<()> {
+        let mut budget = MAX_TRAILERS;
+        loop {
+            let too_large = || invalid_data(ParseError::HeadersTooLarge(MAX_TRAILERS));
+            let line = read_line(&mut self.reader, budget)?.ok_or_else(too_large)?;
+            budget -= line.length;
+            if line.text.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Length(remaining) => {
+                    let read = self.read_data(buf, remaining)?;
+                    let remaining = remaining - read as u64;
+                    self.state = match remaining {
+                        0 => State::Done,
+                        _ => State::Length(remaining),
+                    };
+                    return Ok(read);
+                }
+                State::ChunkSize => {
+                    self.state = match self.read_chunk_size()? {
+                        0 => {
+                            self.skip_trailers()?;
+                            State::Done
+                        }
+                        size => State::ChunkData(size),
+                    };
+                }
+                State::ChunkData(remaining) => {
+                    let read = self.read_data(buf, remaining)?;
+                    let remaining = remaining - read as u64;
+                    self.state = match remaining {
+                        0 => State::ChunkEnd,
+                        _ => State::ChunkData(remaining),
+                    };
+                    return Ok(read);
+                }
+                State::ChunkEnd => {
+                    let mut crlf = [0; 2];
+                    self.reader.read_exact(&mut crlf)?;
+                    if &crlf != b"\r\n" {
+                        let after = String::from_utf8_lossy(&crlf).into_owned();
+                        return Err(invalid_data(ParseError::MalformedChunk(after)));
+                    }
+                    self.state = State::ChunkSize;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn headers(name: &str, value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.push(name, value);
+        headers
+    }
+
+    // Read the whole body from stream, returning it and what's
+    // left of the stream after it
+    fn read_body<'a>(mut stream: &'a [u8], headers: &Headers) -> io::Result<(Vec<u8>, &'a [u8])> {
+        let mut body = Vec::new();
+        BodyReader::new(&mut stream, headers)?.read_to_end(&mut body)?;
+        Ok((body, stream))
+    }
+
+    fn parse_error(e: io::Error) -> ParseError {
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        *e.into_inner().unwrap().downcast().unwrap()
+    }
+
+    #[test]
+    fn content_length() {
+        let (body, rest) = read_body(b"hello, worldGET", &headers("Content-Length", "5")).unwrap();
+        assert_eq!(b"hello", &body[..]);
+        assert_eq!(b", worldGET", rest);
+    }
+
+    #[test]
+    fn content_length_in_small_reads() {
+        let mut stream = "0123456789rest".as_bytes();
+        let headers = headers("Content-Length", "10");
+        let mut body = BodyReader::new(&mut stream, &headers).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(4, body.read(&mut buf).unwrap());
+        assert_eq!(4, body.read(&mut buf).unwrap());
+        assert!(!body.is_finished());
+        assert_eq!(2, body.read(&mut buf).unwrap());
+        assert!(body.is_finished());
+        assert_eq!(0, body.read(&mut buf).unwrap());
+        assert_eq!(b"rest", stream);
+    }
+
+    #[test]
+    fn content_length_truncated() {
+        let e = read_body(b"hel", &headers("Content-Length", "5")).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn no_body() {
+        let (body, rest) = read_body(b"GET / HTTP/1.1\r\n\r\n", &Headers::new()).unwrap();
+        assert!(body.is_empty());
+        assert_eq!(b"GET / HTTP/1.1\r\n\r\n", rest);
+    }
+
+    #[test]
+    fn chunked() {
+        let (body, rest) = read_body(
+            b"5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\n\r\nnext",
+            &headers("Transfer-Encoding", "chunked"),
+        )
+        .unwrap();
+        assert_eq!(b"hello, world", &body[..]);
+        assert_eq!(b"next", rest);
+    }
+
+    #[test]
+    fn chunked_hex_sizes_and_trailers() {
+        let chunk = "x".repeat(26);
+        let stream = format!("1A\r\n{chunk}\r\n0\r\nExpires: never\r\nX-Check: 1\r\n\r\nnext");
+        let (body, rest) =
+            read_body(stream.as_bytes(), &headers("Transfer-Encoding", "Chunked")).unwrap();
+        assert_eq!(chunk.as_bytes(), &body[..]);
+        assert_eq!(b"next", rest);
+    }
+
+    #[test]
+    fn chunked_malformed() {
+        let chunked = headers("Transfer-Encoding", "chunked");
+        for stream in [
+            &b"x\r\nhello\r\n0\r\n\r\n"[..],
+            b"+5\r\nhello\r\n0\r\n\r\n",
+            b"\r\n",
+            b"5\r\nhelloX\r\n0\r\n\r\n",
+            b"ffffffffffffffffff\r\n",
+        ] {
+            let e = read_body(stream, &chunked).unwrap_err();
+            assert!(
+                matches!(parse_error(e), ParseError::MalformedChunk(_)),
+                "{stream:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_truncated() {
+        let chunked = headers("Transfer-Encoding", "chunked");
+        for stream in [&b"5\r\nhel"[..], b"5\r\nhello\r\n", b"0\r\n"] {
+            let e = read_body(stream, &chunked).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, e.kind(), "{stream:?}");
+        }
+    }
+
+    #[test]
+    fn drain_leaves_next_request() {
+        let mut stream = "3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n".as_bytes();
+        let chunked = headers("Transfer-Encoding", "chunked");
+        let mut body = BodyReader::new(&mut stream, &chunked).unwrap();
+        let mut buf = [0; 2];
+        body.read_exact(&mut buf).unwrap();
+        assert_eq!(4, body.drain().unwrap());
+        assert!(body.is_finished());
+        assert_eq!(b"GET / HTTP/1.1\r\n\r\n", stream);
+    }
+
+    #[test]
+    fn drain_up_to() {
+        let chunked = headers("Transfer-Encoding", "chunked");
+        let mut stream = "3\r\nabc\r\n0\r\n\r\n".as_bytes();
+        // Exactly the data, with only the last chunk left over
+        let mut body = BodyReader::new(&mut stream, &chunked).unwrap();
+        assert!(body.drain_up_to(3).unwrap());
+
+        let mut stream = "3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n".as_bytes();
+        let mut body = BodyReader::new(&mut stream, &chunked).unwrap();
+        assert!(!body.drain_up_to(3).unwrap());
+    }
+
+    #[test]
+    fn framing_headers() {
+        assert_eq!(Ok(Framing::Length(0)), framing(&Headers::new()));
+        assert_eq!(
+            Ok(Framing::Length(12)),
+            framing(&headers("Content-Length", "12"))
+        );
+        assert_eq!(
+            Ok(Framing::Chunked),
+            framing(&headers("Transfer-Encoding", "chunked"))
+        );
+        assert_eq!(
+            Err(ParseError::InvalidContentLength(String::from("-1"))),
+            framing(&headers("Content-Length", "-1"))
+        );
+        assert_eq!(
+            Err(ParseError::UnsupportedTransferEncoding(String::from(
+                "gzip, chunked"
+            ))),
+            framing(&headers("Transfer-Encoding", "gzip, chunked"))
+        );
+        let mut both = headers("Transfer-Encoding", "chunked");
+        both.push("Content-Length", "5");
+        assert_eq!(Err(ParseError::ConflictingLength), framing(&both));
+    }
+
+    #[test]
+    fn framing_signed_length() {
+        for value in ["+5", " 5", "5 ", ""] {
+            assert_eq!(
+                Err(ParseError::InvalidContentLength(String::from(value))),
+                framing(&headers("Content-Length", value))
+            );
+        }
+    }
+
+    #[test]
+    fn framing_repeated_length() {
+        let mut same = headers("Content-Length", "5");
+        same.push("content-length", "5");
+        assert_eq!(Ok(Framing::Length(5)), framing(&same));
+        let mut different = headers("Content-Length", "5");
+        different.push("Content-Length", "50");
+        assert_eq!(Err(ParseError::DuplicateContentLength), framing(&different));
+    }
+
+    #[test]
+    fn framing_repeated_transfer_encoding() {
+        let mut twice = headers("Transfer-Encoding", "chunked");
+        twice.push("Transfer-Encoding", "chunked");
+        assert_eq!(Err(ParseError::DuplicateTransferEncoding), framing(&twice));
+        let mut smuggled = headers("Transfer-Encoding", "chunked");
+        smuggled.push("Transfer-Encoding", "identity");
+        assert_eq!(
+            Err(ParseError::DuplicateTransferEncoding),
+            framing(&smuggled)
+        );
+    }
+}
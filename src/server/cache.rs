@@ -0,0 +1,257 @@
+//! Keeping the contents of frequently requested files in memory.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+/// A cache of file contents, so files requested often aren't read from disk every time.
+///
+/// Entries are keyed by path and remember when the file was last modified, so a file
+/// which has changed since it was cached is read again. Once the cached contents add up
+/// to more than the cache's size, the least recently used files are dropped until it
+/// fits. Files bigger than the whole cache are never kept.
+///
+/// Set one on a [Router](super::router::Router) with
+/// [Router::set_file_cache](super::router::Router::set_file_cache).
+///
+/// # Examples
+///
+/// ```
+/// use std::time::SystemTime;
+/// use rust_tutorial_webserver::server::FileCache;
+/// let cache = FileCache::new(1024 * 1024);
+/// let contents = cache
+///     .get_or_load("hello.txt".as_ref(), SystemTime::UNIX_EPOCH, || Ok(b"hi".to_vec()))
+///     .unwrap();
+/// assert_eq!(b"hi", contents.as_slice());
+/// ```
+#[derive(Debug)]
+pub struct FileCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<PathBuf, Entry>,
+    // Least recently used first
+    order: VecDeque<PathBuf>,
+    // Bytes held by all the entries
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    modified: SystemTime,
+    contents: Arc<Vec<u8>>,
+}
+
+impl FileCache {
+    /// Create an empty cache holding at most `max_bytes` of file contents.
+    pub fn new(max_bytes: usize) -> FileCache {
+        FileCache {
+            max_bytes,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Get the contents of the file at `path`, last modified at `modified`.
+    ///
+    /// Returns the cached contents if the file hasn't changed since they were cached.
+    /// Otherwise calls `load` to read it, caching the result if it fits. Returns any
+    /// error from `load`.
+    pub fn get_or_load(
+        &self,
+        path: &Path,
+        modified: SystemTime,
+        load: impl FnOnce() -> io::Result<Vec<u8>>,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(contents) = self.lock().get(path, modified) {
+            return Ok(contents);
+        }
+        // Read without the lock held, so other files can still
+        // be served from the cache meanwhile
+        let contents = Arc::new(load()?);
+        if contents.len() <= self.max_bytes {
+            self.lock()
+                .insert(path, modified, Arc::clone(&contents), self.max_bytes);
+        }
+        Ok(contents)
+    }
+
+    /// Get the number of bytes of file contents currently cached.
+    pub fn size(&self) -> usize {
+        self.lock().bytes
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        // Nothing can panic part way through changing the state,
+        // so it's fine even if the lock is poisoned
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CacheState {
+    // The cached contents, if they're up to date, marking them
+    // as just used
+    fn get(&mut self, path: &Path, modified: SystemTime) -> Option<Arc<Vec<u8>>> {
+        let entry = self.entries.get(path)?;
+        if entry.modified != modified {
+            return None;
+        }
+        let contents = Arc::clone(&entry.contents);
+        self.touch(path);
+        Some(contents)
+    }
+
+    fn insert(
+        &mut self,
+        path: &Path,
+        modified: SystemTime,
+        contents: Arc<Vec<u8>>,
+        max_bytes: usize,
+    ) {
+        self.remove(path);
+        self.bytes += contents.len();
+        self.entries
+            .insert(path.to_path_buf(), Entry { modified, contents });
+        self.order.push_back(path.to_path_buf());
+        while self.bytes > max_bytes {
+            let Some(oldest) = self.order.front().cloned() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.bytes -= entry.contents.len();
+            self.order.retain(|cached| cached != path);
+        }
+    }
+
+    // Move path to the most recently used end
+    fn touch(&mut self, path: &Path) {
+        if let Some(i) = self.order.iter().position(|cached| cached == path) {
+            if let Some(path) = self.order.remove(i) {
+                self.order.push_back(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{cell::Cell, time::Duration};
+
+    // Loads a file's contents, counting how often it's called
+    fn counting<'a>(
+        loads: &'a Cell<usize>,
+        contents: &'a str,
+    ) -> impl FnOnce() -> io::Result<Vec<u8>> + 'a {
+        move || {
+            loads.set(loads.get() + 1);
+            Ok(contents.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn get_or_load_cached() {
+        let cache = FileCache::new(100);
+        let loads = Cell::new(0);
+        let path = Path::new("a.html");
+        let modified = SystemTime::UNIX_EPOCH;
+        for _ in 0..3 {
+            let contents = cache
+                .get_or_load(path, modified, counting(&loads, "a"))
+                .unwrap();
+            assert_eq!(b"a", contents.as_slice());
+        }
+        assert_eq!(1, loads.get());
+        assert_eq!(1, cache.size());
+    }
+
+    #[test]
+    fn get_or_load_modified() {
+        let cache = FileCache::new(100);
+        let loads = Cell::new(0);
+        let path = Path::new("a.html");
+        let before = SystemTime::UNIX_EPOCH;
+        let after = before + Duration::from_secs(1);
+        cache
+            .get_or_load(path, before, counting(&loads, "old"))
+            .unwrap();
+        let contents = cache
+            .get_or_load(path, after, counting(&loads, "new"))
+            .unwrap();
+        assert_eq!(b"new", contents.as_slice());
+        assert_eq!(2, loads.get());
+        assert_eq!(3, cache.size());
+    }
+
+    #[test]
+    fn get_or_load_evicts_least_recent() {
+        let cache = FileCache::new(10);
+        let loads = Cell::new(0);
+        let modified = SystemTime::UNIX_EPOCH;
+        let (a, b, c) = (Path::new("a"), Path::new("b"), Path::new("c"));
+        cache
+            .get_or_load(a, modified, counting(&loads, "aaaa"))
+            .unwrap();
+        cache
+            .get_or_load(b, modified, counting(&loads, "bbbb"))
+            .unwrap();
+        // a is now more recently used than b, so b goes
+        cache
+            .get_or_load(a, modified, counting(&loads, "aaaa"))
+            .unwrap();
+        cache
+            .get_or_load(c, modified, counting(&loads, "cccc"))
+            .unwrap();
+        assert_eq!(3, loads.get());
+        assert_eq!(8, cache.size());
+
+        cache
+            .get_or_load(a, modified, counting(&loads, "aaaa"))
+            .unwrap();
+        assert_eq!(3, loads.get());
+        cache
+            .get_or_load(b, modified, counting(&loads, "bbbb"))
+            .unwrap();
+        assert_eq!(4, loads.get());
+    }
+
+    #[test]
+    fn get_or_load_too_big() {
+        let cache = FileCache::new(2);
+        let loads = Cell::new(0);
+        let path = Path::new("big");
+        for _ in 0..2 {
+            let contents = cache
+                .get_or_load(path, SystemTime::UNIX_EPOCH, counting(&loads, "big"))
+                .unwrap();
+            assert_eq!(b"big", contents.as_slice());
+        }
+        assert_eq!(2, loads.get());
+        assert_eq!(0, cache.size());
+    }
+
+    #[test]
+    fn get_or_load_error() {
+        let cache = FileCache::new(100);
+        let e = cache
+            .get_or_load(Path::new("a"), SystemTime::UNIX_EPOCH, || {
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            })
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, e.kind());
+        assert_eq!(0, cache.size());
+    }
+}
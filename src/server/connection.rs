@@ -0,0 +1,2534 @@
+//! Answering HTTP requests on a connection.
+
+use std::{
+    fs,
+    io::{self, prelude::*, BufReader, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{LogLevel, Logger, PoolHandle};
+
+use super::{
+    body::BodyReader,
+    cors,
+    date::{http_date, parse_http_date},
+    encoding::{choose_encoding, encode, is_compressible, ENCODINGS},
+    error::ServerError,
+    handler::{BodyHandler, Handler},
+    middleware,
+    range::{parse_range, ByteRange},
+    request::{
+        parse_query, read_request_body_continue, read_request_head, send_continue, Headers,
+        ParseError, Request, RequestLimits, RequestLine,
+    },
+    response::{status_line, Response},
+    router::{KeepAlive, Router},
+    static_files::{content_type_for, is_unindexed_dir, resolve_static},
+    stream::Stream,
+};
+
+const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+
+// Bodies for errors which aren't backed by a page on disk
+const BODY_400: &str = "<!DOCTYPE html><html><body><h1>400 BAD REQUEST</h1></body></html>";
+const BODY_401: &str = "<!DOCTYPE html><html><body><h1>401 UNAUTHORIZED</h1></body></html>";
+const BODY_403: &str = "<!DOCTYPE html><html><body><h1>403 FORBIDDEN</h1></body></html>";
+const BODY_405: &str = "<!DOCTYPE html><html><body><h1>405 METHOD NOT ALLOWED</h1></body></html>";
+const BODY_408: &str = "<!DOCTYPE html><html><body><h1>408 REQUEST TIMEOUT</h1></body></html>";
+const BODY_413: &str = "<!DOCTYPE html><html><body><h1>413 PAYLOAD TOO LARGE</h1></body></html>";
+const BODY_416: &str =
+    "<!DOCTYPE html><html><body><h1>416 RANGE NOT SATISFIABLE</h1></body></html>";
+const BODY_431: &str =
+    "<!DOCTYPE html><html><body><h1>431 REQUEST HEADER FIELDS TOO LARGE</h1></body></html>";
+// Only sent if PAGE_500 itself can't be read
+const BODY_500: &str =
+    "<!DOCTYPE html><html><body><h1>500 INTERNAL SERVER ERROR</h1></body></html>";
+
+const MAIN_PAGE: &str = "welcome.html";
+const PAGE_404: &str = "404.html";
+const PAGE_500: &str = "500.html";
+
+// Directory which any other file is served from
+const STATIC_ROOT: &str = "public";
+
+const SLEEP_PATH: &str = "/sleep";
+// How long /sleep takes, unless the ms query parameter says
+// otherwise, and the most it can be asked to take
+const DEFAULT_SLEEP: Duration = Duration::from_millis(5000);
+const MAX_SLEEP: Duration = Duration::from_millis(60_000);
+
+// Files bigger than this are streamed to HTTP/1.1 clients in
+// chunks rather than read into memory all at once
+const CHUNKED_THRESHOLD: u64 = 1024 * 1024;
+// Size of each chunk read from a streamed body
+const CHUNK_SIZE: usize = 8 * 1024;
+
+// How long a client may take to send the next part of a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The id given to the next connection, so the log lines for
+// one connection can be picked out of many
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Create a [Handler] which answers with the load on a pool as JSON, for health checks.
+///
+/// The body looks like `{"active":1,"idle":3,"queued":0}`, giving the pool's
+/// [active](crate::ThreadPool::active_count) and [idle](crate::ThreadPool::idle_count)
+/// workers and its [queued jobs](crate::ThreadPool::queued_jobs) at the time of the
+/// request. Usually registered at `/healthz`.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::{server::{default_router, health_check}, ThreadPool};
+/// let my_thread_pool = ThreadPool::new(4);
+/// let mut router = default_router();
+/// router.insert_handler("GET", "/healthz", health_check(my_thread_pool.handle()));
+/// ```
+pub fn health_check(pool: PoolHandle) -> impl Handler {
+    move |_: &Request| {
+        // Simple enough not to need a JSON library
+        let body = format!(
+            "{{\"active\":{},\"idle\":{},\"queued\":{}}}",
+            pool.active_count(),
+            pool.idle_count(),
+            pool.queued_jobs()
+        );
+        Response::ok()
+            .header("Content-Type", "application/json")
+            .body(body)
+    }
+}
+
+/// Create a [Handler] which answers with a pool's metrics for Prometheus to scrape.
+///
+/// The body is [ThreadPool::metrics_prometheus](crate::ThreadPool::metrics_prometheus)
+/// at the time of the request, in the text exposition format. Usually registered at
+/// `/metrics`.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::{server::{default_router, prometheus_metrics}, ThreadPool};
+/// let my_thread_pool = ThreadPool::new(4);
+/// let mut router = default_router();
+/// router.insert_handler("GET", "/metrics", prometheus_metrics(my_thread_pool.handle()));
+/// ```
+pub fn prometheus_metrics(pool: PoolHandle) -> impl Handler {
+    move |_: &Request| {
+        Response::ok()
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(pool.metrics_prometheus())
+    }
+}
+
+/// Create the router for the routes served by default.
+///
+/// `/` and `/sleep` serve `welcome.html`.
+pub fn default_router() -> Router {
+    let mut router = Router::new();
+    router.insert("GET", "/", MAIN_PAGE);
+    router.insert("GET", SLEEP_PATH, MAIN_PAGE);
+    router
+}
+
+// The methods a path can be requested with, for the Allow
+// header. HEAD works wherever GET does.
+fn allowed_methods<'a>(router: &'a Router, path: &str) -> Vec<&'a str> {
+    let mut allowed = router.allowed_methods(path);
+    if allowed.is_empty() && static_file("GET", path).is_some() {
+        allowed.push("GET");
+    }
+    if allowed.contains(&"GET") {
+        allowed.push("HEAD");
+    }
+    allowed
+}
+
+// Find the file under STATIC_ROOT for a GET request
+fn static_file(method: &str, path: &str) -> Option<PathBuf> {
+    if method != "GET" {
+        return None;
+    }
+    resolve_static(Path::new(STATIC_ROOT), path)
+}
+
+/// Answer every request sent on a connection, until the client closes it or asks for
+/// it to be closed.
+///
+/// Requests are answered from `router`, then from files under `public/`, then with the
+/// router's [not found handler](Router::set_not_found), or the 404 page if it has none.
+/// Paths ending in `/` are served the `index.html` in that directory.
+/// Each response is logged to `logger` in an access log line with the connection's id,
+/// the client's address, the request line, the status code and the number of bytes
+/// sent, e.g. `#7 127.0.0.1:51234 "GET / HTTP/1.1" 200 512`. Every connection gets a
+/// new id, shared by all the lines logged for it. Files which can't be read are logged
+/// at [LogLevel::Error] with the same id and address. Returns an error if the
+/// connection fails.
+///
+/// A connection kept alive is closed once it has been idle between requests for too
+/// long, or has made too many requests, as set with [Router::set_keep_alive]. The last
+/// response it's allowed says so with `Connection: close`. Requests are read within
+/// the [Router::set_request_limits]; one over them is answered with an error, and one
+/// sent too slowly with `408 Request Timeout`, before the connection is closed.
+pub fn handle_connection(
+    stream: impl Stream,
+    router: &Router,
+    logger: &Logger,
+) -> Result<(), ServerError> {
+    // Stop slow or stalled clients from holding a worker forever
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let peer = stream.peer();
+    Ok(handle(stream, peer, router, logger)?)
+}
+
+/// Answer every request sent on a connection with `handler`, until the client closes it
+/// or asks for it to be closed.
+///
+/// Like [handle_connection], but each response comes from [Handler::handle] rather than
+/// the router and files on disk. Requests which can't be read are still answered with
+/// an error by the server, and every response is logged the same way. For `HEAD`
+/// requests, the body of the handler's response is left out. Connections are kept alive
+/// within the [default](KeepAlive::default) limits.
+pub fn handle_connection_with(
+    stream: impl Stream,
+    handler: &dyn Handler,
+    logger: &Logger,
+) -> Result<(), ServerError> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let log = AccessLog::new(stream.peer(), logger);
+    Ok(serve_requests_with(
+        &mut BufStream(BufReader::new(stream)),
+        &log,
+        &RequestLimits::default(),
+        &KeepAlive::default(),
+        |_| false,
+        |request, writer| {
+            let head_only = request.line.method == "HEAD";
+            handler.handle(request).write(writer, head_only)
+        },
+    )?)
+}
+
+// The part of handle_connection which only needs something to
+// read requests from and write responses to, so tests can use
+// an in-memory stream
+fn handle(stream: impl Stream, peer: String, router: &Router, logger: &Logger) -> io::Result<()> {
+    let log = AccessLog::new(peer, logger);
+
+    // BufStream adds buffering to reads by wrapping the stream
+    // in a BufReader, while still letting it be written. The
+    // same BufStream is kept for the whole connection so no
+    // buffered bytes of a later request are lost.
+    serve_requests(&mut BufStream(BufReader::new(stream)), router, &log)
+}
+
+// A stream with buffered reads and unbuffered writes
+struct BufStream<S>(BufReader<S>);
+impl<S: Read> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl<S: Read> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+impl<S: Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+impl<S: Stream> ReadTimeout for BufStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.get_ref().set_read_timeout(timeout)
+    }
+}
+
+// A connection whose read timeout can be changed between
+// requests, since an idle connection needn't be waited on as
+// long as a request part way through
+trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+// Where a connection's access log lines go
+struct AccessLog {
+    // Starts every line, to tell connections apart
+    id: u64,
+    peer: String,
+    logger: Logger,
+}
+impl AccessLog {
+    // Start the log for a new connection, giving it the next id
+    fn new(peer: String, logger: &Logger) -> AccessLog {
+        AccessLog {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            logger: Arc::clone(logger),
+        }
+    }
+
+    // Log something which went wrong on the connection
+    fn warn(&self, message: &str) {
+        self.log(LogLevel::Warn, message);
+    }
+
+    // Log something which went wrong on the server's side while
+    // answering a request, e.g. a file which couldn't be read
+    fn error(&self, message: &str) {
+        self.log(LogLevel::Error, message);
+    }
+
+    fn log(&self, level: LogLevel, message: &str) {
+        (self.logger)(level, &format!("#{} {} {message}", self.id, self.peer));
+    }
+
+    // Log the response just written through recorder. The
+    // request line is missing if it couldn't be parsed.
+    fn record<W>(&self, line: Option<&RequestLine>, recorder: &Recorder<W>) {
+        let line = line.map_or_else(
+            || String::from("-"),
+            |line| format!("{} {} {}", line.method, line.path, line.version),
+        );
+        let status = recorder
+            .status
+            .map_or_else(|| String::from("-"), |status| status.to_string());
+        (self.logger)(
+            LogLevel::Info,
+            &format!(
+                "#{} {} \"{line}\" {status} {}",
+                self.id, self.peer, recorder.bytes
+            ),
+        );
+    }
+}
+
+// Passes writes on, counting the bytes of the response and
+// picking out its status code from the status line. Reads pass
+// straight through.
+struct Recorder<W> {
+    inner: W,
+    status: Option<u16>,
+    bytes: usize,
+    // Whether to add "Connection: close" to the response, as
+    // it's the last the connection will send
+    closing: bool,
+    // Added to the response straight after the status line,
+    // whichever way it's written, e.g. for CORS
+    headers: Vec<(String, String)>,
+}
+impl<W: Write> Recorder<W> {
+    fn new(inner: W) -> Recorder<W> {
+        Recorder {
+            inner,
+            status: None,
+            bytes: 0,
+            closing: false,
+            headers: Vec::new(),
+        }
+    }
+
+    // Start recording the next response
+    fn reset(&mut self) {
+        self.status = None;
+        self.bytes = 0;
+        self.headers.clear();
+    }
+}
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Responses start with the status line, e.g.
+        // "HTTP/1.1 200 OK"
+        if self.bytes == 0 {
+            self.status = buf
+                .split(|&byte| byte == b' ')
+                .nth(1)
+                .and_then(|code| std::str::from_utf8(code).ok())
+                .and_then(|code| code.parse().ok());
+            // Every way of writing a response writes its whole
+            // head at once, so the headers can go straight
+            // after the status line
+            let adding = self.closing || !self.headers.is_empty();
+            if let (true, Some(end)) = (adding, buf.windows(2).position(|w| w == b"\r\n")) {
+                let mut head = buf[..end + 2].to_vec();
+                for (name, value) in &self.headers {
+                    head.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+                }
+                if self.closing {
+                    head.extend_from_slice(b"Connection: close\r\n");
+                }
+                self.inner.write_all(&head)?;
+                self.bytes += head.len();
+                return Ok(end + 2);
+            }
+        }
+        let written = self.inner.write(buf)?;
+        self.bytes += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<W: Read> Read for Recorder<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+impl<W: BufRead> BufRead for Recorder<W> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+// Serve requests until the client closes the connection or
+// asks for it to be closed
+fn serve_requests(
+    stream: &mut (impl BufRead + Write + ReadTimeout),
+    router: &Router,
+    log: &AccessLog,
+) -> io::Result<()> {
+    serve_requests_with(
+        stream,
+        log,
+        router.request_limits(),
+        router.keep_alive(),
+        |request| !is_preflight(router, request) && body_handler(router, request).is_some(),
+        |request, writer| {
+            if let Some(cors) = router.cors() {
+                // Preflights are answered before anything else,
+                // as browsers never send credentials with them
+                if is_preflight(router, request) {
+                    return cors.preflight(request).write_to(writer);
+                }
+                writer.headers = cors.headers(request);
+            }
+            match body_handler(router, request) {
+                Some(handler) => respond_streaming(request, router, log, handler, writer),
+                None => respond(request, router, log, writer),
+            }
+        },
+    )
+}
+
+// Like serve_requests, with respond writing the response to
+// each request which was read successfully. The bodies of
+// requests which streams picks out are left for respond to
+// read.
+fn serve_requests_with<S: BufRead + Write + ReadTimeout>(
+    stream: &mut S,
+    log: &AccessLog,
+    limits: &RequestLimits,
+    keep_alive: &KeepAlive,
+    streams: impl Fn(&Request) -> bool,
+    mut respond: impl FnMut(&Request, &mut Recorder<&mut S>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut writer = Recorder::new(stream);
+    let mut served = 0;
+    loop {
+        if served > 0 {
+            // Only wait so long for the next request to start.
+            // A client which has gone quiet is just closed; it
+            // isn't owed a 408.
+            writer
+                .inner
+                .set_read_timeout(Some(keep_alive.idle_timeout))?;
+            match writer.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => (),
+                Err(e) if is_timeout(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            writer.inner.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        }
+
+        // Read the request line, headers and body, telling the
+        // client to go ahead with the body if it's waiting to
+        // be asked
+        let request = read_request_head(&mut writer, limits).and_then(|mut request| {
+            if !streams(&request) {
+                read_request_body_continue(&mut writer, &mut request, limits)?;
+            }
+            Ok(request)
+        });
+        // A 100 Continue isn't the response to log
+        writer.reset();
+        let request = match request {
+            Ok(request) => request,
+            // Client hung up, possibly mid-request. There's no
+            // one left to answer.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // Can't tell where the next request would start,
+                // so give up on the connection
+                log.warn(&e.to_string());
+                let (status, body) = match e.get_ref().and_then(|e| e.downcast_ref()) {
+                    Some(ParseError::BodyTooLarge(_)) => (413, BODY_413),
+                    Some(ParseError::RequestLineTooLong(_) | ParseError::HeadersTooLarge(_)) => {
+                        (431, BODY_431)
+                    }
+                    _ => (400, BODY_400),
+                };
+                writer.closing = true;
+                html_response(status, body).write_to(&mut writer)?;
+                log.record(None, &writer);
+                return Ok(());
+            }
+            Err(e) if is_timeout(&e) => {
+                writer.closing = true;
+                html_response(408, BODY_408).write_to(&mut writer)?;
+                log.record(None, &writer);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        served += 1;
+        let keep_alive_wanted = should_keep_alive(&request.line.version, &request.headers);
+        writer.closing = keep_alive_wanted && served >= keep_alive.max_requests;
+        respond(&request, &mut writer)?;
+        log.record(Some(&request.line), &writer);
+
+        if !keep_alive_wanted || writer.closing {
+            return Ok(());
+        }
+    }
+}
+
+// A read timeout shows up as WouldBlock on Unix and TimedOut on
+// Windows
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// Whether to wait for another request after answering one.
+// HTTP/1.1 connections stay open unless the client sends
+// "Connection: close"; HTTP/1.0 ones close unless it sends
+// "Connection: keep-alive".
+fn should_keep_alive(version: &str, headers: &Headers) -> bool {
+    let has_option = |option: &str| {
+        headers.get("Connection").is_some_and(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        })
+    };
+    if version == "HTTP/1.1" {
+        !has_option("close")
+    } else {
+        has_option("keep-alive")
+    }
+}
+
+// Whether a request is a CORS preflight the router answers
+// itself, rather than its routes
+fn is_preflight(router: &Router, request: &Request) -> bool {
+    router.cors().is_some() && cors::is_preflight(request)
+}
+
+// The body handler for a request, if its route has one. HEAD
+// is routed like GET, and routing ignores any query string.
+fn body_handler<'a>(router: &'a Router, request: &Request) -> Option<&'a dyn BodyHandler> {
+    let method = match request.line.method.as_str() {
+        "HEAD" => "GET",
+        method => method,
+    };
+    let (path, _) = parse_query(&request.line.path);
+    router.resolve_body_handler(method, path)
+}
+
+// Write the response to a request for a body handler, which
+// reads the body straight from the connection
+fn respond_streaming<S: BufRead + Write>(
+    request: &Request,
+    router: &Router,
+    log: &AccessLog,
+    handler: &dyn BodyHandler,
+    writer: &mut Recorder<S>,
+) -> io::Result<()> {
+    let head_only = request.line.method == "HEAD";
+    let (path, _) = parse_query(&request.line.path);
+    // Protected areas are still checked before anything else.
+    // The body is left unsent, so there's no telling where the
+    // next request would start.
+    if router
+        .auth_challenge(path, request.headers.get("Authorization"))
+        .is_some()
+    {
+        writer.closing = true;
+        return respond(request, router, log, writer);
+    }
+
+    // Straight to the connection, so a 100 Continue isn't the
+    // response logged or given "Connection: close"
+    send_continue(&mut writer.inner, request)?;
+    let mut body = BodyReader::new(writer, &request.headers)?;
+    let response = middleware::run(router.middleware(), request, |request| {
+        handler.handle(request, &mut body)
+    });
+    // Whatever the handler left unread has to be got out of the
+    // way before the next request. If there's too much of it,
+    // or it's malformed, close the connection instead.
+    let limit = router.request_limits().max_body_size as u64;
+    if !body.drain_up_to(limit).unwrap_or(false) {
+        writer.closing = true;
+    }
+    response.write(writer, head_only)
+}
+
+// Write the response to a single request
+fn respond(
+    request: &Request,
+    router: &Router,
+    log: &AccessLog,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let request_line = &request.line;
+
+    // HEAD is answered exactly like GET, minus the body
+    let head_only = request_line.method == "HEAD";
+    let method = if head_only {
+        "GET"
+    } else {
+        &request_line.method
+    };
+
+    // Routing ignores any query string
+    let (path, query) = parse_query(&request_line.path);
+
+    // Protected areas are checked before anything else, so
+    // nothing about them leaks out without credentials
+    if let Some(challenge) = router.auth_challenge(path, request.headers.get("Authorization")) {
+        return html_response(401, BODY_401)
+            .header("WWW-Authenticate", &challenge)
+            .write(writer, head_only);
+    }
+
+    // Simulated slow response
+    if method == "GET" && path == SLEEP_PATH {
+        thread::sleep(sleep_duration(query.get("ms").map(String::as_str)));
+    }
+
+    if let Some(handler) = router.resolve_handler(method, path) {
+        return middleware::run(router.middleware(), request, |request| {
+            handler.handle(request)
+        })
+        .write(writer, head_only);
+    }
+
+    // Routes take priority over static files. Fall back to the
+    // not found handler or 404 page for anything else.
+    let (status, filename) = match router.resolve(method, path) {
+        Some(filename) => (200, filename.to_path_buf()),
+        None => match static_file(method, path) {
+            Some(filename) => (200, filename),
+            None => {
+                // The path exists, just not for this method
+                let allowed = allowed_methods(router, path);
+                if !allowed.is_empty() {
+                    return html_response(405, BODY_405)
+                        .header("Allow", &allowed.join(", "))
+                        .write(writer, head_only);
+                }
+                if router.forbids_unindexed()
+                    && method == "GET"
+                    && is_unindexed_dir(Path::new(STATIC_ROOT), path)
+                {
+                    return html_response(403, BODY_403).write(writer, head_only);
+                }
+                if let Some(not_found) = router.not_found() {
+                    return not_found(request).write(writer, head_only);
+                }
+                (404, PathBuf::from(PAGE_404))
+            }
+        },
+    };
+
+    let file = fs::File::open(&filename).and_then(|file| Ok((file.metadata()?, file)));
+    // Only files served successfully say when they last
+    // changed, so clients can ask for them conditionally
+    let modified = match &file {
+        Ok((metadata, _)) if status == 200 => metadata.modified().ok(),
+        _ => None,
+    };
+    let validators = match (&file, modified) {
+        (Ok((metadata, _)), Some(modified)) => {
+            Some((http_date(modified), etag(metadata.len(), modified)))
+        }
+        _ => None,
+    };
+    let content_type = content_type_for(&filename);
+    // A gzipped copy saved next to the file saves compressing
+    // it again for every request
+    let gz = match (status, &file) {
+        (200, Ok((metadata, _))) => precompressed(&filename, metadata),
+        _ => None,
+    };
+    let mut headers = Vec::new();
+    if let Some((last_modified, etag)) = &validators {
+        headers.push(("Last-Modified", last_modified.as_str()));
+        headers.push(("ETag", etag.as_str()));
+    }
+    // Whether the response is compressed depends on what the
+    // client accepts, so caches have to keep them apart, even
+    // when this client got it uncompressed
+    if is_compressible(content_type) || gz.is_some() {
+        headers.push(("Vary", "Accept-Encoding"));
+    }
+    if let (Some(modified), Some((_, etag))) = (modified, &validators) {
+        if method == "GET" && !changed(&request.headers, modified, etag) {
+            // A 304 has no body, so no Content-Type either
+            return with_headers(Response::new(304), &headers).write_to(writer);
+        }
+    }
+
+    // A client may ask for just part of a file, e.g. to resume
+    // a download or skip through a video
+    let range = match (&file, request.headers.get("Range")) {
+        (Ok((metadata, _)), Some(range)) if status == 200 => parse_range(range, metadata.len()),
+        _ => None,
+    };
+    if let (Some(range), Ok((metadata, file))) = (range, &file) {
+        let length = metadata.len();
+        let (start, end) = match range {
+            ByteRange::Satisfiable { start, end } => (start, end),
+            ByteRange::Unsatisfiable => {
+                return html_response(416, BODY_416)
+                    .header("Content-Range", &format!("bytes */{length}"))
+                    .write(writer, head_only);
+            }
+        };
+        return match read_range(file, start, end) {
+            Ok(part) => with_headers(
+                Response::new(206).header("Content-Type", content_type),
+                &headers,
+            )
+            .header("Content-Range", &format!("bytes {start}-{end}/{length}"))
+            .body(part)
+            .write(writer, head_only),
+            Err(e) => {
+                log.error(&format!("Error reading {}: {e}", filename.display()));
+                html_response(500, page_or(PAGE_500, BODY_500, log)).write(writer, head_only)
+            }
+        };
+    }
+
+    let gzip_accepted = request
+        .headers
+        .get("Accept-Encoding")
+        .and_then(|accepted| choose_encoding(accepted, &["gzip"]))
+        == Some("gzip");
+    // The gzipped copy is sent in place of the file, and read
+    // just the same way
+    let (filename, file, precompressed) = match (gzip_accepted, gz) {
+        (true, Some((gz_filename, gz_metadata, gz_file))) => {
+            headers.push(("Content-Encoding", "gzip"));
+            (gz_filename, Ok((gz_metadata, gz_file)), true)
+        }
+        _ => (filename, file, false),
+    };
+
+    // Chunked encoding is HTTP/1.1 only
+    let can_stream = request_line.version == "HTTP/1.1";
+    let contents = match file {
+        Ok((metadata, file)) if can_stream && metadata.len() > CHUNKED_THRESHOLD => {
+            // HEAD needs no body, so there's no need to read it
+            if head_only {
+                write_chunked_head(writer, status, content_type, &headers)?;
+                return writer.flush();
+            }
+            return write_chunked(writer, status, content_type, &headers, file);
+        }
+        Ok((metadata, file)) => match (router.file_cache(), metadata.modified()) {
+            (Some(cache), Ok(modified)) => {
+                cache.get_or_load(&filename, modified, || read_file(&metadata, file))
+            }
+            _ => read_file(&metadata, file).map(Arc::new),
+        },
+        Err(e) => Err(e),
+    };
+
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(e) => {
+            log.error(&format!("Error reading {}: {e}", filename.display()));
+            return html_response(500, page_or(PAGE_500, BODY_500, log)).write(writer, head_only);
+        }
+    };
+    let response = with_headers(
+        Response::new(status).header("Content-Type", content_type),
+        &headers,
+    );
+    // With no encoding acceptable, uncompressed is still the
+    // best bet
+    let compressed = request
+        .headers
+        .get("Accept-Encoding")
+        .and_then(|accepted| choose_encoding(accepted, &ENCODINGS))
+        .filter(|_| !precompressed && is_compressible(content_type))
+        .and_then(|encoding| Some((encoding, encode(encoding, &contents)?)));
+    match compressed {
+        Some((encoding, Ok(compressed))) => {
+            return response
+                .header("Content-Encoding", encoding)
+                .body(compressed)
+                .write(writer, head_only);
+        }
+        // Still fine to send uncompressed
+        Some((_, Err(e))) => log.error(&format!("Error compressing {}: {e}", filename.display())),
+        None => (),
+    }
+    // Straight from the cache, if that's where it came from
+    response.write_with(writer, &contents, head_only)
+}
+
+// Open the gzipped copy of a file, e.g. style.css.gz for
+// style.css, if there is one. A copy older than the file was
+// made from an earlier version of it, so is left alone.
+fn precompressed(
+    filename: &Path,
+    original: &fs::Metadata,
+) -> Option<(PathBuf, fs::Metadata, fs::File)> {
+    let mut gz = filename.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    let file = fs::File::open(&gz).ok()?;
+    let metadata = file.metadata().ok().filter(fs::Metadata::is_file)?;
+    let up_to_date = match (metadata.modified(), original.modified()) {
+        (Ok(gz_modified), Ok(modified)) => gz_modified >= modified,
+        _ => false,
+    };
+    up_to_date.then_some((gz, metadata, file))
+}
+
+// Read the whole of a file. Raw bytes, since not every file is
+// text.
+fn read_file(metadata: &fs::Metadata, mut file: fs::File) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// Read the bytes from start to end of a file, inclusive
+fn read_range(mut file: &fs::File, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut part = Vec::with_capacity((end - start + 1) as usize);
+    file.take(end - start + 1).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+// An HTML response, for pages and errors
+fn html_response(status: u16, body: impl Into<Vec<u8>>) -> Response {
+    Response::new(status)
+        .header("Content-Type", CONTENT_TYPE_HTML)
+        .body(body)
+}
+
+// Add headers to the end of a response's headers
+fn with_headers(response: Response, headers: &[(&str, &str)]) -> Response {
+    headers.iter().fold(response, |response, (name, value)| {
+        response.header(name, value)
+    })
+}
+
+// A weak ETag for a file, made from its size and when it was
+// last modified. Cheaper than hashing the whole file, and
+// changes whenever the file is rewritten.
+fn etag(length: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |modified| modified.as_nanos());
+    format!("W/\"{length:x}-{modified:x}\"")
+}
+
+// Whether the client's cached copy of a file is out of date.
+// If-None-Match takes precedence over If-Modified-Since, and
+// without either the client has no copy at all.
+fn changed(headers: &Headers, modified: SystemTime, etag: &str) -> bool {
+    match headers.get("If-None-Match") {
+        Some(etags) => !etag_matches(etags, etag),
+        None => modified_since(headers, modified),
+    }
+}
+
+// Whether an If-None-Match list names etag. The comparison is
+// weak, so W/ prefixes are ignored.
+fn etag_matches(etags: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    etags
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+// Whether a file last modified at modified has changed since
+// the request's If-Modified-Since date. Without a date which
+// can be understood, it's assumed to have.
+fn modified_since(headers: &Headers, modified: SystemTime) -> bool {
+    let Some(since) = headers.get("If-Modified-Since").and_then(parse_http_date) else {
+        return true;
+    };
+    // HTTP dates only go down to the second
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |modified| modified.as_secs());
+    let since = since
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    modified > since
+}
+
+// How long /sleep should take, given its ms query parameter.
+// Missing or malformed values get the default and large ones
+// are capped, so a bad value never causes an error.
+fn sleep_duration(ms: Option<&str>) -> Duration {
+    let Some(ms) = ms.map(str::trim) else {
+        return DEFAULT_SLEEP;
+    };
+    match ms.parse() {
+        Ok(ms) => Duration::from_millis(ms).min(MAX_SLEEP),
+        // Too many digits to fit in a u64 is still just too long
+        Err(_) if !ms.is_empty() && ms.bytes().all(|byte| byte.is_ascii_digit()) => MAX_SLEEP,
+        Err(_) => DEFAULT_SLEEP,
+    }
+}
+
+// Read an error page, or use the hardcoded fallback if even
+// that can't be read, so a response can always be sent
+fn page_or(filename: &str, fallback: &str, log: &AccessLog) -> Vec<u8> {
+    fs::read(filename).unwrap_or_else(|e| {
+        log.error(&format!("Error reading {filename}: {e}"));
+        fallback.as_bytes().to_vec()
+    })
+}
+
+// Write the head of a response whose body is sent with
+// Transfer-Encoding: chunked, e.g. on its own for HEAD
+fn write_chunked_head(
+    stream: &mut impl Write,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+) -> io::Result<()> {
+    let mut head = format!(
+        "{}\r\nDate: {}\r\nContent-Type: {content_type}\r\n",
+        status_line(status),
+        http_date(SystemTime::now())
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+// Write a response whose length isn't known up front. Each
+// read from body is sent as a chunk prefixed with its size in
+// hex, and a zero-size chunk marks the end.
+fn write_chunked(
+    stream: &mut impl Write,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    mut body: impl Read,
+) -> io::Result<()> {
+    write_chunked_head(stream, status, content_type, headers)?;
+
+    let mut buf = [0; CHUNK_SIZE];
+    loop {
+        let n = match body.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write!(stream, "{n:X}\r\n")?;
+        stream.write_all(&buf[..n])?;
+        stream.write_all(b"\r\n")?;
+    }
+    stream.write_all(b"0\r\n\r\n")?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{request::MinDataRate, Cors, FileCache, Middleware};
+    use pretty_assertions::assert_eq;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    fn quiet_logger() -> Logger {
+        Arc::new(|_, _| ())
+    }
+
+    fn quiet_log() -> AccessLog {
+        AccessLog::new(String::from("-"), &quiet_logger())
+    }
+
+    // An in-memory connection: requests are read from the first
+    // half and responses written to the second
+    struct Duplex<'a, R>(&'a mut R, &'a mut Vec<u8>);
+    impl<R: Read> Read for Duplex<'_, R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl<R: BufRead> BufRead for Duplex<'_, R> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.0.fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.0.consume(amt)
+        }
+    }
+    impl<R> Write for Duplex<'_, R> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    // Nothing to wait for, since every request is there already
+    impl<R> ReadTimeout for Duplex<'_, R> {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl<R: Read + Send> Stream for Duplex<'_, R> {
+        fn peer(&self) -> String {
+            String::from("-")
+        }
+
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Run handle_connection on one end of a real TCP connection,
+    // send it a request from the other and return the response
+    fn round_trip(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let handler =
+            thread::spawn(move || handle_connection(server, &default_router(), &quiet_logger()));
+        client.write_all(request.as_bytes()).unwrap();
+        // Closing the write half tells the server no more
+        // requests are coming
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handler.join().unwrap().unwrap();
+        response
+    }
+
+    // Run handle on an in-memory stream holding request and
+    // return everything written back
+    fn handle_bytes(request: &str) -> Vec<u8> {
+        let mut reader = io::Cursor::new(request);
+        let mut response = Vec::new();
+        handle(
+            Duplex(&mut reader, &mut response),
+            String::from("-"),
+            &default_router(),
+            &quiet_logger(),
+        )
+        .unwrap();
+        response
+    }
+
+    // Remove every Date header, which changes from second to
+    // second, so the rest of a response can be compared exactly
+    fn strip_date(response: &[u8]) -> Vec<u8> {
+        let mut stripped = Vec::with_capacity(response.len());
+        let mut rest = response;
+        while let Some(start) = rest.windows(8).position(|w| w == b"\r\nDate: ") {
+            let end = start
+                + 2
+                + rest[start + 2..]
+                    .windows(2)
+                    .position(|w| w == b"\r\n")
+                    .unwrap();
+            stripped.extend_from_slice(&rest[..start]);
+            rest = &rest[end..];
+        }
+        stripped.extend_from_slice(rest);
+        stripped
+    }
+
+    #[test]
+    fn handle_date() {
+        let response = String::from_utf8(handle_bytes("GET / HTTP/1.0\r\n\r\n")).unwrap();
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        // e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+        assert_eq!(29, date.len());
+        assert!(date.ends_with(" GMT"));
+    }
+
+    #[test]
+    fn handle_ok_bytes() {
+        let body = fs::read(MAIN_PAGE).unwrap();
+        let (modified, etag) = main_page_validators();
+        let mut expected = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nLast-Modified: {modified}\r\nETag: {etag}\r\nVary: Accept-Encoding\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        expected.extend_from_slice(&body);
+        assert_eq!(
+            expected,
+            strip_date(&handle_bytes("GET / HTTP/1.0\r\n\r\n"))
+        );
+    }
+
+    // The Last-Modified and ETag headers sent with MAIN_PAGE
+    fn main_page_validators() -> (String, String) {
+        let metadata = fs::metadata(MAIN_PAGE).unwrap();
+        let modified = metadata.modified().unwrap();
+        (http_date(modified), etag(metadata.len(), modified))
+    }
+
+    #[test]
+    fn handle_not_modified() {
+        let (modified, etag) = main_page_validators();
+        let request = format!("GET / HTTP/1.0\r\nIf-Modified-Since: {modified}\r\n\r\n");
+        let expected = format!(
+            "HTTP/1.1 304 NOT MODIFIED\r\nLast-Modified: {modified}\r\nETag: {etag}\r\nVary: Accept-Encoding\r\n\r\n"
+        );
+        assert_eq!(expected.as_bytes(), strip_date(&handle_bytes(&request)));
+    }
+
+    #[test]
+    fn handle_none_match() {
+        let (_, etag) = main_page_validators();
+        for if_none_match in [
+            etag.clone(),
+            format!("\"other\", {etag}"),
+            String::from("*"),
+        ] {
+            let request = format!("GET / HTTP/1.0\r\nIf-None-Match: {if_none_match}\r\n\r\n");
+            let response = handle_bytes(&request);
+            assert!(
+                response.starts_with(b"HTTP/1.1 304 NOT MODIFIED\r\n"),
+                "{if_none_match}"
+            );
+        }
+    }
+
+    #[test]
+    fn handle_none_match_changed() {
+        // If-None-Match wins over an If-Modified-Since which
+        // would have matched
+        let (modified, _) = main_page_validators();
+        let request = format!(
+            "GET / HTTP/1.0\r\nIf-None-Match: W/\"0-0\"\r\nIf-Modified-Since: {modified}\r\n\r\n"
+        );
+        let response = handle_bytes(&request);
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&fs::read(MAIN_PAGE).unwrap()));
+    }
+
+    #[test]
+    fn etag_quoted() {
+        let tag = etag(255, UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!("W/\"ff-3b9aca00\"", tag);
+        assert!(etag_matches("\"ff-3b9aca00\"", &tag));
+        assert!(!etag_matches("\"ff\", W/\"3b9aca00\"", &tag));
+    }
+
+    #[test]
+    fn handle_modified_since() {
+        for since in ["Thu, 01 Jan 1970 00:00:00 GMT", "yesterday"] {
+            let request = format!("GET / HTTP/1.0\r\nIf-Modified-Since: {since}\r\n\r\n");
+            let response = handle_bytes(&request);
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"), "{since}");
+            assert!(response.ends_with(&fs::read(MAIN_PAGE).unwrap()), "{since}");
+        }
+    }
+
+    #[test]
+    fn handle_head_bytes() {
+        let length = fs::metadata(PAGE_404).unwrap().len();
+        let expected = format!(
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nVary: Accept-Encoding\r\nContent-Length: {length}\r\n\r\n"
+        );
+        assert_eq!(
+            expected.as_bytes(),
+            strip_date(&handle_bytes(
+                "HEAD /nope HTTP/1.1\r\nConnection: close\r\n\r\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn handle_keep_alive_bytes() {
+        let one = format!(
+            "HTTP/1.1 405 METHOD NOT ALLOWED\r\nContent-Type: text/html; charset=utf-8\r\nAllow: GET, HEAD\r\nContent-Length: {}\r\n\r\n{BODY_405}",
+            BODY_405.len()
+        );
+        // Both requests are answered, in order, on the one
+        // connection
+        let response = strip_date(&handle_bytes(
+            "DELETE / HTTP/1.1\r\n\r\nDELETE / HTTP/1.1\r\n\r\n",
+        ));
+        assert_eq!(format!("{one}{one}").as_bytes(), response);
+    }
+
+    #[test]
+    fn handle_connection_ok() {
+        let response = round_trip("GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_query() {
+        let response = round_trip("GET /?lang=en HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn serve_requests_gzip() {
+        let mut reader =
+            io::Cursor::new("GET / HTTP/1.0\r\nAccept-Encoding: deflate, gzip\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let body = &response[split + 4..];
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(head.contains(&format!("\r\nContent-Length: {}", body.len())));
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(fs::read(MAIN_PAGE).unwrap(), decompressed);
+    }
+
+    #[test]
+    fn serve_requests_deflate_preferred() {
+        let mut reader =
+            io::Cursor::new("GET / HTTP/1.0\r\nAccept-Encoding: gzip;q=0.5, deflate;q=0.8\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        assert!(head.contains("\r\nContent-Encoding: deflate\r\n"));
+
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(&response[split + 4..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(fs::read(MAIN_PAGE).unwrap(), decompressed);
+    }
+
+    #[test]
+    fn serve_requests_no_gzip() {
+        let mut reader = io::Cursor::new("GET / HTTP/1.0\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(!response.contains("Content-Encoding"));
+        // Another client might have got it compressed
+        assert!(response.contains("\r\nVary: Accept-Encoding\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_sleep_ms() {
+        let start = std::time::Instant::now();
+        let response = round_trip("GET /sleep?ms=10 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < DEFAULT_SLEEP);
+    }
+
+    #[test]
+    fn sleep_duration_parsed() {
+        assert_eq!(Duration::from_millis(200), sleep_duration(Some("200")));
+        assert_eq!(Duration::ZERO, sleep_duration(Some("0")));
+    }
+
+    #[test]
+    fn sleep_duration_default() {
+        assert_eq!(DEFAULT_SLEEP, sleep_duration(None));
+        assert_eq!(DEFAULT_SLEEP, sleep_duration(Some("")));
+        assert_eq!(DEFAULT_SLEEP, sleep_duration(Some("soon")));
+        assert_eq!(DEFAULT_SLEEP, sleep_duration(Some("-5")));
+    }
+
+    #[test]
+    fn sleep_duration_capped() {
+        assert_eq!(MAX_SLEEP, sleep_duration(Some("3600000")));
+        assert_eq!(MAX_SLEEP, sleep_duration(Some("99999999999999999999")));
+    }
+
+    #[test]
+    fn handle_connection_head() {
+        let length = fs::read(MAIN_PAGE).unwrap().len();
+        let response = round_trip("HEAD / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("Content-Length: {length}\r\n")));
+        // Nothing follows the blank line ending the headers
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_head_not_found() {
+        let response = round_trip("HEAD /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_keep_alive() {
+        let response = round_trip("GET / HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\r\n");
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+        assert_eq!(1, response.matches("HTTP/1.1 404 NOT FOUND\r\n").count());
+    }
+
+    #[test]
+    fn handle_connection_close() {
+        // The second request is never answered
+        let response =
+            round_trip("GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    #[test]
+    fn handle_connection_http_1_0() {
+        let response = round_trip("GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    #[test]
+    fn handle_connection_disconnect_mid_request() {
+        let response = round_trip("GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nHost: a");
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    #[test]
+    fn handle_connection_closed_immediately() {
+        assert_eq!("", round_trip(""));
+    }
+
+    #[test]
+    fn handle_connection_not_found() {
+        let response = round_trip("GET /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_wrong_method() {
+        let response = round_trip("POST / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+        assert!(response.contains("\r\nAllow: GET, HEAD\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_wrong_method_unknown_path() {
+        let response = round_trip("POST /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(!response.contains("Allow:"));
+    }
+
+    fn connection(value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.push("Connection", value);
+        headers
+    }
+
+    #[test]
+    fn should_keep_alive_http_1_1() {
+        assert!(should_keep_alive("HTTP/1.1", &Headers::new()));
+        assert!(should_keep_alive("HTTP/1.1", &connection("keep-alive")));
+        assert!(!should_keep_alive("HTTP/1.1", &connection("close")));
+        assert!(!should_keep_alive(
+            "HTTP/1.1",
+            &connection("Upgrade, Close")
+        ));
+    }
+
+    #[test]
+    fn should_keep_alive_http_1_0() {
+        assert!(!should_keep_alive("HTTP/1.0", &Headers::new()));
+        assert!(!should_keep_alive("HTTP/1.0", &connection("close")));
+        assert!(should_keep_alive("HTTP/1.0", &connection("Keep-Alive")));
+    }
+
+    #[test]
+    fn handle_connection_http_1_0_keep_alive() {
+        let response = round_trip(
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        );
+        // The third request comes after the connection closed
+        assert_eq!(2, response.matches("HTTP/1.1 200 OK").count());
+    }
+
+    #[test]
+    fn serve_requests_keep_alive_max_requests() {
+        let mut router = default_router();
+        router.set_keep_alive(KeepAlive {
+            max_requests: 2,
+            ..KeepAlive::default()
+        });
+        let mut reader = io::Cursor::new("GET / HTTP/1.1\r\n\r\n".repeat(3));
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        let responses: Vec<&str> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(2, responses.len());
+        assert!(!responses[0].contains("Connection: close"));
+        // The last response says so, and starts with the header
+        assert!(responses[1].starts_with("Connection: close\r\nDate: "));
+        assert!(responses[1].contains("\r\nContent-Length: "));
+        // The third request is never read
+        assert_eq!(
+            "GET / HTTP/1.1\r\n\r\n".len() as u64,
+            reader.get_ref().len() as u64 - reader.position()
+        );
+    }
+
+    #[test]
+    fn handle_connection_keep_alive_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut router = default_router();
+        router.set_keep_alive(KeepAlive {
+            idle_timeout: Duration::from_millis(50),
+            ..KeepAlive::default()
+        });
+
+        let handler = thread::spawn(move || handle_connection(server, &router, &quiet_logger()));
+        // The client never sends a second request, nor closes
+        // the connection
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handler.join().unwrap().unwrap();
+        assert_eq!(1, response.matches("HTTP/1.1 ").count());
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_bad_version() {
+        let response = round_trip("GET / HTTP/2\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_bare_lf() {
+        let response = round_trip("GET / HTTP/1.1\nHost: a\n\n");
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn handle_connection_malformed() {
+        let response = round_trip("garbage\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\n"));
+    }
+
+    // A reader standing in for a client which has stopped sending
+    struct PausedReader(io::ErrorKind);
+    impl Read for PausedReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(self.0))
+        }
+    }
+
+    #[test]
+    fn serve_requests_timeout() {
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut] {
+            let mut reader = BufReader::new(PausedReader(kind));
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &default_router(),
+                &quiet_log(),
+            )
+            .unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 408 REQUEST TIMEOUT\r\nConnection: close\r\n"));
+        }
+    }
+
+    // A client sending its request a byte every 20ms
+    struct SlowLoris(&'static [u8]);
+    impl Read for SlowLoris {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&byte, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            thread::sleep(Duration::from_millis(20));
+            buf[0] = byte;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn serve_requests_too_slow() {
+        let mut router = default_router();
+        router.set_request_limits(RequestLimits {
+            min_data_rate: Some(MinDataRate {
+                bytes: 100,
+                per: Duration::from_millis(50),
+            }),
+            ..RequestLimits::default()
+        });
+        // A slow-loris client, which would take over a second
+        // to send the whole request
+        let mut reader = BufReader::new(SlowLoris(
+            b"GET / HTTP/1.1\r\nUser-Agent: slowloris\r\nX-a: b\r\nX-c: d\r\n\r\n",
+        ));
+        let mut response = Vec::new();
+        let start = std::time::Instant::now();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(response.starts_with(b"HTTP/1.1 408 REQUEST TIMEOUT\r\nConnection: close\r\n"));
+        assert!(!reader.into_inner().0.is_empty());
+    }
+
+    #[test]
+    fn serve_requests_io_error() {
+        let mut reader = BufReader::new(PausedReader(io::ErrorKind::ConnectionReset));
+        let mut response = Vec::new();
+        let e = serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionReset, e.kind());
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn serve_requests_empty() {
+        let mut reader = io::Cursor::new("");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn serve_requests_empty_line() {
+        for request in ["\r\n", "\n", "\r\n\r\n"] {
+            let mut reader = io::Cursor::new(request);
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &default_router(),
+                &quiet_log(),
+            )
+            .unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\n"));
+        }
+    }
+
+    // Answers every request with its path, and counts them
+    struct Echo(std::sync::atomic::AtomicUsize);
+    impl Handler for Echo {
+        fn handle(&self, request: &Request) -> Response {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Response::ok()
+                .header("Content-Type", "text/plain")
+                .body(request.line.path.clone())
+        }
+    }
+
+    #[test]
+    fn handle_connection_with_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let handler = Echo(std::sync::atomic::AtomicUsize::new(0));
+
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\n\r\nHEAD /there HTTP/1.0\r\n\r\n")
+            .unwrap();
+        handle_connection_with(server, &handler, &quiet_logger()).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+
+        let expected =
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\n/hello\
+            HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\n";
+        assert_eq!(expected, String::from_utf8(strip_date(&response)).unwrap());
+        assert_eq!(2, handler.0.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn handle_connection_with_closure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let handler = |_: &Request| Response::new(204);
+
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        handle_connection_with(server, &handler, &quiet_logger()).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(
+            b"HTTP/1.1 204 NO CONTENT\r\n\r\n".to_vec(),
+            strip_date(&response)
+        );
+    }
+
+    #[test]
+    fn serve_requests_health_check() {
+        let tp = crate::ThreadPool::new(2);
+        let mut router = default_router();
+        router.insert_handler("GET", "/healthz", health_check(tp.handle()));
+        let mut reader = io::Cursor::new("GET /healthz HTTP/1.0\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(body.starts_with('{') && body.ends_with('}'));
+        for key in ["\"active\":", "\"idle\":", "\"queued\":"] {
+            assert!(body.contains(key), "{body}");
+        }
+        assert!(body.contains("\"idle\":2"), "{body}");
+    }
+
+    #[test]
+    fn serve_requests_prometheus_metrics() {
+        let tp = crate::ThreadPool::new(2);
+        let mut router = default_router();
+        router.insert_handler("GET", "/metrics", prometheus_metrics(tp.handle()));
+        let mut reader = io::Cursor::new("GET /metrics HTTP/1.0\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(body.contains("\nthreadpool_idle_workers 2\n"), "{body}");
+    }
+
+    #[test]
+    fn serve_requests_auth_middleware() {
+        // Refuses requests without an Authorization header
+        struct RequireAuth;
+        impl Middleware for RequireAuth {
+            fn before(&self, request: &Request) -> Option<Response> {
+                match request.headers.get("Authorization") {
+                    Some(_) => None,
+                    None => Some(Response::new(401).header("WWW-Authenticate", "Bearer")),
+                }
+            }
+        }
+        let mut router = default_router();
+        router.insert_handler("GET", "/secret", |_: &Request| {
+            Response::ok().body("hidden")
+        });
+        router.add_middleware(Arc::new(RequireAuth));
+
+        let serve = |request: &'static str| {
+            let mut reader = io::Cursor::new(request);
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &router,
+                &quiet_log(),
+            )
+            .unwrap();
+            String::from_utf8(response).unwrap()
+        };
+        let refused = serve("GET /secret HTTP/1.0\r\n\r\n");
+        assert!(refused.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        assert!(refused.contains("\r\nWWW-Authenticate: Bearer\r\n"));
+        assert!(!refused.contains("hidden"));
+
+        let allowed = serve("GET /secret HTTP/1.0\r\nAuthorization: Bearer x\r\n\r\n");
+        assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(allowed.ends_with("\r\n\r\nhidden"));
+    }
+
+    #[test]
+    fn serve_requests_basic_auth() {
+        let (mut router, file) = range_router("basic_auth");
+        router.require_basic_auth("/data", "Data", "alice", "s3cret");
+        let serve = |request: String| {
+            let mut reader = io::Cursor::new(request);
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &router,
+                &quiet_log(),
+            )
+            .unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let with_auth = |credentials: &str| {
+            serve(format!(
+                "GET /data HTTP/1.0\r\nAuthorization: Basic {credentials}\r\n\r\n"
+            ))
+        };
+
+        let missing = serve(String::from("GET /data HTTP/1.0\r\n\r\n"));
+        assert!(missing.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        assert!(missing.contains("\r\nWWW-Authenticate: Basic realm=\"Data\"\r\n"));
+        // alice:wrong
+        assert!(with_auth("YWxpY2U6d3Jvbmc=").starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        assert!(with_auth("not base64!").starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        // alice:s3cret
+        assert!(with_auth("YWxpY2U6czNjcmV0").starts_with("HTTP/1.1 200 OK\r\n"));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn serve_requests_basic_auth_unnormalized() {
+        let (mut router, file) = range_router("basic_auth_unnormalized");
+        router.require_basic_auth("/data", "Data", "alice", "s3cret");
+        // However the path's written, it's the protected one
+        for path in ["//data", "/./data", "/x/../data", "/%64ata", "/data/."] {
+            let mut reader = io::Cursor::new(format!("GET {path} HTTP/1.0\r\n\r\n"));
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &router,
+                &quiet_log(),
+            )
+            .unwrap();
+            assert!(
+                response.starts_with(b"HTTP/1.1 401 UNAUTHORIZED\r\n"),
+                "{path} wasn't protected"
+            );
+        }
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn serve_requests_custom_not_found() {
+        let mut router = default_router();
+        router.set_not_found(Box::new(|request| {
+            Response::new(404)
+                .header("Content-Type", "text/plain")
+                .body(format!("No {} here", request.line.path))
+        }));
+        let mut reader = io::Cursor::new("GET /nope HTTP/1.0\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(response.ends_with("\r\n\r\nNo /nope here"));
+    }
+
+    #[test]
+    fn serve_requests_file_cache() {
+        let (mut router, file) = range_router("cache");
+        router.set_file_cache(FileCache::new(4096));
+        let mut reader = io::Cursor::new("GET /data HTTP/1.1\r\n\r\n");
+        let mut first = Vec::new();
+        serve_requests(&mut Duplex(&mut reader, &mut first), &router, &quiet_log()).unwrap();
+        assert_eq!(1000, router.file_cache().unwrap().size());
+
+        // The second response comes from the cache
+        let contents = fs::read(&file).unwrap();
+        let mut reader = io::Cursor::new("GET /data HTTP/1.1\r\n\r\n");
+        let mut second = Vec::new();
+        serve_requests(&mut Duplex(&mut reader, &mut second), &router, &quiet_log()).unwrap();
+        assert_eq!(strip_date(&first), strip_date(&second));
+        assert!(second.ends_with(&contents));
+        fs::remove_file(file).unwrap();
+    }
+
+    // A router serving a stylesheet at /style.css, along with a
+    // gzipped copy of it if gz is set. Returns the files made.
+    fn precompressed_router(name: &str, gz: bool) -> (Router, Vec<PathBuf>) {
+        let file = std::env::temp_dir().join(format!(
+            "rust-tutorial-webserver-{}-{name}.css",
+            std::process::id()
+        ));
+        fs::write(&file, "body { color: red; }").unwrap();
+        let mut files = vec![file.clone()];
+        if gz {
+            let gz_file = file.with_extension("css.gz");
+            // Not real gzip, so it's clear which file was served
+            fs::write(&gz_file, "precompressed").unwrap();
+            files.push(gz_file);
+        }
+        let mut router = Router::new();
+        router.insert("GET", "/style.css", file.to_str().unwrap());
+        (router, files)
+    }
+
+    fn get_style(router: &Router, accept_encoding: &str) -> String {
+        let mut reader = io::Cursor::new(format!(
+            "GET /style.css HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n"
+        ));
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            router,
+            &quiet_log(),
+        )
+        .unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn serve_requests_precompressed() {
+        let (router, files) = precompressed_router("precompressed", true);
+        let response = get_style(&router, "gzip");
+        assert!(response.contains("\r\nContent-Type: text/css; charset=utf-8\r\n"));
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert_eq!(1, response.matches("\r\nVary: Accept-Encoding\r\n").count());
+        assert!(response.ends_with("\r\n\r\nprecompressed"));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn serve_requests_precompressed_missing() {
+        let (router, files) = precompressed_router("precompressed_missing", false);
+        // Compressed on the fly instead
+        let response = get_style(&router, "gzip");
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(!response.contains("precompressed"));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn serve_requests_precompressed_not_accepted() {
+        let (router, files) = precompressed_router("precompressed_not_accepted", true);
+        for accept_encoding in ["br", "gzip;q=0", "deflate"] {
+            let response = get_style(&router, accept_encoding);
+            assert!(!response.contains("precompressed"), "{accept_encoding}");
+            assert!(
+                !response.contains("Content-Encoding: gzip"),
+                "{accept_encoding}"
+            );
+        }
+        let identity = get_style(&router, "identity");
+        assert!(identity.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert!(identity.ends_with("\r\n\r\nbody { color: red; }"));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn serve_requests_precompressed_stale() {
+        let (router, files) = precompressed_router("precompressed_stale", true);
+        // Made before the stylesheet last changed
+        let modified = fs::metadata(&files[0]).unwrap().modified().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&files[1])
+            .unwrap()
+            .set_modified(modified - Duration::from_secs(60))
+            .unwrap();
+        // Compressed on the fly instead
+        let response = get_style(&router, "gzip");
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(!response.contains("precompressed"));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn serve_requests_precompressed_cached() {
+        let (mut router, files) = precompressed_router("precompressed_cached", true);
+        router.set_file_cache(FileCache::new(4096));
+        let first = get_style(&router, "gzip");
+        // Only the gzipped copy was read
+        assert_eq!("precompressed".len(), router.file_cache().unwrap().size());
+        let second = get_style(&router, "gzip");
+        assert!(first.ends_with("\r\n\r\nprecompressed"));
+        assert!(second.ends_with("\r\n\r\nprecompressed"));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    #[test]
+    fn serve_requests_precompressed_streamed() {
+        let (router, files) = precompressed_router("precompressed_streamed", true);
+        fs::write(&files[1], vec![b'z'; CHUNKED_THRESHOLD as usize + 1]).unwrap();
+        let serve = |method: &str| {
+            let mut reader = io::Cursor::new(format!(
+                "{method} /style.css HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"
+            ));
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &router,
+                &quiet_log(),
+            )
+            .unwrap();
+            response
+        };
+
+        let get = serve("GET");
+        let split = get.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(get[..split].to_vec()).unwrap();
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(head.ends_with("\r\nTransfer-Encoding: chunked"));
+        assert!(get.ends_with(b"\r\n0\r\n\r\n"));
+
+        // Just the same head, without reading the file
+        let head_response = serve("HEAD");
+        assert_eq!(strip_date(&get[..split + 4]), strip_date(&head_response));
+        files
+            .into_iter()
+            .for_each(|file| fs::remove_file(file).unwrap());
+    }
+
+    // A router serving a 1000 byte file whose every byte is its
+    // offset mod 256, so any part of it can be checked
+    fn range_router(name: &str) -> (Router, PathBuf) {
+        let file = std::env::temp_dir().join(format!(
+            "rust-tutorial-webserver-{}-{name}.bin",
+            std::process::id()
+        ));
+        let contents: Vec<u8> = (0..1000).map(|n| (n % 256) as u8).collect();
+        fs::write(&file, contents).unwrap();
+        let mut router = Router::new();
+        router.insert("GET", "/data", file.to_str().unwrap());
+        (router, file)
+    }
+
+    fn range_request(router: &Router, range: &str) -> Vec<u8> {
+        let request = format!("GET /data HTTP/1.0\r\nRange: {range}\r\n\r\n");
+        let mut reader = io::Cursor::new(request);
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            router,
+            &quiet_log(),
+        )
+        .unwrap();
+        response
+    }
+
+    // Split a response into its head and body
+    fn split_response(response: &[u8]) -> (String, &[u8]) {
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, &response[split + 4..])
+    }
+
+    #[test]
+    fn serve_requests_range() {
+        let (router, file) = range_router("range");
+        let response = range_request(&router, "bytes=0-1023");
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 206 PARTIAL CONTENT\r\n"));
+        assert!(head.contains("\r\nContent-Range: bytes 0-999/1000\r\n"));
+        assert!(head.contains("\r\nContent-Length: 1000"));
+        assert_eq!(fs::read(&file).unwrap(), body);
+
+        let response = range_request(&router, "bytes=10-19");
+        let (head, body) = split_response(&response);
+        assert!(head.contains("\r\nContent-Range: bytes 10-19/1000\r\n"));
+        assert!(head.contains("\r\nContent-Length: 10"));
+        assert_eq!((10..20).collect::<Vec<u8>>(), body);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn serve_requests_range_open_ended() {
+        let (router, file) = range_router("open-ended");
+        let response = range_request(&router, "bytes=500-");
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 206 PARTIAL CONTENT\r\n"));
+        assert!(head.contains("\r\nContent-Range: bytes 500-999/1000\r\n"));
+        assert!(head.contains("\r\nContent-Length: 500"));
+        assert_eq!(&fs::read(&file).unwrap()[500..], body);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn serve_requests_range_unsatisfiable() {
+        let (router, file) = range_router("unsatisfiable");
+        let response = range_request(&router, "bytes=1000-2000");
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 416 RANGE NOT SATISFIABLE\r\n"));
+        assert!(head.contains("\r\nContent-Range: bytes */1000\r\n"));
+        assert_eq!(BODY_416.as_bytes(), body);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn serve_requests_range_ignored() {
+        let (router, file) = range_router("ignored");
+        let response = range_request(&router, "bytes=0-1,5-9");
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(fs::read(&file).unwrap(), body);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn handle_connection_access_log() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = Arc::clone(&lines);
+        let logger: Logger = Arc::new(move |_, message| {
+            captured.lock().unwrap().push(String::from(message));
+        });
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET /nope HTTP/1.0\r\n\r\n")
+            .unwrap();
+        handle_connection(server, &default_router(), &logger).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+
+        let lines = lines.lock().unwrap();
+        let client_addr = client.local_addr().unwrap().to_string();
+        assert_eq!(2, lines.len());
+        let (id, line) = lines[0].split_once(' ').unwrap();
+        assert!(id.starts_with('#'));
+        assert!(line.starts_with(&format!("{client_addr} \"GET / HTTP/1.1\" 200 ")));
+        assert!(lines[1].starts_with(&format!("{id} {client_addr} \"GET /nope HTTP/1.0\" 404 ")));
+        // The byte counts add up to everything the client got
+        let bytes: usize = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(response.len(), bytes);
+    }
+
+    #[test]
+    fn handle_connection_ids_concurrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = Arc::clone(&lines);
+        let logger: Logger = Arc::new(move |_, message| {
+            captured.lock().unwrap().push(String::from(message));
+        });
+
+        // Both connections are open at once, and their
+        // requests interleave
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let logger = Arc::clone(&logger);
+            servers.push(thread::spawn(move || {
+                handle_connection(server, &default_router(), &logger)
+            }));
+            clients.push(client);
+        }
+        for _ in 0..2 {
+            for client in &mut clients {
+                client.write_all(b"GET /nope HTTP/1.1\r\n\r\n").unwrap();
+                let mut head = [0; 12];
+                client.read_exact(&mut head).unwrap();
+            }
+        }
+        for (client, server) in clients.iter().zip(servers) {
+            client.shutdown(Shutdown::Write).unwrap();
+            server.join().unwrap().unwrap();
+        }
+
+        // Each client's lines share an id no other client's have
+        let lines = lines.lock().unwrap();
+        assert_eq!(4, lines.len());
+        let mut ids = Vec::new();
+        for client in &clients {
+            let addr = client.local_addr().unwrap().to_string();
+            let own: Vec<&str> = lines
+                .iter()
+                .filter(|line| line.contains(&format!(" {addr} ")))
+                .map(|line| line.split(' ').next().unwrap())
+                .collect();
+            assert_eq!(2, own.len());
+            assert_eq!(own[0], own[1]);
+            ids.push(own[0]);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn serve_requests_access_log_bad_request() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = Arc::clone(&lines);
+        let logger: Logger = Arc::new(move |level, message| {
+            if level == LogLevel::Info {
+                captured.lock().unwrap().push(String::from(message));
+            }
+        });
+        let log = AccessLog::new(String::from("1.2.3.4:5"), &logger);
+        let mut reader = io::Cursor::new("nonsense\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &log,
+        )
+        .unwrap();
+
+        let expected = format!("#{} 1.2.3.4:5 \"-\" 400 {}", log.id, response.len());
+        assert_eq!(vec![expected], *lines.lock().unwrap());
+    }
+
+    #[test]
+    fn serve_requests_body() {
+        let mut reader = io::Cursor::new(
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbodyGET / HTTP/1.1\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        // The body was skipped over, so the second request was
+        // read from the right place
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    // A router with a body handler at "/upload" which reads up to
+    // max bytes of the body, answering with what it read
+    fn upload_router(max: u64) -> Router {
+        let mut router = default_router();
+        router.insert_body_handler(
+            "PUT",
+            "/upload",
+            move |_: &Request, body: &mut BodyReader| {
+                let mut read = Vec::new();
+                match body.take(max).read_to_end(&mut read) {
+                    Ok(_) => Response::ok().body(read),
+                    Err(_) => Response::new(400),
+                }
+            },
+        );
+        router
+    }
+
+    #[test]
+    fn serve_requests_body_handler() {
+        let mut reader = io::Cursor::new(
+            "PUT /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            PUT /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nhey\r\n4\r\n you\r\n0\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &upload_router(100),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(strip_date(&response)).unwrap();
+        let bodies: Vec<_> = response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| response.split_once("\r\n\r\n").unwrap().1)
+            .collect();
+        assert_eq!(vec!["hello", "hey you"], bodies);
+    }
+
+    #[test]
+    fn serve_requests_body_handler_drains() {
+        // Reads just the first 2 bytes of each
+        let body = "a".repeat(2 * RequestLimits::default().max_body_size);
+        let request = format!(
+            "PUT /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789\
+            PUT /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+            PUT /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}\
+            GET / HTTP/1.1\r\n\r\n",
+            body.len()
+        );
+        let mut reader = io::Cursor::new(request);
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &upload_router(2),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        // What was left of the first two bodies was skipped
+        // over. The third was too long to drain, so the
+        // connection was closed after it.
+        let response = String::from_utf8(strip_date(&response)).unwrap();
+        assert_eq!(
+            3,
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            "{response}"
+        );
+        assert_eq!(1, response.matches("\r\nConnection: close\r\n").count());
+        assert!(response.ends_with("\r\n\r\naa"), "{response}");
+    }
+
+    #[test]
+    fn serve_requests_body_handler_continue() {
+        let mut reader = io::Cursor::new(
+            "PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &upload_router(100),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        let final_response = response
+            .strip_prefix("HTTP/1.1 100 Continue\r\n\r\n")
+            .unwrap();
+        assert!(final_response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(final_response.ends_with("\r\n\r\nbody"));
+    }
+
+    #[test]
+    fn serve_requests_body_handler_unauthorized() {
+        let mut router = upload_router(100);
+        router.require_basic_auth("/upload", "uploads", "user", "pass");
+        let mut reader = io::Cursor::new(
+            "PUT /upload HTTP/1.1\r\nContent-Length: 22\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        // The body wasn't read, so it can't be mistaken for the
+        // next request
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\nConnection: close\r\n"));
+        assert_eq!(1, response.matches("HTTP/1.1 ").count());
+    }
+
+    #[test]
+    fn serve_requests_chunked_body() {
+        let mut reader = io::Cursor::new(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n0\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+        assert_eq!(1, response.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    fn cors_router() -> Router {
+        let mut router = upload_router(100);
+        router.set_cors(
+            Cors::new()
+                .allow_origin("https://app.example.com")
+                .allow_method("PUT")
+                .allow_header("Content-Type"),
+        );
+        router
+    }
+
+    #[test]
+    fn serve_requests_cors_preflight() {
+        // Answered even though the upload needs credentials
+        let mut router = cors_router();
+        router.require_basic_auth("/upload", "uploads", "user", "pass");
+        let mut reader = io::Cursor::new(
+            "OPTIONS /upload HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+            Access-Control-Request-Method: PUT\r\n\
+            Access-Control-Request-Headers: content-type\r\n\r\n\
+            OPTIONS /upload HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+            Access-Control-Request-Method: DELETE\r\n\r\n\
+            OPTIONS / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(strip_date(&response)).unwrap();
+        let mut responses = response.split("HTTP/1.1 ").skip(1);
+        assert_eq!(
+            Some(
+                "204 NO CONTENT\r\n\
+                Access-Control-Allow-Origin: https://app.example.com\r\n\
+                Access-Control-Allow-Methods: GET, HEAD, POST, PUT\r\n\
+                Access-Control-Allow-Headers: Content-Type\r\n\
+                Vary: Origin\r\n\r\n"
+            ),
+            responses.next()
+        );
+        assert_eq!(
+            Some("403 FORBIDDEN\r\nVary: Origin\r\nContent-Length: 0\r\n\r\n"),
+            responses.next()
+        );
+        // Not a preflight, so routed as usual
+        assert!(responses
+            .next()
+            .unwrap()
+            .starts_with("405 METHOD NOT ALLOWED\r\nVary: Origin\r\nContent-Type: "));
+    }
+
+    #[test]
+    fn serve_requests_cors_get() {
+        let mut reader = io::Cursor::new(
+            "GET / HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n\
+            GET / HTTP/1.1\r\nOrigin: https://evil.example.com\r\n\r\n\
+            GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &cors_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        let responses: Vec<_> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(3, responses.len());
+        assert!(responses[0].starts_with(
+            "200 OK\r\nAccess-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\n"
+        ));
+        assert!(responses[1].starts_with("200 OK\r\nVary: Origin\r\nDate: "));
+        assert!(responses[2].starts_with("200 OK\r\nVary: Origin\r\nDate: "));
+    }
+
+    #[test]
+    fn serve_requests_continue() {
+        let mut reader = io::Cursor::new(
+            "POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        let final_response = response
+            .strip_prefix("HTTP/1.1 100 Continue\r\n\r\n")
+            .unwrap();
+        assert!(final_response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+    }
+
+    #[test]
+    fn serve_requests_body_too_large() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            RequestLimits::default().max_body_size + 1
+        );
+        let mut reader = io::Cursor::new(request);
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &default_router(),
+            &quiet_log(),
+        )
+        .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 413 PAYLOAD TOO LARGE\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn serve_requests_headers_too_large() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000));
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(20_000)
+        );
+        for request in [long_line, long_header] {
+            let mut reader = io::Cursor::new(request);
+            let mut response = Vec::new();
+            serve_requests(
+                &mut Duplex(&mut reader, &mut response),
+                &default_router(),
+                &quiet_log(),
+            )
+            .unwrap();
+            assert!(response.starts_with(
+                b"HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\nConnection: close\r\n"
+            ));
+        }
+    }
+
+    #[test]
+    fn serve_requests_unreadable_route() {
+        let mut router = Router::new();
+        router.insert("GET", "/broken", "no-such-file.html");
+        let mut reader = io::Cursor::new("GET /broken HTTP/1.0\r\n\r\n");
+        let mut response = Vec::new();
+        serve_requests(
+            &mut Duplex(&mut reader, &mut response),
+            &router,
+            &quiet_log(),
+        )
+        .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR\r\n"));
+        assert!(response.ends_with(&fs::read_to_string(PAGE_500).unwrap()));
+    }
+
+    #[test]
+    fn page_or_fallback() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = Arc::clone(&lines);
+        let logger: Logger = Arc::new(move |level, message| {
+            captured
+                .lock()
+                .unwrap()
+                .push((level, String::from(message)));
+        });
+        let log = AccessLog::new(String::from("1.2.3.4:5"), &logger);
+        assert_eq!(
+            BODY_500.as_bytes(),
+            page_or("no-such-file.html", BODY_500, &log)
+        );
+        let lines = lines.lock().unwrap();
+        assert_eq!(1, lines.len());
+        assert_eq!(LogLevel::Error, lines[0].0);
+        let prefix = format!("#{} 1.2.3.4:5 Error reading no-such-file.html: ", log.id);
+        assert!(lines[0].1.starts_with(&prefix));
+        assert_eq!(
+            fs::read(PAGE_500).unwrap(),
+            page_or(PAGE_500, BODY_500, &quiet_log())
+        );
+    }
+
+    // Hands out its data a few bytes per read
+    struct Trickle<'a>(&'a [u8], usize);
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(self.1).min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn write_chunked_framing() {
+        let mut response = Vec::new();
+        let body = Trickle(b"Hello, chunked world!", 16);
+        write_chunked(&mut response, 200, CONTENT_TYPE_HTML, &[], body).unwrap();
+
+        let expected = "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Transfer-Encoding: chunked\r\n\r\n\
+            10\r\nHello, chunked w\r\n\
+            5\r\norld!\r\n\
+            0\r\n\r\n";
+        assert_eq!(expected, String::from_utf8(strip_date(&response)).unwrap());
+    }
+
+    #[test]
+    fn write_chunked_empty() {
+        let mut response = Vec::new();
+        write_chunked(&mut response, 200, CONTENT_TYPE_HTML, &[], io::empty()).unwrap();
+        assert!(response.ends_with(b"chunked\r\n\r\n0\r\n\r\n"));
+    }
+}
@@ -0,0 +1,309 @@
+//! Cross-origin resource sharing, so pages served from other origins can call the
+//! server from a browser.
+
+use std::time::Duration;
+
+use super::{request::Request, response::Response};
+
+/// Which other origins may make requests to the server from a browser, and how, given
+/// to [Router::set_cors](super::router::Router::set_cors).
+///
+/// A browser only lets a page read the response to a request for another origin if the
+/// response says that origin's allowed, with `Access-Control-Allow-Origin`. Before
+/// requests which aren't simple, e.g. a `PUT` or one with a custom header, it first
+/// sends an `OPTIONS` preflight request asking whether they'd be allowed. The server
+/// answers preflights with `204 No Content` and the `Access-Control-Allow-*` headers
+/// if the origin, method and headers asked for are all allowed, or `403 Forbidden`
+/// otherwise.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use rust_tutorial_webserver::server::{router::Router, Cors};
+/// let mut router = Router::new();
+/// router.set_cors(
+///     Cors::new()
+///         .allow_origin("https://app.example.com")
+///         .allow_method("PUT")
+///         .allow_header("Content-Type")
+///         .max_age(Duration::from_secs(600)),
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    max_age: Option<Duration>,
+}
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+impl Cors {
+    /// Create settings allowing no origins yet, with the `GET`, `HEAD` and `POST`
+    /// methods and no extra headers.
+    pub fn new() -> Cors {
+        Cors::default()
+    }
+
+    /// Allow requests from `origin`, e.g. `https://app.example.com`, or from any origin
+    /// if it's `*`.
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        self.origins.push(String::from(origin));
+        self
+    }
+
+    /// Allow requests with `method` as well as those allowed already. Methods are case
+    /// sensitive, e.g. `DELETE` but not `delete`.
+    pub fn allow_method(mut self, method: &str) -> Cors {
+        if !self.methods.iter().any(|allowed| allowed == method) {
+            self.methods.push(String::from(method));
+        }
+        self
+    }
+
+    /// Allow requests to send the header `name`, e.g. `Content-Type` or `X-Api-Key`.
+    /// Header names aren't case sensitive.
+    pub fn allow_header(mut self, name: &str) -> Cors {
+        self.headers.push(String::from(name));
+        self
+    }
+
+    /// Let browsers remember the answer to a preflight for `max_age`, rather than asking
+    /// again before every request. Browsers cap how long they'll remember it for.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // The Access-Control-Allow-Origin for requests from origin,
+    // if it's allowed
+    fn allowed_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            self.origins
+                .iter()
+                .any(|allowed| allowed == origin)
+                .then_some(origin)
+        }
+    }
+
+    // Responses differ by origin unless every origin is
+    // allowed, so caches have to know to keep them apart
+    fn vary(&self) -> Option<(String, String)> {
+        (!self.origins.iter().any(|allowed| allowed == "*"))
+            .then(|| (String::from("Vary"), String::from("Origin")))
+    }
+
+    fn allows_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }
+
+    // The headers to add to the response to an ordinary, not
+    // preflight, request
+    pub(super) fn headers(&self, request: &Request) -> Vec<(String, String)> {
+        let origin = request
+            .headers
+            .get("Origin")
+            .and_then(|origin| self.allowed_origin(origin));
+        origin
+            .map(|origin| {
+                (
+                    String::from("Access-Control-Allow-Origin"),
+                    String::from(origin),
+                )
+            })
+            .into_iter()
+            .chain(self.vary())
+            .collect()
+    }
+
+    // Answer a preflight request, allowing it only if its
+    // origin, the method it asks for and every header it asks
+    // for are allowed
+    pub(super) fn preflight(&self, request: &Request) -> Response {
+        let origin = request
+            .headers
+            .get("Origin")
+            .and_then(|origin| self.allowed_origin(origin));
+        let method = request
+            .headers
+            .get("Access-Control-Request-Method")
+            .map(str::trim)
+            .filter(|method| self.methods.iter().any(|allowed| allowed == method));
+        let headers_allowed = request
+            .headers
+            .get("Access-Control-Request-Headers")
+            .is_none_or(|names| {
+                names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .all(|name| self.allows_header(name))
+            });
+
+        let mut response = match (origin, method, headers_allowed) {
+            (Some(origin), Some(_), true) => {
+                let mut response = Response::new(204)
+                    .header("Access-Control-Allow-Origin", origin)
+                    .header("Access-Control-Allow-Methods", &self.methods.join(", "));
+                if !self.headers.is_empty() {
+                    response =
+                        response.header("Access-Control-Allow-Headers", &self.headers.join(", "));
+                }
+                if let Some(max_age) = self.max_age {
+                    response =
+                        response.header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+                }
+                response
+            }
+            _ => Response::new(403),
+        };
+        response.headers.extend(self.vary());
+        response
+    }
+}
+
+// Whether a request is a CORS preflight: an OPTIONS request
+// from an origin, asking whether it may use a method
+pub(super) fn is_preflight(request: &Request) -> bool {
+    request.line.method == "OPTIONS"
+        && request.headers.get("Origin").is_some()
+        && request
+            .headers
+            .get("Access-Control-Request-Method")
+            .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::read_request;
+    use pretty_assertions::assert_eq;
+
+    fn request(method: &str, headers: &[&str]) -> Request {
+        let headers: String = headers.iter().map(|h| format!("{h}\r\n")).collect();
+        let text = format!("{method} /api HTTP/1.1\r\n{headers}\r\n");
+        read_request(&mut text.as_bytes(), &Default::default()).unwrap()
+    }
+
+    fn cors() -> Cors {
+        Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_method("PUT")
+            .allow_header("Content-Type")
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn is_preflight_needs_all_parts() {
+        let origin = "Origin: https://app.example.com";
+        let method = "Access-Control-Request-Method: PUT";
+        assert!(is_preflight(&request("OPTIONS", &[origin, method])));
+        assert!(!is_preflight(&request("OPTIONS", &[origin])));
+        assert!(!is_preflight(&request("OPTIONS", &[method])));
+        assert!(!is_preflight(&request("GET", &[origin, method])));
+    }
+
+    #[test]
+    fn preflight_allowed() {
+        let response = cors().max_age(Duration::from_secs(600)).preflight(&request(
+            "OPTIONS",
+            &[
+                "Origin: https://app.example.com",
+                "Access-Control-Request-Method: PUT",
+                "Access-Control-Request-Headers: content-type",
+            ],
+        ));
+        // As sent, without the Date header
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        let (status_line, rest) = written.split_once("\r\n").unwrap();
+        let (_date, rest) = rest.split_once("\r\n").unwrap();
+        assert_eq!(
+            "HTTP/1.1 204 NO CONTENT\r\n\
+            Access-Control-Allow-Origin: https://app.example.com\r\n\
+            Access-Control-Allow-Methods: GET, HEAD, POST, PUT\r\n\
+            Access-Control-Allow-Headers: Content-Type\r\n\
+            Access-Control-Max-Age: 600\r\n\
+            Vary: Origin\r\n\r\n",
+            format!("{status_line}\r\n{rest}")
+        );
+    }
+
+    #[test]
+    fn preflight_refused() {
+        let refused: [&[&str]; 3] = [
+            &[
+                "Origin: https://evil.example.com",
+                "Access-Control-Request-Method: PUT",
+            ],
+            &[
+                "Origin: https://app.example.com",
+                "Access-Control-Request-Method: DELETE",
+            ],
+            &[
+                "Origin: https://app.example.com",
+                "Access-Control-Request-Method: PUT",
+                "Access-Control-Request-Headers: Content-Type, X-Secret",
+            ],
+        ];
+        for headers in refused {
+            let response = cors().preflight(&request("OPTIONS", headers));
+            assert_eq!(403, response.status);
+            assert_eq!(None, header(&response, "Access-Control-Allow-Origin"));
+        }
+    }
+
+    #[test]
+    fn headers_any_origin() {
+        let cors = Cors::new().allow_origin("*");
+        assert_eq!(
+            vec![(
+                String::from("Access-Control-Allow-Origin"),
+                String::from("*")
+            )],
+            cors.headers(&request("GET", &["Origin: https://anywhere.example.com"]))
+        );
+        assert!(cors.headers(&request("GET", &[])).is_empty());
+    }
+
+    #[test]
+    fn headers_listed_origin() {
+        let allowed = cors().headers(&request("GET", &["Origin: https://app.example.com"]));
+        assert_eq!(
+            vec![
+                (
+                    String::from("Access-Control-Allow-Origin"),
+                    String::from("https://app.example.com")
+                ),
+                (String::from("Vary"), String::from("Origin")),
+            ],
+            allowed
+        );
+        let refused = cors().headers(&request("GET", &["Origin: https://evil.example.com"]));
+        assert_eq!(
+            vec![(String::from("Vary"), String::from("Origin"))],
+            refused
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Dates in the format HTTP uses.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Format `now` as an HTTP date (IMF-fixdate), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+///
+/// Times before 1970 are given as the start of 1970.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use rust_tutorial_webserver::server::http_date;
+/// let date = http_date(UNIX_EPOCH + Duration::from_secs(784_111_777));
+/// assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", date);
+/// ```
+pub fn http_date(now: SystemTime) -> String {
+    let seconds = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = seconds / SECONDS_PER_DAY;
+    let time = seconds % SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        // The epoch was a Thursday
+        DAY_NAMES[(days % 7) as usize],
+        MONTH_NAMES[month as usize - 1],
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+    )
+}
+
+/// Parse an HTTP date in the format [http_date] writes.
+///
+/// Returns `None` if `date` isn't a valid IMF-fixdate. The older formats HTTP allows
+/// aren't understood, since clients only send them back if a server sent them first.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    // e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+    let parts: Vec<&str> = date.split(' ').collect();
+    let [day_name, day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day_name = day_name.strip_suffix(',')?;
+    if !DAY_NAMES.contains(&day_name) || year.len() != 4 {
+        return None;
+    }
+    let day = two_digits(day)?;
+    let month = MONTH_NAMES.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let clock: Vec<u64> = time.split(':').map(two_digits).collect::<Option<_>>()?;
+    let [hours, minutes, seconds] = clock[..] else {
+        return None;
+    };
+    if year < 1970 || day == 0 || day > 31 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    // Catches days past the end of the month, e.g. 31 Feb
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    let seconds = days * SECONDS_PER_DAY + hours * 3600 + minutes * 60 + seconds;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+// The (year, month, day) which is `days` days after 1970-01-01,
+// using Howard Hinnant's civil_from_days algorithm. Counts in
+// 400-year eras, which always have the same number of days,
+// and in years starting from March so leap days come last.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch back to 0000-03-01
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+// Parse a number written with exactly two digits, and no sign
+fn two_digits(number: &str) -> Option<u64> {
+    if number.len() == 2 && number.bytes().all(|byte| byte.is_ascii_digit()) {
+        number.parse().ok()
+    } else {
+        None
+    }
+}
+
+// The number of days from 1970-01-01 to the given date, the
+// inverse of civil_from_days. Only valid for 1970 onwards.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Years start in March, so January and February belong to
+    // the year before
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn http_date_rfc_example() {
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(at(784_111_777)));
+    }
+
+    #[test]
+    fn http_date_epoch() {
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", http_date(UNIX_EPOCH));
+        assert_eq!(
+            "Thu, 01 Jan 1970 00:00:00 GMT",
+            http_date(UNIX_EPOCH - Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn http_date_leap_day() {
+        assert_eq!("Tue, 29 Feb 2000 23:59:59 GMT", http_date(at(951_868_799)));
+        assert_eq!("Wed, 01 Mar 2000 00:00:00 GMT", http_date(at(951_868_800)));
+        assert_eq!(
+            "Fri, 31 Dec 2100 12:00:00 GMT",
+            http_date(at(4_133_937_600))
+        );
+    }
+
+    #[test]
+    fn parse_http_date_round_trip() {
+        for seconds in [0, 784_111_777, 951_868_799, 951_868_800, 4_133_937_600] {
+            assert_eq!(Some(at(seconds)), parse_http_date(&http_date(at(seconds))));
+        }
+    }
+
+    #[test]
+    fn parse_http_date_invalid() {
+        for date in [
+            "",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 31 Feb 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Xyz, 06 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(None, parse_http_date(date), "{date}");
+        }
+    }
+}
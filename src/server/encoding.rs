@@ -0,0 +1,214 @@
+//! Compression of response bodies.
+
+use std::io::{self, Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+/// The encodings the server can compress bodies with, in order of preference
+pub const ENCODINGS: [&str; 2] = ["gzip", "deflate"];
+
+/// Pick the encoding to send a body in, given the client's `Accept-Encoding` header and
+/// the encodings the server supports.
+///
+/// Each encoding the client lists may have a quality from 0 to 1, e.g. `gzip;q=0.5`,
+/// defaulting to 1. A quality of 0 refuses it, and `*` stands for anything not listed.
+/// The supported encoding with the highest quality wins, earlier ones in `supported`
+/// breaking ties.
+///
+/// Returns `identity`, meaning the body is sent as it is, if the client prefers that or
+/// accepts none of `supported`. Returns `None` if the client accepts nothing the
+/// server can send, having refused `identity` too, e.g. with `identity;q=0`.
+pub fn choose_encoding<'a>(header: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let listed: Vec<(&str, f32)> = header.split(',').filter_map(parse_coding).collect();
+    let quality = |name: &str| {
+        listed
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+            .or_else(|| listed.iter().find(|(coding, _)| *coding == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let best = supported
+        .iter()
+        .map(|&encoding| (encoding, quality(encoding).unwrap_or(0.0)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(&str, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        );
+    // Unless the client says otherwise, identity is always
+    // acceptable, but only as a last resort
+    match (best, quality("identity")) {
+        (Some((_, q)), Some(identity_q)) if identity_q > q => Some("identity"),
+        (Some((encoding, _)), _) => Some(encoding),
+        (None, Some(identity_q)) if identity_q <= 0.0 => None,
+        (None, _) => Some("identity"),
+    }
+}
+
+// Split a coding from Accept-Encoding into its name and
+// quality. Codings with a malformed quality are left out.
+fn parse_coding(coding: &str) -> Option<(&str, f32)> {
+    let mut params = coding.split(';').map(str::trim);
+    let name = params.next().filter(|name| !name.is_empty())?;
+    let mut q = 1.0;
+    for param in params {
+        if let Some(value) = param.strip_prefix("q=") {
+            q = value
+                .parse::<f32>()
+                .ok()
+                .filter(|q| (0.0..=1.0).contains(q))?;
+        }
+    }
+    Some((name, q))
+}
+
+/// Whether a body of the given Content-Type is worth compressing.
+///
+/// Text shrinks well. Images and the like are usually compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/javascript" | "application/json" | "image/svg+xml"
+        )
+}
+
+/// Compress a body with gzip.
+pub fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Compress a body with deflate, which HTTP takes to mean the zlib format.
+pub fn deflate(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Compress a body with one of the [ENCODINGS], or return `None` for any other
+/// encoding.
+pub fn encode(encoding: &str, body: &[u8]) -> Option<io::Result<Vec<u8>>> {
+    match encoding {
+        "gzip" => Some(gzip(body)),
+        "deflate" => Some(deflate(body)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    #[test]
+    fn choose_encoding_listed() {
+        assert_eq!(Some("gzip"), choose_encoding("gzip", &ENCODINGS));
+        assert_eq!(
+            Some("gzip"),
+            choose_encoding("deflate, GZIP;q=0.5, br", &["gzip"])
+        );
+        assert_eq!(Some("deflate"), choose_encoding("deflate", &ENCODINGS));
+    }
+
+    #[test]
+    fn choose_encoding_quality_order() {
+        assert_eq!(
+            Some("deflate"),
+            choose_encoding("gzip;q=0.5, deflate;q=0.8", &ENCODINGS)
+        );
+        assert_eq!(
+            Some("gzip"),
+            choose_encoding("deflate;q=0.5 , gzip ; q=0.9", &ENCODINGS)
+        );
+        // Ties go to the server's preference
+        assert_eq!(Some("gzip"), choose_encoding("deflate, gzip", &ENCODINGS));
+        assert_eq!(Some("gzip"), choose_encoding("*", &ENCODINGS));
+        assert_eq!(
+            Some("deflate"),
+            choose_encoding("gzip;q=0.2, *;q=0.5", &ENCODINGS)
+        );
+    }
+
+    #[test]
+    fn choose_encoding_identity() {
+        assert_eq!(
+            Some("identity"),
+            choose_encoding("gzip;q=0.5, identity", &ENCODINGS)
+        );
+        // Listed encodings beat the implied identity
+        assert_eq!(Some("gzip"), choose_encoding("gzip;q=0.1", &ENCODINGS));
+        assert_eq!(Some("identity"), choose_encoding("", &ENCODINGS));
+    }
+
+    #[test]
+    fn choose_encoding_identity_refused() {
+        assert_eq!(None, choose_encoding("identity;q=0", &ENCODINGS));
+        assert_eq!(None, choose_encoding("br, *;q=0", &ENCODINGS));
+        assert_eq!(
+            Some("gzip"),
+            choose_encoding("gzip, identity;q=0", &ENCODINGS)
+        );
+        // An explicit identity overrides *
+        assert_eq!(
+            Some("identity"),
+            choose_encoding("*;q=0, identity", &ENCODINGS)
+        );
+    }
+
+    #[test]
+    fn choose_encoding_unsupported() {
+        assert_eq!(Some("identity"), choose_encoding("br, zstd", &ENCODINGS));
+        assert_eq!(Some("identity"), choose_encoding("x-gzip", &ENCODINGS));
+        assert_eq!(Some("identity"), choose_encoding("gzip;q=0", &ENCODINGS));
+        assert_eq!(Some("identity"), choose_encoding("gzip;q=lots", &ENCODINGS));
+        assert_eq!(Some("identity"), choose_encoding("gzip", &[]));
+    }
+
+    #[test]
+    fn is_compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/javascript; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = b"<h1>Hello!</h1>".repeat(100);
+        let compressed = gzip(&body).unwrap();
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(body, decompressed);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let body = b"<h1>Hello!</h1>".repeat(100);
+        let compressed = encode("deflate", &body).unwrap().unwrap();
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = Vec::new();
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(body, decompressed);
+        assert!(encode("br", &body).is_none());
+    }
+}
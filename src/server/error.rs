@@ -0,0 +1,67 @@
+//! The one error type returned by the server's public functions.
+
+use std::{error::Error, fmt, io};
+
+/// An error thrown while serving, wrapping whatever caused it.
+///
+/// [Error::source] gives the wrapped cause, and each cause converts into a ServerError
+/// with `?`. The message describes only what the server was doing, so print the
+/// sources too to see why it failed.
+///
+/// # Examples
+///
+/// ```
+/// use std::io;
+/// use rust_tutorial_webserver::server::ServerError;
+///
+/// fn bind() -> Result<(), ServerError> {
+///     Err(io::Error::from(io::ErrorKind::AddrInUse))?
+/// }
+/// assert!(matches!(bind(), Err(ServerError::Io(_))));
+/// ```
+#[derive(Debug)]
+pub enum ServerError {
+    /// Reading from or writing to a socket or file failed
+    Io(io::Error),
+}
+impl fmt::Display for ServerError {
+    // The cause is left to source, so it isn't repeated when
+    // the whole chain is printed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Io(_) => write!(f, "Server I/O error"),
+        }
+    }
+}
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+        }
+    }
+}
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn source_io() {
+        let e = ServerError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io::ErrorKind::ConnectionReset, source.kind());
+    }
+
+    #[test]
+    fn display_leaves_out_cause() {
+        let e = ServerError::from(io::Error::other("connection reset"));
+        assert_eq!("Server I/O error", e.to_string());
+        assert_eq!("connection reset", e.source().unwrap().to_string());
+    }
+}
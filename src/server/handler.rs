@@ -0,0 +1,80 @@
+//! Answering requests with user code, leaving the HTTP plumbing to the server.
+
+use super::{body::BodyReader, request::Request, response::Response};
+
+/// Something which answers a parsed request with a response.
+///
+/// Unlike a [ConnectionHandler](super::ConnectionHandler), a Handler never sees the
+/// connection: [handle_connection_with](super::handle_connection_with) reads each
+/// request and writes back the response, so a Handler only holds the application's
+/// logic and any state it needs. Closures taking a `&Request` and returning a
+/// [Response] are Handlers too.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use rust_tutorial_webserver::server::{request::Request, Handler, Response};
+///
+/// // Counts the requests it has answered
+/// struct Counter(AtomicUsize);
+/// impl Handler for Counter {
+///     fn handle(&self, _request: &Request) -> Response {
+///         let count = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+///         Response::ok().body(count.to_string())
+///     }
+/// }
+/// ```
+pub trait Handler: Send + Sync {
+    /// Build the response to `request`.
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Something which answers a request while reading its body as it arrives.
+///
+/// Registered with [Router::insert_body_handler](super::router::Router::insert_body_handler)
+/// for requests whose bodies are too big to hold in memory, like uploads which are
+/// better streamed to disk. The server doesn't read the body first, so
+/// [RequestLimits::max_body_size](super::request::RequestLimits::max_body_size) doesn't
+/// apply and [Request::body] is empty. Whatever of the body the handler leaves unread
+/// is drained once it returns, up to `max_body_size` bytes; beyond that the connection
+/// is closed after the response instead. Closures taking a `&Request` and a
+/// `&mut BodyReader` and returning a [Response] are BodyHandlers too.
+///
+/// # Examples
+///
+/// ```
+/// use std::io;
+/// use rust_tutorial_webserver::server::{
+///     request::Request, router::Router, BodyReader, Response,
+/// };
+/// let mut router = Router::new();
+/// router.insert_body_handler("PUT", "/upload", |_: &Request, body: &mut BodyReader| {
+///     match io::copy(body, &mut io::sink()) {
+///         Ok(length) => Response::ok().body(format!("{length} bytes")),
+///         Err(_) => Response::new(400),
+///     }
+/// });
+/// ```
+pub trait BodyHandler: Send + Sync {
+    /// Build the response to `request`, reading its body from `body`.
+    fn handle(&self, request: &Request, body: &mut BodyReader) -> Response;
+}
+
+impl<F> BodyHandler for F
+where
+    F: Fn(&Request, &mut BodyReader) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request, body: &mut BodyReader) -> Response {
+        self(request, body)
+    }
+}
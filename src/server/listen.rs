@@ -0,0 +1,771 @@
+//! Accepting TCP connections and handing them to a ThreadPool.
+
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{LogLevel, ThreadPool};
+
+use super::{error::ServerError, stream::Stream};
+
+// How long the accept loop sleeps when there's no connection
+// waiting, between checks of the stop flag
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The longest the accept loop waits before trying again after
+// an error. Errors like running out of file descriptors won't
+// go away straight away, so each one in a row doubles the wait,
+// starting from ACCEPT_POLL_INTERVAL.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// How long a connection over the limit waits for a free slot
+// before being refused
+const CONNECTION_WAIT: Duration = Duration::from_millis(50);
+
+// Sent to connections refused because the server is full
+const RESPONSE_503: &[u8] = b"HTTP/1.1 503 SERVICE UNAVAILABLE\r\n\
+    Content-Type: text/html; charset=utf-8\r\n\
+    Content-Length: 73\r\n\
+    Connection: close\r\n\r\n\
+    <!DOCTYPE html><html><body><h1>503 SERVICE UNAVAILABLE</h1></body></html>";
+
+/// A function which handles a single connection, plain or encrypted
+pub type ConnectionHandler = Arc<dyn Fn(Box<dyn Stream>) + Send + Sync>;
+
+/// Settings for [serve] and [serve_listener].
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::server::ServeOptions;
+/// let options = ServeOptions::new().max_connections(100);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    max_connections: Option<usize>,
+    nodelay: bool,
+}
+impl Default for ServeOptions {
+    fn default() -> ServeOptions {
+        ServeOptions {
+            max_connections: None,
+            nodelay: true,
+        }
+    }
+}
+impl ServeOptions {
+    /// Create the default options: no limit on connections, with `TCP_NODELAY` set.
+    pub fn new() -> ServeOptions {
+        ServeOptions::default()
+    }
+
+    /// Handle at most `max_connections` connections at once.
+    ///
+    /// A connection arriving while the server is full waits briefly for one to finish,
+    /// then is sent a 503 response and closed.
+    pub fn max_connections(mut self, max_connections: usize) -> ServeOptions {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on each connection accepted, or not.
+    ///
+    /// On by default, so small responses are sent straight away rather than held back by
+    /// Nagle's algorithm waiting for more data. Turning it off can save bandwidth when
+    /// clients mostly download large files.
+    pub fn nodelay(mut self, nodelay: bool) -> ServeOptions {
+        self.nodelay = nodelay;
+        self
+    }
+}
+
+/// Counts connections in flight, up to a limit
+struct ConnectionLimit {
+    in_flight: Mutex<usize>,
+    // Signalled when a connection finishes
+    released: Condvar,
+    max: usize,
+}
+impl ConnectionLimit {
+    fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+            max,
+        }
+    }
+
+    // Take a slot, waiting up to timeout for one to come free.
+    // The slot is given back when the permit is dropped.
+    fn acquire(self: &Arc<Self>, timeout: Duration) -> Option<ConnectionPermit> {
+        let in_flight = self.in_flight.lock().unwrap();
+        let (mut in_flight, _) = self
+            .released
+            .wait_timeout_while(in_flight, timeout, |in_flight| *in_flight >= self.max)
+            .unwrap();
+        if *in_flight >= self.max {
+            return None;
+        }
+        *in_flight += 1;
+        Some(ConnectionPermit(Arc::clone(self)))
+    }
+}
+
+/// A connection's slot under a [ConnectionLimit]
+struct ConnectionPermit(Arc<ConnectionLimit>);
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        *self.0.in_flight.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Bind a listener to `addr` which can be bound again straight after the server stops.
+///
+/// Sets `SO_REUSEADDR` before binding, so a restarted server isn't refused the address
+/// while the last one's closed connections linger in TIME_WAIT. `backlog` is how many
+/// connections may wait to be accepted before the OS starts turning them away. The
+/// listener can then be passed to [serve_listener].
+///
+/// Returns an error if `addr` can't be resolved or none of its addresses can be bound.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_tutorial_webserver::server::bind_reusable;
+/// let listener = bind_reusable("127.0.0.1:7878", 128).unwrap();
+/// ```
+pub fn bind_reusable(addr: &str, backlog: i32) -> io::Result<TcpListener> {
+    // Like TcpListener::bind, try each address the name
+    // resolves to until one works
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_reusable_addr(addr, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_reusable_addr(addr: SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Has to be set before bind() to have any effect
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+/// Listen on `addr`, passing each incoming connection to `handler` on one of the pool's
+/// workers.
+///
+/// Runs until `stop` is set, then waits for the connections already accepted to be
+/// handled and drops the pool, logging how many connections were left to drain.
+/// Connections which fail to be accepted are logged to the pool's
+/// [Logger](crate::Logger). See [ServeOptions] for the other settings.
+///
+/// Returns an error if `addr` can't be bound.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{io::Write, sync::{atomic::AtomicBool, Arc}};
+/// use rust_tutorial_webserver::{serve, server::ServeOptions, ThreadPool};
+/// let stop = Arc::new(AtomicBool::new(false));
+/// serve(
+///     "127.0.0.1:7878",
+///     ThreadPool::new(4),
+///     Arc::new(|mut stream| {
+///         let _ = stream.write_all(b"HTTP/1.1 204 NO CONTENT\r\n\r\n");
+///     }),
+///     stop,
+///     ServeOptions::new(),
+/// )
+/// .unwrap();
+/// ```
+pub fn serve(
+    addr: &str,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+) -> Result<(), ServerError> {
+    let listener = TcpListener::bind(addr)?;
+    serve_listener(listener, pool, handler, stop, options)
+}
+
+/// Like [serve], but accepting connections from a listener which is already bound.
+///
+/// Useful for binding to port 0 and finding out which port was picked with
+/// [TcpListener::local_addr] before serving.
+pub fn serve_listener(
+    listener: TcpListener,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+) -> Result<(), ServerError> {
+    Ok(accept_loop(listener, pool, stop, options, move |stream| {
+        handler(Box::new(stream))
+    })?)
+}
+
+/// Listen on each of `addrs` at once, passing incoming connections from all of them to
+/// `handler` on the same pool, like [serve].
+///
+/// Each address gets an accept thread of its own, and they all stop when `stop` is set.
+/// [ServeOptions::max_connections] limits the connections across all the addresses
+/// together. Addresses which can't be bound are logged to the pool's
+/// [Logger](crate::Logger) and skipped, so the server still runs on the rest.
+///
+/// Returns an error if none of `addrs` can be bound.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{io::Write, sync::{atomic::AtomicBool, Arc}};
+/// use rust_tutorial_webserver::{server::{serve_many, ServeOptions}, ThreadPool};
+/// let stop = Arc::new(AtomicBool::new(false));
+/// serve_many(
+///     &["127.0.0.1:7878", "[::1]:7878"],
+///     ThreadPool::new(4),
+///     Arc::new(|mut stream| {
+///         let _ = stream.write_all(b"HTTP/1.1 204 NO CONTENT\r\n\r\n");
+///     }),
+///     stop,
+///     ServeOptions::new(),
+/// )
+/// .unwrap();
+/// ```
+pub fn serve_many(
+    addrs: &[&str],
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+) -> Result<(), ServerError> {
+    let mut listeners = Vec::new();
+    let mut first_error = None;
+    for addr in addrs {
+        match TcpListener::bind(addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                (pool.logger)(
+                    LogLevel::Warn,
+                    &format!("Error binding {addr}: {e}; not listening there."),
+                );
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if listeners.is_empty() {
+        let e = first_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to listen on")
+        });
+        return Err(e.into());
+    }
+    serve_listeners(listeners, pool, handler, stop, options)
+}
+
+/// Like [serve_many], but accepting connections from listeners which are already bound.
+pub fn serve_listeners(
+    listeners: Vec<TcpListener>,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+) -> Result<(), ServerError> {
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    let accepts = listeners
+        .into_iter()
+        .map(|listener| move || listener.accept().map(|(stream, _)| stream))
+        .collect();
+    Ok(accept_all(accepts, pool, stop, options, move |stream| {
+        handler(Box::new(stream))
+    })?)
+}
+
+// Accept connections until stop is set, running connect on a
+// worker for each. serve_tls shares this, wrapping each stream
+// in TLS before handing it on.
+pub(super) fn accept_loop(
+    listener: TcpListener,
+    pool: ThreadPool,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+    connect: impl Fn(TcpStream) + Send + Sync + 'static,
+) -> io::Result<()> {
+    // A blocking accept() would never get round to checking
+    // the stop flag while no one is connecting
+    listener.set_nonblocking(true)?;
+    accept_streams(
+        || listener.accept().map(|(stream, _)| stream),
+        pool,
+        stop,
+        options,
+        connect,
+    )
+}
+
+/// A stream accept_streams can take from a nonblocking listener
+pub(super) trait Accepted: Write + Send + Sized + 'static {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Only TCP streams have anything to set
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Accepted for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+// Like accept_loop, taking streams from accept, which mustn't
+// block, so serve_unix can share it too
+pub(super) fn accept_streams<S: Accepted>(
+    accept: impl Fn() -> io::Result<S> + Send,
+    pool: ThreadPool,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+    connect: impl Fn(S) + Send + Sync + 'static,
+) -> io::Result<()> {
+    accept_all(vec![accept], pool, stop, options, connect)
+}
+
+// Like accept_streams, but taking streams from each of accepts
+// on a thread of its own, all feeding the same pool and sharing
+// one connection limit
+fn accept_all<S: Accepted>(
+    accepts: Vec<impl Fn() -> io::Result<S> + Send>,
+    pool: ThreadPool,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+    connect: impl Fn(S) + Send + Sync + 'static,
+) -> io::Result<()> {
+    let connect = Arc::new(connect);
+    let limit = options
+        .max_connections
+        .map(|max| Arc::new(ConnectionLimit::new(max)));
+
+    thread::scope(|scope| {
+        for accept in accepts {
+            let (pool, stop, options) = (&pool, &stop, &options);
+            let (connect, limit) = (&connect, &limit);
+            scope.spawn(move || accept_until_stopped(accept, pool, stop, options, connect, limit));
+        }
+    });
+
+    // Wait for connections already accepted to be handled,
+    // saying how many there were so it's clear what shutdown is
+    // waiting on
+    let remaining = pool.queued_jobs() + pool.active_count();
+    (pool.logger)(
+        LogLevel::Info,
+        &format!("Stopped accepting connections; draining {remaining} remaining jobs."),
+    );
+    let completed = pool.metrics().completed;
+    pool.join();
+    let completed = pool.metrics().completed.saturating_sub(completed);
+    (pool.logger)(
+        LogLevel::Info,
+        &format!("Drained; {completed} of the remaining jobs completed."),
+    );
+    drop(pool);
+    Ok(())
+}
+
+// One listener's accept loop, running until stop is set
+fn accept_until_stopped<S: Accepted, C: Fn(S) + Send + Sync + 'static>(
+    accept: impl Fn() -> io::Result<S>,
+    pool: &ThreadPool,
+    stop: &AtomicBool,
+    options: &ServeOptions,
+    connect: &Arc<C>,
+    limit: &Option<Arc<ConnectionLimit>>,
+) {
+    let mut backoff = ACCEPT_POLL_INTERVAL;
+    while !stop.load(Ordering::SeqCst) {
+        match accept() {
+            Ok(stream) => {
+                backoff = ACCEPT_POLL_INTERVAL;
+                // Some platforms pass nonblocking on to accepted
+                // streams, but handlers expect blocking reads
+                if let Err(e) = stream.set_nonblocking(false) {
+                    (pool.logger)(LogLevel::Warn, &format!("Error setting up connection: {e}"));
+                    continue;
+                }
+                // Not worth dropping the connection over
+                if options.nodelay {
+                    if let Err(e) = stream.set_nodelay(true) {
+                        (pool.logger)(LogLevel::Warn, &format!("Error setting TCP_NODELAY: {e}"));
+                    }
+                }
+                let permit = match &limit {
+                    Some(limit) => match limit.acquire(CONNECTION_WAIT) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            refuse(stream);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                // Each job gets its own handle on the handler
+                let connect = Arc::clone(connect);
+                pool.execute(move || {
+                    connect(stream);
+                    // Free the slot once the connection's done
+                    drop(permit);
+                });
+            }
+            // Nothing waiting yet
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            // One failed connection shouldn't bring the server
+            // down, but retrying at once would just fail again
+            Err(e) => {
+                (pool.logger)(
+                    LogLevel::Warn,
+                    &format!("Error accepting connection: {e}; retrying in {backoff:?}."),
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+// Tell a client the server is too busy, without letting a slow
+// client hold up the accept loop
+fn refuse(mut stream: impl Accepted) {
+    let _ = stream.set_write_timeout(Some(CONNECTION_WAIT));
+    let _ = stream.write_all(RESPONSE_503);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{
+        io::Read,
+        sync::{atomic::AtomicUsize, mpsc},
+        time::Instant,
+    };
+
+    #[test]
+    fn accept_errors_back_off() {
+        let pool = ThreadPool::with_logger(1, Arc::new(|_, _| ()));
+        let attempts = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                accept_until_stopped(
+                    || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        Err::<TcpStream, _>(io::Error::other("too many open files"))
+                    },
+                    &pool,
+                    &stop,
+                    &ServeOptions::new(),
+                    &Arc::new(|_: TcpStream| ()),
+                    &None,
+                );
+            });
+            thread::sleep(Duration::from_millis(200));
+            stop.store(true, Ordering::SeqCst);
+        });
+        // 10ms, 20ms, 40ms, 80ms...
+        let attempts = attempts.load(Ordering::SeqCst);
+        assert!((2..=6).contains(&attempts), "{attempts} attempts");
+    }
+
+    #[test]
+    fn serve_listener_handles() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler: ConnectionHandler = Arc::new(move |stream| {
+            tx.send(stream.peer()).unwrap();
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            serve_listener(
+                listener,
+                ThreadPool::new(2),
+                handler,
+                server_stop,
+                ServeOptions::new(),
+            )
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            client.local_addr().unwrap().to_string(),
+            rx.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serve_listener_stops() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handler: ConnectionHandler = Arc::new(|_| ());
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            serve_listener(
+                listener,
+                ThreadPool::new(2),
+                handler,
+                server_stop,
+                ServeOptions::new(),
+            )
+        });
+
+        stop.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        while !server.is_finished() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "server didn't stop"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serve_bind_error() {
+        let handler: ConnectionHandler = Arc::new(|_| ());
+        let stop = Arc::new(AtomicBool::new(true));
+        let e = serve(
+            "not an address",
+            ThreadPool::new(1),
+            handler,
+            stop,
+            ServeOptions::new(),
+        )
+        .unwrap_err();
+        let ServerError::Io(e) = e;
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn serve_listeners_all_serve() {
+        let listeners: Vec<_> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let handler: ConnectionHandler = Arc::new(move |mut stream| {
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&request).unwrap();
+            tx.lock().unwrap().send(()).unwrap();
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            serve_listeners(
+                listeners,
+                ThreadPool::new(2),
+                handler,
+                server_stop,
+                ServeOptions::new(),
+            )
+        });
+
+        for addr in addrs {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"ping").unwrap();
+            let mut response = [0; 4];
+            client.read_exact(&mut response).unwrap();
+            assert_eq!(b"ping", &response);
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serve_many_some_bind_errors() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logged = Arc::clone(&messages);
+        let pool = ThreadPool::with_logger(
+            1,
+            Arc::new(move |_, message: &str| logged.lock().unwrap().push(message.to_string())),
+        );
+        let handler: ConnectionHandler = Arc::new(|_| ());
+        let stop = Arc::new(AtomicBool::new(true));
+        serve_many(
+            &["127.0.0.1:0", "not an address"],
+            pool,
+            handler,
+            stop,
+            ServeOptions::new(),
+        )
+        .unwrap();
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.starts_with("Error binding not an address: ")));
+    }
+
+    #[test]
+    fn serve_many_no_addresses_bound() {
+        let handler: ConnectionHandler = Arc::new(|_| ());
+        let stop = Arc::new(AtomicBool::new(true));
+        let e = serve_many(
+            &["not an address", "nor this"],
+            ThreadPool::new(1),
+            handler,
+            stop,
+            ServeOptions::new(),
+        )
+        .unwrap_err();
+        let ServerError::Io(e) = e;
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn bind_reusable_rebind() {
+        let listener = bind_reusable("127.0.0.1:0", 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Closing the server's end first leaves the connection
+        // in TIME_WAIT on the server's address
+        let client = TcpStream::connect(addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        drop(server);
+        drop(client);
+        drop(listener);
+
+        let listener = bind_reusable(&addr.to_string(), 16).unwrap();
+        assert_eq!(addr, listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn bind_reusable_error() {
+        let e = bind_reusable("not an address", 16).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn serve_listener_connection_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let handler: ConnectionHandler = Arc::new(move |_| {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.lock().unwrap().recv();
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let options = ServeOptions::new().max_connections(1);
+        let server = thread::spawn(move || {
+            serve_listener(listener, ThreadPool::new(2), handler, server_stop, options)
+        });
+
+        // The first connection takes the only slot...
+        let _first = TcpStream::connect(addr).unwrap();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // ...so the second is turned away
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+
+        drop(release_tx);
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+
+    // Accept one connection with the given options and return
+    // whether TCP_NODELAY was set on the server's end
+    fn accepted_nodelay(options: ServeOptions) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            accept_loop(
+                listener,
+                ThreadPool::new(1),
+                server_stop,
+                options,
+                move |stream| {
+                    tx.lock().unwrap().send(stream.nodelay().unwrap()).unwrap();
+                },
+            )
+        });
+
+        let _client = TcpStream::connect(addr).unwrap();
+        let nodelay = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+        nodelay
+    }
+
+    #[test]
+    fn accept_loop_nodelay() {
+        assert!(accepted_nodelay(ServeOptions::new()));
+        assert!(!accepted_nodelay(ServeOptions::new().nodelay(false)));
+    }
+
+    #[test]
+    fn connection_limit_released() {
+        let limit = Arc::new(ConnectionLimit::new(1));
+        let permit = limit.acquire(Duration::ZERO).unwrap();
+        assert!(limit.acquire(Duration::ZERO).is_none());
+        drop(permit);
+        assert!(limit.acquire(Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn response_503_length() {
+        let response = std::str::from_utf8(RESPONSE_503).unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+    }
+}
@@ -0,0 +1,159 @@
+//! Logic run around handlers, such as auth checks, logging or timing.
+
+use std::sync::Arc;
+
+use super::{request::Request, response::Response};
+
+/// Something run before and after the [Handler](super::Handler) or
+/// [BodyHandler](super::BodyHandler) answering a request.
+///
+/// Add one to a [Router](super::router::Router) with
+/// [Router::add_middleware](super::router::Router::add_middleware). Each request
+/// answered by a handler first goes through every middleware's `before`, in the order
+/// they were added. If one returns a response, that's sent instead, and neither the
+/// handler nor any later middleware sees the request. The response then goes through
+/// `after` in the opposite order, for each middleware whose `before` let the request
+/// through.
+///
+/// Both methods do nothing by default, so only the one needed has to be written.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::server::{request::Request, Middleware, Response};
+///
+/// // Refuses requests without an API key
+/// struct RequireKey;
+/// impl Middleware for RequireKey {
+///     fn before(&self, request: &Request) -> Option<Response> {
+///         match request.headers.get("X-Api-Key") {
+///             Some(_) => None,
+///             None => Some(Response::new(401)),
+///         }
+///     }
+/// }
+/// ```
+pub trait Middleware: Send + Sync {
+    /// Look at a request before it's handled, returning a response to send it instead of
+    /// handling it, or `None` to let it through.
+    fn before(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// Change the response to a request before it's sent.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+// Answer request with handler, going through each middleware
+// in chain on the way in and back out
+pub(super) fn run(
+    chain: &[Arc<dyn Middleware>],
+    request: &Request,
+    handler: impl FnOnce(&Request) -> Response,
+) -> Response {
+    let mut passed = 0;
+    let mut response = None;
+    for middleware in chain {
+        response = middleware.before(request);
+        if response.is_some() {
+            break;
+        }
+        passed += 1;
+    }
+    let mut response = response.unwrap_or_else(|| handler(request));
+    for middleware in chain[..passed].iter().rev() {
+        middleware.after(request, &mut response);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::read_request;
+    use pretty_assertions::assert_eq;
+    use std::sync::Mutex;
+
+    // Records the order it's called in, optionally refusing
+    // every request
+    struct Tracer {
+        name: &'static str,
+        refuse: bool,
+        calls: Calls,
+    }
+    impl Middleware for Tracer {
+        fn before(&self, _request: &Request) -> Option<Response> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            self.refuse.then(|| Response::new(403))
+        }
+
+        fn after(&self, _request: &Request, response: &mut Response) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+            response
+                .headers
+                .push((String::from("X-Seen-By"), self.name.into()));
+        }
+    }
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    fn chain(refusing: Option<&'static str>) -> (Vec<Arc<dyn Middleware>>, Calls) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let chain = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                Arc::new(Tracer {
+                    name,
+                    refuse: refusing == Some(name),
+                    calls: Arc::clone(&calls),
+                }) as Arc<dyn Middleware>
+            })
+            .collect();
+        (chain, calls)
+    }
+
+    fn request() -> Request {
+        read_request(
+            &mut "GET / HTTP/1.1\r\n\r\n".as_bytes(),
+            &Default::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn run_in_order() {
+        let (chain, calls) = chain(None);
+        let response = run(&chain, &request(), |_: &Request| Response::ok());
+        assert_eq!(200, response.status);
+        assert_eq!(
+            vec!["a before", "b before", "c before", "c after", "b after", "a after"],
+            *calls.lock().unwrap()
+        );
+        let seen: Vec<&str> = response.headers.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vec!["c", "b", "a"], seen);
+    }
+
+    #[test]
+    fn run_short_circuit() {
+        let (chain, calls) = chain(Some("b"));
+        let handler = |_: &Request| -> Response { panic!("handler ran") };
+        let response = run(&chain, &request(), handler);
+        assert_eq!(403, response.status);
+        assert_eq!(
+            vec!["a before", "b before", "a after"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn run_empty_chain() {
+        let response = run(&[], &request(), |_: &Request| Response::new(204));
+        assert_eq!(204, response.status);
+    }
+}
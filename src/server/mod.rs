@@ -0,0 +1,48 @@
+//! The HTTP server built on top of the ThreadPool.
+//!
+//! Only available with the `server` feature, which is on by default. Serving HTTPS
+//! needs the `tls` feature too.
+
+mod auth;
+mod body;
+mod cache;
+mod connection;
+mod cors;
+mod date;
+mod encoding;
+mod error;
+mod handler;
+mod listen;
+mod middleware;
+mod range;
+pub mod request;
+mod response;
+pub mod router;
+mod static_files;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
+
+pub use auth::parse_basic_auth;
+pub use body::BodyReader;
+pub use cache::FileCache;
+pub use connection::{
+    default_router, handle_connection, handle_connection_with, health_check, prometheus_metrics,
+};
+pub use cors::Cors;
+pub use date::{http_date, parse_http_date};
+pub use error::ServerError;
+pub use handler::{BodyHandler, Handler};
+pub use listen::{
+    bind_reusable, serve, serve_listener, serve_listeners, serve_many, ConnectionHandler,
+    ServeOptions,
+};
+pub use middleware::Middleware;
+pub use response::Response;
+pub use stream::Stream;
+#[cfg(feature = "tls")]
+pub use tls::{serve_tls, serve_tls_listener};
+#[cfg(unix)]
+pub use unix::serve_unix;
@@ -0,0 +1,129 @@
+//! Range requests, for sending part of a file.
+
+/// The part of a file asked for in a Range header
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The bytes from `start` to `end`, inclusive, which both lie within the file
+    Satisfiable {
+        /// The first byte to send
+        start: u64,
+        /// The last byte to send
+        end: u64,
+    },
+    /// None of the range lies within the file
+    Unsatisfiable,
+}
+
+/// Work out which bytes of a file `length` bytes long a Range header asks for.
+///
+/// Understands a single range of bytes: `bytes=0-499`, the open-ended `bytes=500-` and
+/// the last 500 bytes, `bytes=-500`. A range running past the end of the file is cut
+/// short. Returns `None` for anything else, including lists of ranges, in which case
+/// the header should be ignored and the whole file sent.
+pub fn parse_range(header: &str, length: u64) -> Option<ByteRange> {
+    let (unit, range) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || range.contains(',') {
+        return None;
+    }
+    let (first, last) = range.trim().split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // A suffix: the last so many bytes
+        let suffix = number(last)?;
+        if suffix == 0 || length == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable {
+            start: length.saturating_sub(suffix),
+            end: length - 1,
+        });
+    }
+
+    let start = number(first)?;
+    let end = match last {
+        "" => u64::MAX,
+        last => number(last)?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= length {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable {
+        start,
+        end: end.min(length - 1),
+    })
+}
+
+// Parse a number made only of digits. parse() alone would
+// accept a sign.
+pub(super) fn number(digits: &str) -> Option<u64> {
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn satisfiable(start: u64, end: u64) -> Option<ByteRange> {
+        Some(ByteRange::Satisfiable { start, end })
+    }
+
+    #[test]
+    fn parse_range_bounded() {
+        assert_eq!(satisfiable(0, 499), parse_range("bytes=0-499", 1000));
+        assert_eq!(satisfiable(10, 10), parse_range("bytes=10-10", 1000));
+        // Cut short at the end of the file
+        assert_eq!(satisfiable(900, 999), parse_range("bytes=900-5000", 1000));
+    }
+
+    #[test]
+    fn parse_range_open_ended() {
+        assert_eq!(satisfiable(500, 999), parse_range("bytes=500-", 1000));
+        assert_eq!(satisfiable(999, 999), parse_range("Bytes = 999-", 1000));
+    }
+
+    #[test]
+    fn parse_range_suffix() {
+        assert_eq!(satisfiable(500, 999), parse_range("bytes=-500", 1000));
+        assert_eq!(satisfiable(0, 999), parse_range("bytes=-5000", 1000));
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert_eq!(
+            Some(ByteRange::Unsatisfiable),
+            parse_range("bytes=1000-", 1000)
+        );
+        assert_eq!(
+            Some(ByteRange::Unsatisfiable),
+            parse_range("bytes=2000-3000", 1000)
+        );
+        assert_eq!(
+            Some(ByteRange::Unsatisfiable),
+            parse_range("bytes=-0", 1000)
+        );
+        assert_eq!(Some(ByteRange::Unsatisfiable), parse_range("bytes=-5", 0));
+    }
+
+    #[test]
+    fn parse_range_ignored() {
+        for header in [
+            "bytes=0-1,5-9",
+            "items=0-5",
+            "bytes=5-1",
+            "bytes=-",
+            "bytes=+1-5",
+            "bytes=a-b",
+            "0-5",
+        ] {
+            assert_eq!(None, parse_range(header, 1000), "{header}");
+        }
+    }
+}
@@ -0,0 +1,1237 @@
+//! Parsing of incoming HTTP requests.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read, Write},
+    time::{Duration, Instant},
+};
+
+use super::body::{framing, BodyReader, Framing};
+
+/// The HTTP versions the server understands
+const VERSIONS: [&str; 2] = ["HTTP/1.0", "HTTP/1.1"];
+
+// Sent to clients waiting for the go-ahead to send a body
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// An error thrown when a request can't be parsed
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The request line didn't have exactly three parts
+    WrongPartCount(usize),
+    /// The request line's HTTP version isn't supported
+    UnknownVersion(String),
+    /// The request line's path didn't decode to UTF-8
+    InvalidPath(String),
+    /// A header line wasn't of the form `Name: value`
+    MalformedHeader(String),
+    /// The Content-Length header wasn't a number
+    InvalidContentLength(String),
+    /// The Content-Length header was over the limit, which is given
+    BodyTooLarge(usize),
+    /// The request line was longer than the limit, which is given
+    RequestLineTooLong(usize),
+    /// The header lines added up to more than the limit, which is given
+    HeadersTooLarge(usize),
+    /// A line ended in a bare `\n` rather than `\r\n`
+    BareLineFeed,
+    /// A line held a control character other than a tab, e.g. NUL
+    ControlCharacter(String),
+    /// A header line started with whitespace, continuing the one before it with the
+    /// obsolete line folding
+    FoldedHeader(String),
+    /// The Transfer-Encoding header named something other than `chunked`
+    UnsupportedTransferEncoding(String),
+    /// Both Content-Length and Transfer-Encoding headers were sent
+    ConflictingLength,
+    /// Content-Length headers with different values were sent
+    DuplicateContentLength,
+    /// More than one Transfer-Encoding header was sent
+    DuplicateTransferEncoding,
+    /// A chunk of a chunked body was malformed. Holds the offending line.
+    MalformedChunk(String),
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::WrongPartCount(count) => write!(
+                f,
+                "Error parsing request line: Expected 3 parts, found {count}"
+            ),
+            ParseError::UnknownVersion(version) => write!(
+                f,
+                "Error parsing request line: Unknown HTTP version {version}"
+            ),
+            ParseError::InvalidPath(path) => {
+                write!(f, "Error parsing request line: Invalid path {path:?}")
+            }
+            ParseError::MalformedHeader(line) => {
+                write!(f, "Error parsing header: Malformed line {line:?}")
+            }
+            ParseError::InvalidContentLength(value) => {
+                write!(f, "Error parsing header: Invalid Content-Length {value:?}")
+            }
+            ParseError::BodyTooLarge(max) => {
+                write!(
+                    f,
+                    "Error reading body: Longer than the maximum of {max} bytes"
+                )
+            }
+            ParseError::RequestLineTooLong(max) => write!(
+                f,
+                "Error reading request line: Longer than the maximum of {max} bytes"
+            ),
+            ParseError::HeadersTooLarge(max) => write!(
+                f,
+                "Error reading headers: Longer than the maximum of {max} bytes"
+            ),
+            ParseError::BareLineFeed => {
+                write!(f, "Error reading request: Line ended without a CR")
+            }
+            ParseError::ControlCharacter(line) => {
+                write!(
+                    f,
+                    "Error reading request: Control character in line {line:?}"
+                )
+            }
+            ParseError::FoldedHeader(line) => {
+                write!(f, "Error parsing header: Folded line {line:?}")
+            }
+            ParseError::UnsupportedTransferEncoding(value) => write!(
+                f,
+                "Error parsing header: Unsupported Transfer-Encoding {value:?}"
+            ),
+            ParseError::ConflictingLength => write!(
+                f,
+                "Error parsing header: Both Content-Length and Transfer-Encoding given"
+            ),
+            ParseError::DuplicateContentLength => write!(
+                f,
+                "Error parsing header: Content-Length given with different values"
+            ),
+            ParseError::DuplicateTransferEncoding => write!(
+                f,
+                "Error parsing header: Transfer-Encoding given more than once"
+            ),
+            ParseError::MalformedChunk(line) => {
+                write!(f, "Error reading body: Malformed chunk {line:?}")
+            }
+        }
+    }
+}
+impl Error for ParseError {}
+
+/// The header fields of a request, in the order they were received
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+impl Headers {
+    /// Create a new, empty set of headers.
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Add a header, keeping any others with the same name.
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((String::from(name), String::from(value)));
+    }
+
+    /// Get the value of the first header with the given name, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The values of every header with the given name, ignoring
+    // case, in the order they were received
+    pub(super) fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The first line of an HTTP request, e.g. `GET / HTTP/1.1`
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestLine {
+    /// The request method, e.g. `GET`
+    pub method: String,
+    /// The requested path, e.g. `/index.html`
+    pub path: String,
+    /// The HTTP version, e.g. `HTTP/1.1`
+    pub version: String,
+}
+impl RequestLine {
+    /// Parse a request line, splitting it on whitespace.
+    ///
+    /// The path is normalized, so that however it was written, it's checked and looked
+    /// up the same way: percent-escapes are decoded, except `%25` and `%3F` which would
+    /// change its meaning, repeated slashes and `.` segments are dropped, and `..`
+    /// segments are resolved. The query string, if any, is left as it was.
+    ///
+    /// Returns [ParseError] if there aren't exactly three parts, the version isn't one
+    /// of `HTTP/1.0` or `HTTP/1.1`, or the path doesn't decode to UTF-8.
+    pub fn parse(line: &str) -> Result<RequestLine, ParseError> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [method, path, version] = parts[..] else {
+            return Err(ParseError::WrongPartCount(parts.len()));
+        };
+
+        if !VERSIONS.contains(&version) {
+            return Err(ParseError::UnknownVersion(String::from(version)));
+        }
+        let (bare, query) = match path.split_once('?') {
+            Some((bare, query)) => (bare, Some(query)),
+            None => (path, None),
+        };
+        let Some(mut normalized) = normalize_path(bare) else {
+            return Err(ParseError::InvalidPath(String::from(path)));
+        };
+        if let Some(query) = query {
+            normalized.push('?');
+            normalized.push_str(query);
+        }
+
+        Ok(RequestLine {
+            method: String::from(method),
+            path: normalized,
+            version: String::from(version),
+        })
+    }
+}
+
+/// The most of each part of a request [read_request] will read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// The longest request line, in bytes, including its line ending
+    pub max_request_line: usize,
+    /// The most bytes of header lines, including their line endings and the blank line
+    /// which ends them
+    pub max_headers: usize,
+    /// The longest body, in bytes
+    pub max_body_size: usize,
+    /// The slowest the request line and headers may be sent, or `None` to wait however
+    /// long they take
+    pub min_data_rate: Option<MinDataRate>,
+}
+impl Default for RequestLimits {
+    /// 8KiB for the request line, 16KiB for the headers and 1MiB for the body. The
+    /// request line and headers must come at least 100 bytes per 5 seconds.
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            min_data_rate: Some(MinDataRate {
+                bytes: 100,
+                per: Duration::from_secs(5),
+            }),
+        }
+    }
+}
+
+/// A minimum rate at which a client must send data, so it can't hold a connection
+/// open by trickling in a request a byte at a time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinDataRate {
+    /// How many bytes must arrive...
+    pub bytes: usize,
+    /// ...in this long, on average. The rate isn't checked until this long has passed.
+    pub per: Duration,
+}
+
+/// A parsed HTTP request
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    /// The first line of the request
+    pub line: RequestLine,
+    /// The header fields following the request line
+    pub headers: Headers,
+    /// The body, which is empty unless the request had a Content-Length or a chunked
+    /// Transfer-Encoding. Always empty for requests to a
+    /// [BodyHandler](super::BodyHandler), which reads the body itself.
+    pub body: Vec<u8>,
+}
+
+/// Read a request line, all the header lines up to the blank line which ends them, and
+/// the body if there's a Content-Length header or it's chunked.
+///
+/// Never reads more of any part of the request than `limits` allow, so a client can't
+/// make the server buffer an endless line. Returns an error of kind
+/// [io::ErrorKind::InvalidData] wrapping a [ParseError] if the request is malformed or
+/// over one of the limits, [io::ErrorKind::TimedOut] if the request line and headers
+/// arrive slower than the limits' [MinDataRate], and [io::ErrorKind::UnexpectedEof] if
+/// the connection closes before the request ends.
+pub fn read_request(reader: &mut impl BufRead, limits: &RequestLimits) -> io::Result<Request> {
+    let mut request = read_request_head(reader, limits)?;
+    read_request_body(reader, &mut request, limits)?;
+    Ok(request)
+}
+
+/// Like [read_request], but for a stream which can be written to as well, so a client
+/// which sends `Expect: 100-continue` can be told to go ahead and send the body.
+///
+/// The `HTTP/1.1 100 Continue` interim response is written once the request line and
+/// headers have been read and found to be within `limits`, before any of the body is
+/// read. Clients which don't ask, or speak HTTP/1.0, aren't sent one.
+pub fn read_request_continue(
+    stream: &mut (impl BufRead + Write),
+    limits: &RequestLimits,
+) -> io::Result<Request> {
+    let mut request = read_request_head(stream, limits)?;
+    read_request_body_continue(stream, &mut request, limits)?;
+    Ok(request)
+}
+
+/// Read a request line and all the header lines up to the blank line which ends them,
+/// leaving the body to be read.
+///
+/// The body can then be read all at once with [read_request_body], or as it arrives
+/// with a [BodyReader]. Returns the same errors as [read_request],
+/// including when the headers don't say how long the body is in a way the server
+/// understands.
+pub fn read_request_head(reader: &mut impl BufRead, limits: &RequestLimits) -> io::Result<Request> {
+    let reader = &mut Paced::new(reader, limits.min_data_rate.as_ref());
+    let line = read_line(reader, limits.max_request_line)?
+        .ok_or_else(|| invalid_data(ParseError::RequestLineTooLong(limits.max_request_line)))?;
+    let line = RequestLine::parse(&line.text).map_err(invalid_data)?;
+
+    let mut headers = Headers::new();
+    // What's left of the header limit
+    let mut budget = limits.max_headers;
+    loop {
+        let too_large = || invalid_data(ParseError::HeadersTooLarge(limits.max_headers));
+        let header = read_line(reader, budget)?.ok_or_else(too_large)?;
+        budget -= header.length;
+        if header.text.is_empty() {
+            break;
+        }
+        // Whether a folded line belongs to the header before it
+        // is exactly the kind of thing a proxy in front of the
+        // server might see differently, so refuse it
+        if header.text.starts_with([' ', '\t']) {
+            return Err(invalid_data(ParseError::FoldedHeader(header.text)));
+        }
+        // Nor can there be whitespace before the colon, for the
+        // same reason
+        let Some((name, value)) = header
+            .text
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty() && !name.ends_with([' ', '\t']))
+        else {
+            return Err(invalid_data(ParseError::MalformedHeader(header.text)));
+        };
+        headers.push(name.trim(), value.trim());
+    }
+
+    // Refused now rather than once the body's wanted, as
+    // there's no telling where the next request would start
+    framing(&headers).map_err(invalid_data)?;
+    Ok(Request {
+        line,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Read the body of a request whose head was read with [read_request_head] into
+/// [Request::body].
+///
+/// Returns an error of kind [io::ErrorKind::InvalidData] wrapping a [ParseError] if the
+/// body is malformed or longer than `limits` allow, and [io::ErrorKind::UnexpectedEof]
+/// if the connection closes before it ends.
+pub fn read_request_body(
+    reader: &mut impl BufRead,
+    request: &mut Request,
+    limits: &RequestLimits,
+) -> io::Result<()> {
+    check_body_size(&request.headers, limits)?;
+    buffer_body(reader, request, limits)
+}
+
+// Like read_request_body, telling a client which sent
+// "Expect: 100-continue" to go ahead once the body's length
+// has been found to be within limits
+pub(super) fn read_request_body_continue(
+    stream: &mut (impl BufRead + Write),
+    request: &mut Request,
+    limits: &RequestLimits,
+) -> io::Result<()> {
+    check_body_size(&request.headers, limits)?;
+    send_continue(stream, request)?;
+    buffer_body(stream, request, limits)
+}
+
+// Tell the client to go ahead and send the body, if it's
+// waiting to be asked
+pub(super) fn send_continue(stream: &mut impl Write, request: &Request) -> io::Result<()> {
+    // HTTP/1.0 has no interim responses
+    let expected = request.line.version == "HTTP/1.1"
+        && request
+            .headers
+            .get("Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+    if expected && framing(&request.headers) != Ok(Framing::Length(0)) {
+        stream.write_all(CONTINUE)?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+// Check the body's length against the limit before reading any
+// of it, so a client can't make the server reserve huge
+// amounts of memory just by asking. A chunked body's length
+// isn't known until it's read.
+fn check_body_size(headers: &Headers, limits: &RequestLimits) -> io::Result<()> {
+    match framing(headers).map_err(invalid_data)? {
+        Framing::Length(length) if length > limits.max_body_size as u64 => {
+            Err(invalid_data(ParseError::BodyTooLarge(limits.max_body_size)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Read the whole body into the request, up to the limit
+fn buffer_body(
+    reader: &mut impl BufRead,
+    request: &mut Request,
+    limits: &RequestLimits,
+) -> io::Result<()> {
+    let max = limits.max_body_size;
+    let mut body = Vec::new();
+    BodyReader::new(reader, &request.headers)?
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > max {
+        return Err(invalid_data(ParseError::BodyTooLarge(max)));
+    }
+    request.body = body;
+    Ok(())
+}
+
+/// Split a request path into the bare path and its query parameters.
+///
+/// `/search?q=foo&page=2` gives `/search` and `{"q": "foo", "page": "2"}`. Keys and
+/// values are percent-decoded, with `+` decoded as a space. A key without `=` gets an
+/// empty value, and if a key is repeated, its last value wins.
+pub fn parse_query(path: &str) -> (&str, HashMap<String, String>) {
+    let Some((path, query)) = path.split_once('?') else {
+        return (path, HashMap::new());
+    };
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    (path, params)
+}
+
+// Decode %XX escapes and + as a space. Malformed escapes are
+// left as they are.
+fn percent_decode(s: &str) -> String {
+    // Decoded bytes might not be valid UTF-8
+    String::from_utf8_lossy(&unescape(s, b"", true)).into_owned()
+}
+
+// Decode the %XX escapes in s, except those for the bytes in
+// keep, and + as a space if plus_is_space. Malformed escapes
+// are left as they are.
+fn unescape(s: &str, keep: &[u8], plus_is_space: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_is_space => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    // from_str_radix alone would accept a sign
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .filter(|byte| !keep.contains(byte));
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+// Put a request path into the one form which is checked
+// against protected areas and looked up, so "//admin",
+// "/./admin" and "/%61dmin" can't slip past a check for
+// "/admin". Escapes are decoded, empty and "." segments
+// dropped, and ".." segments resolved, never going above the
+// root. "%25" and "%3F" stay escaped, so the query still starts
+// at the first "?" and normalizing twice changes nothing.
+// Returns None if the path doesn't decode to UTF-8. Anything
+// not starting with "/", e.g. "*", is left alone.
+fn normalize_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return Some(String::from(path));
+    }
+    let decoded = String::from_utf8(unescape(path, b"%?", false)).ok()?;
+    let mut segments = Vec::new();
+    // Whether the path names a directory
+    let mut trailing_slash = false;
+    for segment in decoded.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized: String = segments.iter().flat_map(|segment| ["/", segment]).collect();
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+pub(super) fn invalid_data(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Reads through to another reader, failing once the data has
+// come in slower than a minimum rate
+struct Paced<'a, R> {
+    inner: R,
+    min_rate: Option<&'a MinDataRate>,
+    // When the first byte arrived. A client may take its time
+    // to start, as the read timeout already covers that.
+    start: Option<Instant>,
+    // Bytes read since start
+    read: usize,
+}
+impl<'a, R: BufRead> Paced<'a, R> {
+    fn new(inner: R, min_rate: Option<&'a MinDataRate>) -> Paced<'a, R> {
+        Paced {
+            inner,
+            min_rate,
+            start: None,
+            read: 0,
+        }
+    }
+
+    // Fail if less has been read than the rate demands by now.
+    // Until a whole period has passed, any rate is fine.
+    fn check_rate(&self) -> io::Result<()> {
+        let (Some(min_rate), Some(start)) = (self.min_rate, self.start) else {
+            return Ok(());
+        };
+        let elapsed = start.elapsed();
+        if elapsed < min_rate.per {
+            return Ok(());
+        }
+        let expected = min_rate.bytes as f64 * elapsed.as_secs_f64() / min_rate.per.as_secs_f64();
+        if (self.read as f64) < expected {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "request sent too slowly: {} bytes in {elapsed:?}, below {} bytes per {:?}",
+                    self.read, min_rate.bytes, min_rate.per
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+impl<R: BufRead> Read for Paced<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_rate()?;
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.start.get_or_insert_with(Instant::now);
+        }
+        self.read += n;
+        Ok(n)
+    }
+}
+impl<R: BufRead> BufRead for Paced<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.check_rate()?;
+        let buf = self.inner.fill_buf()?;
+        if !buf.is_empty() {
+            self.start.get_or_insert_with(Instant::now);
+        }
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read += amt;
+        self.inner.consume(amt)
+    }
+}
+
+/// A line read by [read_line]
+pub(super) struct Line {
+    /// The line, without its line ending
+    pub(super) text: String,
+    /// How many bytes were read, including the line ending
+    pub(super) length: usize,
+}
+
+// Read one line of at most max bytes, including its CRLF line
+// ending. Returns None if the line is longer, having read no
+// more than max + 1 bytes of it.
+pub(super) fn read_line(reader: &mut impl BufRead, max: usize) -> io::Result<Option<Line>> {
+    let mut line = Vec::new();
+    // take() stops read_until from growing the line without end
+    let length = reader
+        .by_ref()
+        .take(max as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if length == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed mid-request",
+        ));
+    }
+    if length > max {
+        return Ok(None);
+    }
+    // Every line must end in CRLF. Accepting a bare LF too
+    // would let a request be split into lines differently here
+    // than by a proxy in front of the server.
+    if !line.ends_with(b"\r\n") {
+        if line.ends_with(b"\n") {
+            return Err(invalid_data(ParseError::BareLineFeed));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed mid-line",
+        ));
+    }
+    line.truncate(line.len() - 2);
+    if line.iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+        let line = String::from_utf8_lossy(&line).into_owned();
+        return Err(invalid_data(ParseError::ControlCharacter(line)));
+    }
+    let text = String::from_utf8(line).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )
+    })?;
+    Ok(Some(Line { text, length }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    const MAX_BODY_SIZE: usize = 1024;
+    const LIMITS: RequestLimits = RequestLimits {
+        max_request_line: 64,
+        max_headers: 128,
+        max_body_size: MAX_BODY_SIZE,
+        min_data_rate: None,
+    };
+
+    fn request_line(method: &str, path: &str, version: &str) -> RequestLine {
+        RequestLine {
+            method: String::from(method),
+            path: String::from(path),
+            version: String::from(version),
+        }
+    }
+
+    #[test]
+    fn parse_ok() {
+        assert_eq!(
+            Ok(request_line("GET", "/", "HTTP/1.1")),
+            RequestLine::parse("GET / HTTP/1.1")
+        );
+        assert_eq!(
+            Ok(request_line("POST", "/form", "HTTP/1.0")),
+            RequestLine::parse("POST /form HTTP/1.0")
+        );
+    }
+
+    #[test]
+    fn parse_extra_whitespace() {
+        assert_eq!(
+            Ok(request_line("GET", "/sleep", "HTTP/1.1")),
+            RequestLine::parse("  GET   /sleep\tHTTP/1.1 ")
+        );
+    }
+
+    #[test]
+    fn parse_missing_parts() {
+        assert_eq!(Err(ParseError::WrongPartCount(0)), RequestLine::parse(""));
+        assert_eq!(
+            Err(ParseError::WrongPartCount(1)),
+            RequestLine::parse("GET")
+        );
+        assert_eq!(
+            Err(ParseError::WrongPartCount(2)),
+            RequestLine::parse("GET /")
+        );
+    }
+
+    #[test]
+    fn parse_too_many_parts() {
+        assert_eq!(
+            Err(ParseError::WrongPartCount(4)),
+            RequestLine::parse("GET / HTTP/1.1 extra")
+        );
+    }
+
+    #[test]
+    fn parse_normalizes_path() {
+        for (path, normalized) in [
+            ("//admin/users", "/admin/users"),
+            ("/./admin/./users", "/admin/users"),
+            ("/public/../admin/", "/admin/"),
+            ("/../../admin", "/admin"),
+            ("/admin/.", "/admin/"),
+            ("/admin/users/..", "/admin/"),
+            ("/%61dmin%2Fusers", "/admin/users"),
+            ("/a%20b+c", "/a b+c"),
+            ("/100%25?q=%2F", "/100%25?q=%2F"),
+            ("/what%3F?q=1", "/what%3F?q=1"),
+            ("/%zz", "/%zz"),
+            ("*", "*"),
+        ] {
+            assert_eq!(
+                Ok(request_line("GET", normalized, "HTTP/1.1")),
+                RequestLine::parse(&format!("GET {path} HTTP/1.1")),
+                "{path}"
+            );
+            // Already as normal as it gets
+            let (bare, _) = normalized.split_once('?').unwrap_or((normalized, ""));
+            assert_eq!(Some(String::from(bare)), normalize_path(bare));
+        }
+    }
+
+    #[test]
+    fn parse_invalid_path() {
+        assert_eq!(
+            Err(ParseError::InvalidPath(String::from("/%ff"))),
+            RequestLine::parse("GET /%ff HTTP/1.1")
+        );
+    }
+
+    #[test]
+    fn parse_unknown_version() {
+        assert_eq!(
+            Err(ParseError::UnknownVersion(String::from("HTTP/9"))),
+            RequestLine::parse("GET / HTTP/9")
+        );
+    }
+
+    #[test]
+    fn read_request_headers() {
+        let mut reader = Cursor::new(
+            "GET /index.html HTTP/1.1\r\n\
+             Host: localhost:7878\r\n\
+             User-Agent: test\r\n\
+             Accept:text/html\r\n\
+             \r\n",
+        );
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+
+        assert_eq!(request_line("GET", "/index.html", "HTTP/1.1"), request.line);
+        let mut expected = Headers::new();
+        expected.push("Host", "localhost:7878");
+        expected.push("User-Agent", "test");
+        expected.push("Accept", "text/html");
+        assert_eq!(expected, request.headers);
+    }
+
+    #[test]
+    fn read_request_leaves_rest() {
+        let mut reader = Cursor::new("GET / HTTP/1.1\r\nHost: a\r\n\r\nnext");
+        read_request(&mut reader, &LIMITS).unwrap();
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!("next", rest);
+    }
+
+    #[test]
+    fn read_request_no_headers() {
+        let mut reader = Cursor::new("GET / HTTP/1.1\r\n\r\n");
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+        assert_eq!(Headers::new(), request.headers);
+    }
+
+    #[test]
+    fn read_request_malformed_header() {
+        let mut reader = Cursor::new("GET / HTTP/1.1\r\nnot a header\r\n\r\n");
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    // The ParseError a request is rejected with
+    fn rejection(request: &[u8]) -> ParseError {
+        let e = read_request(&mut Cursor::new(request), &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        *e.into_inner().unwrap().downcast().unwrap()
+    }
+
+    #[test]
+    fn read_request_bare_lf() {
+        assert_eq!(ParseError::BareLineFeed, rejection(b"GET / HTTP/1.1\n\r\n"));
+        assert_eq!(
+            ParseError::BareLineFeed,
+            rejection(b"GET / HTTP/1.1\r\nHost: a\n\r\n")
+        );
+        assert_eq!(
+            ParseError::BareLineFeed,
+            rejection(b"GET / HTTP/1.1\r\nHost: a\r\n\n")
+        );
+    }
+
+    #[test]
+    fn read_request_control_characters() {
+        assert_eq!(
+            ParseError::ControlCharacter(String::from("Host: a\0b")),
+            rejection(b"GET / HTTP/1.1\r\nHost: a\0b\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::ControlCharacter(String::from("X-Smuggled: a\rContent-Length: 5")),
+            rejection(b"GET / HTTP/1.1\r\nX-Smuggled: a\rContent-Length: 5\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::ControlCharacter(String::from("GET /\x7f HTTP/1.1")),
+            rejection(b"GET /\x7f HTTP/1.1\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn read_request_folded_header() {
+        assert_eq!(
+            ParseError::FoldedHeader(String::from(" text/html")),
+            rejection(b"GET / HTTP/1.1\r\nAccept: text/plain,\r\n text/html\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::FoldedHeader(String::from("\tb")),
+            rejection(b"GET / HTTP/1.1\r\nX-A: a\r\n\tb\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn read_request_space_before_colon() {
+        assert_eq!(
+            ParseError::MalformedHeader(String::from("Content-Length : 5")),
+            rejection(b"POST / HTTP/1.1\r\nContent-Length : 5\r\n\r\nhello")
+        );
+        assert_eq!(
+            ParseError::MalformedHeader(String::from(": a")),
+            rejection(b"GET / HTTP/1.1\r\n: a\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn read_request_well_formed() {
+        let mut reader = Cursor::new(
+            "POST /form HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             X-Tabbed:\ta\tb\r\n\
+             Content-Length: 2\r\n\
+             \r\n\
+             ok",
+        );
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+        assert_eq!(Some("a\tb"), request.headers.get("X-Tabbed"));
+        assert_eq!(b"ok", &request.body[..]);
+    }
+
+    #[test]
+    fn read_request_truncated() {
+        let mut reader = Cursor::new("GET / HTTP/1.1\r\nHost: a\r\n");
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn read_request_body() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, next");
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+        assert_eq!(b"hello", &request.body[..]);
+
+        // Only the declared length is read
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!(", next", rest);
+    }
+
+    #[test]
+    fn read_request_no_length() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\n\r\nignored");
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn read_request_body_too_large() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\nContent-Length: 1025\r\n\r\n");
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert_eq!(
+            Some(&ParseError::BodyTooLarge(MAX_BODY_SIZE)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+    }
+
+    #[test]
+    fn read_request_invalid_length() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n");
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    #[test]
+    fn read_request_chunked() {
+        let mut reader = Cursor::new(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nnext",
+        );
+        let request = read_request(&mut reader, &LIMITS).unwrap();
+        assert_eq!(b"hello", &request.body[..]);
+
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!("next", rest);
+    }
+
+    #[test]
+    fn read_request_chunked_too_large() {
+        // The length isn't known until the chunks are read
+        let chunk = "a".repeat(MAX_BODY_SIZE);
+        let request = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            {MAX_BODY_SIZE:x}\r\n{chunk}\r\n1\r\na\r\n0\r\n\r\n"
+        );
+        assert_eq!(
+            ParseError::BodyTooLarge(MAX_BODY_SIZE),
+            rejection(request.as_bytes())
+        );
+    }
+
+    #[test]
+    fn read_request_unsupported_framing() {
+        assert_eq!(
+            ParseError::UnsupportedTransferEncoding(String::from("gzip")),
+            rejection(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::ConflictingLength,
+            rejection(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n"
+            )
+        );
+        assert_eq!(
+            ParseError::DuplicateContentLength,
+            rejection(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!")
+        );
+        assert_eq!(
+            ParseError::InvalidContentLength(String::from("+5")),
+            rejection(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello")
+        );
+    }
+
+    #[test]
+    fn read_request_head_leaves_body() {
+        let mut reader = Cursor::new("PUT / HTTP/1.1\r\nContent-Length: 5000\r\n\r\nhello");
+        // Over the body limit, but it's up to the caller whether
+        // the body's read
+        let mut request = read_request_head(&mut reader, &LIMITS).unwrap();
+        assert!(request.body.is_empty());
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!("hello", rest);
+
+        let e = super::read_request_body(&mut reader, &mut request, &LIMITS).unwrap_err();
+        assert_eq!(
+            Some(&ParseError::BodyTooLarge(MAX_BODY_SIZE)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+    }
+
+    #[test]
+    fn read_request_body_truncated() {
+        let mut reader = Cursor::new("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi");
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+    }
+
+    #[test]
+    fn read_request_line_too_long() {
+        let mut reader = Cursor::new(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64)));
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(
+            Some(&ParseError::RequestLineTooLong(64)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+    }
+
+    #[test]
+    fn read_request_line_at_limit() {
+        // 64 bytes exactly, counting the line ending
+        let line = format!("GET /{} HTTP/1.1\r\n", "a".repeat(48));
+        assert_eq!(64, line.len());
+        let mut reader = Cursor::new(format!("{line}\r\n"));
+        assert!(read_request(&mut reader, &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn read_request_headers_too_large() {
+        // Each header fits, but not all of them together
+        let header = format!("X-Padding: {}\r\n", "a".repeat(40));
+        let mut reader = Cursor::new(format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(3)));
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert_eq!(
+            Some(&ParseError::HeadersTooLarge(128)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+    }
+
+    #[test]
+    fn read_request_endless_line() {
+        // A client which never ends its request line. Without
+        // the limit this would never return.
+        let mut reader = io::BufReader::new(io::repeat(b'a'));
+        let e = read_request(&mut reader, &RequestLimits::default()).unwrap_err();
+        assert_eq!(
+            Some(&ParseError::RequestLineTooLong(8 * 1024)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+        let mut reader = io::BufReader::new(io::Read::chain(
+            Cursor::new("GET / HTTP/1.1\r\nX-Endless: "),
+            io::repeat(b'a'),
+        ));
+        let e = read_request(&mut reader, &RequestLimits::default()).unwrap_err();
+        assert_eq!(
+            Some(&ParseError::HeadersTooLarge(16 * 1024)),
+            e.get_ref().and_then(|e| e.downcast_ref())
+        );
+    }
+
+    // Sends a request a byte at a time, pausing before each
+    struct Slow {
+        request: &'static [u8],
+        pause: Duration,
+    }
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&byte, rest)) = self.request.split_first() else {
+                return Ok(0);
+            };
+            std::thread::sleep(self.pause);
+            buf[0] = byte;
+            self.request = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn read_request_too_slow() {
+        let limits = RequestLimits {
+            min_data_rate: Some(MinDataRate {
+                bytes: 100,
+                per: Duration::from_millis(50),
+            }),
+            ..RequestLimits::default()
+        };
+        let mut reader = io::BufReader::new(Slow {
+            request: b"GET / HTTP/1.1\r\nHost: a\r\n\r\n",
+            pause: Duration::from_millis(10),
+        });
+        let e = read_request(&mut reader, &limits).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, e.kind());
+        // Given up on well before the whole request arrived
+        assert!(reader.into_inner().request.len() > 10);
+    }
+
+    #[test]
+    fn read_request_slow_start() {
+        // Only the rate after the first byte counts
+        let limits = RequestLimits {
+            min_data_rate: Some(MinDataRate {
+                bytes: 100,
+                per: Duration::from_millis(50),
+            }),
+            ..RequestLimits::default()
+        };
+        let mut reader = io::BufReader::new(io::Read::chain(
+            Slow {
+                request: b"G",
+                pause: Duration::from_millis(100),
+            },
+            Cursor::new("ET / HTTP/1.1\r\n\r\n"),
+        ));
+        assert!(read_request(&mut reader, &limits).is_ok());
+    }
+
+    // Records how much of the request had been read each time
+    // something is written
+    struct Watched {
+        reader: Cursor<&'static str>,
+        written: Vec<u8>,
+        read_at_write: Vec<u64>,
+    }
+    impl Watched {
+        fn new(request: &'static str) -> Watched {
+            Watched {
+                reader: Cursor::new(request),
+                written: Vec::new(),
+                read_at_write: Vec::new(),
+            }
+        }
+    }
+    impl Read for Watched {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reader.read(buf)
+        }
+    }
+    impl BufRead for Watched {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.reader.fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.reader.consume(amt)
+        }
+    }
+    impl Write for Watched {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.read_at_write.push(self.reader.position());
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_request_continue_before_body() {
+        let head = "PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n";
+        let mut stream = Watched::new(
+            "PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\nbody",
+        );
+        let request = read_request_continue(&mut stream, &RequestLimits::default()).unwrap();
+        assert_eq!(b"body".to_vec(), request.body);
+        assert_eq!(b"HTTP/1.1 100 Continue\r\n\r\n".to_vec(), stream.written);
+        // Written once the head was read, before the body
+        assert_eq!(vec![head.len() as u64], stream.read_at_write);
+    }
+
+    #[test]
+    fn read_request_continue_not_expected() {
+        for request in [
+            "PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody",
+            "PUT / HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\nbody",
+            "GET / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n",
+        ] {
+            let mut stream = Watched::new(request);
+            read_request_continue(&mut stream, &RequestLimits::default()).unwrap();
+            assert!(stream.written.is_empty(), "{request}");
+        }
+    }
+
+    #[test]
+    fn read_request_continue_too_large() {
+        // Refused before the client sends the body
+        let mut stream =
+            Watched::new("PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 99\r\n\r\n");
+        let limits = RequestLimits {
+            max_body_size: 10,
+            ..RequestLimits::default()
+        };
+        let e = read_request_continue(&mut stream, &limits).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert!(stream.written.is_empty());
+    }
+
+    #[test]
+    fn read_request_invalid_utf8() {
+        let mut reader = Cursor::new(b"GET /\xff HTTP/1.1\r\n\r\n".to_vec());
+        let e = read_request(&mut reader, &LIMITS).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    #[test]
+    fn headers_get() {
+        let mut headers = Headers::new();
+        headers.push("Content-Length", "5");
+        headers.push("Accept", "a");
+        headers.push("accept", "b");
+        assert_eq!(Some("5"), headers.get("content-length"));
+        assert_eq!(Some("a"), headers.get("ACCEPT"));
+        assert_eq!(None, headers.get("Host"));
+    }
+
+    #[test]
+    fn parse_error_display() {
+        assert_eq!(
+            "Error parsing request line: Expected 3 parts, found 1",
+            ParseError::WrongPartCount(1).to_string()
+        );
+    }
+
+    #[test]
+    fn parse_query_none() {
+        assert_eq!(("/search", HashMap::new()), parse_query("/search"));
+        assert_eq!(("/search", HashMap::new()), parse_query("/search?"));
+    }
+
+    #[test]
+    fn parse_query_params() {
+        let (path, params) = parse_query("/search?q=foo&page=2");
+        assert_eq!("/search", path);
+        assert_eq!(Some("foo"), params.get("q").map(String::as_str));
+        assert_eq!(Some("2"), params.get("page").map(String::as_str));
+    }
+
+    #[test]
+    fn parse_query_empty_values() {
+        let (_, params) = parse_query("/?a=&b&&c=1");
+        assert_eq!(3, params.len());
+        assert_eq!(Some(""), params.get("a").map(String::as_str));
+        assert_eq!(Some(""), params.get("b").map(String::as_str));
+    }
+
+    #[test]
+    fn parse_query_repeated_key() {
+        let (_, params) = parse_query("/?q=first&q=last");
+        assert_eq!(Some("last"), params.get("q").map(String::as_str));
+    }
+
+    #[test]
+    fn parse_query_encoded() {
+        let (_, params) = parse_query("/?q=hello%20big+world&a%26b=%3D");
+        assert_eq!(Some("hello big world"), params.get("q").map(String::as_str));
+        assert_eq!(Some("="), params.get("a&b").map(String::as_str));
+    }
+
+    #[test]
+    fn parse_query_malformed_escape() {
+        let (_, params) = parse_query("/?q=100%&r=%zz&s=%+1");
+        assert_eq!(Some("100%"), params.get("q").map(String::as_str));
+        assert_eq!(Some("%zz"), params.get("r").map(String::as_str));
+        assert_eq!(Some("% 1"), params.get("s").map(String::as_str));
+    }
+}
@@ -0,0 +1,220 @@
+//! HTTP responses built by handlers.
+
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use super::date::http_date;
+
+/// An HTTP response, ready to be written to a connection.
+///
+/// # Examples
+///
+/// ```
+/// use rust_tutorial_webserver::server::Response;
+/// let mut written = Vec::new();
+/// Response::ok()
+///     .header("Content-Type", "text/plain")
+///     .body("Hello!")
+///     .write_to(&mut written)
+///     .unwrap();
+/// assert!(written.starts_with(b"HTTP/1.1 200 OK\r\n"));
+/// assert!(written.ends_with(b"Content-Length: 6\r\n\r\nHello!"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// The status code, e.g. 200
+    pub status: u16,
+    /// Header fields sent after the status line, in order. `Date` and `Content-Length`
+    /// are added when the response is written.
+    pub headers: Vec<(String, String)>,
+    /// The body
+    pub body: Vec<u8>,
+}
+impl Response {
+    /// Create a response with the given status code, no headers and an empty body.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Create a `200 OK` response with no headers and an empty body.
+    pub fn ok() -> Response {
+        Response::new(200)
+    }
+
+    /// Add a header, keeping any others with the same name.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Set the body, replacing any set before.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Write the status line, a `Date` header, the other headers, a `Content-Length`
+    /// header and the body.
+    ///
+    /// Informational (1xx), `204 No Content` and `304 Not Modified` responses never have
+    /// a body, so get neither a body nor a `Content-Length`, whatever the body's set to.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        self.write(w, false)
+    }
+
+    // Like write_to, but if head_only is set the body is left
+    // out, for answering HEAD requests. Content-Length still
+    // gives the length of the body which would have been sent.
+    pub(super) fn write(&self, w: &mut impl Write, head_only: bool) -> io::Result<()> {
+        self.write_with(w, &self.body, head_only)
+    }
+
+    // Like write, but sending body in place of the response's
+    // own, so bytes shared with e.g. the FileCache don't have to
+    // be copied into it
+    pub(super) fn write_with(
+        &self,
+        w: &mut impl Write,
+        body: &[u8],
+        head_only: bool,
+    ) -> io::Result<()> {
+        let mut head = format!(
+            "{}\r\nDate: {}\r\n",
+            status_line(self.status),
+            http_date(SystemTime::now())
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let bodiless = matches!(self.status, 100..=199 | 204 | 304);
+        if !bodiless {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+
+        // write_all() takes &[u8] & sends those bytes directly
+        // down the connection
+        w.write_all(head.as_bytes())?;
+        if !head_only && !bodiless {
+            w.write_all(body)?;
+        }
+        w.flush()
+    }
+}
+
+// The status line for a status code, e.g. "HTTP/1.1 200 OK".
+// Reason phrases are upper case, like the rest of the server's.
+pub(super) fn status_line(status: u16) -> String {
+    let reason = match status {
+        100 => "CONTINUE",
+        200 => "OK",
+        201 => "CREATED",
+        204 => "NO CONTENT",
+        206 => "PARTIAL CONTENT",
+        301 => "MOVED PERMANENTLY",
+        302 => "FOUND",
+        304 => "NOT MODIFIED",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        403 => "FORBIDDEN",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        408 => "REQUEST TIMEOUT",
+        413 => "PAYLOAD TOO LARGE",
+        416 => "RANGE NOT SATISFIABLE",
+        431 => "REQUEST HEADER FIELDS TOO LARGE",
+        500 => "INTERNAL SERVER ERROR",
+        503 => "SERVICE UNAVAILABLE",
+        _ => "UNKNOWN",
+    };
+    format!("HTTP/1.1 {status} {reason}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // Write a response and split off its Date header, which
+    // changes from second to second
+    fn written(response: &Response) -> (String, String) {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        let (status_line, rest) = text.split_once("\r\n").unwrap();
+        let (date, rest) = rest.split_once("\r\n").unwrap();
+        (date.to_string(), format!("{status_line}\r\n{rest}"))
+    }
+
+    #[test]
+    fn write_to_ok() {
+        let response = Response::ok()
+            .header("Content-Type", "text/plain")
+            .header("X-Greeting", "hi")
+            .body("Hello!");
+        let (date, rest) = written(&response);
+        assert!(date.starts_with("Date: ") && date.ends_with(" GMT"));
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-Greeting: hi\r\nContent-Length: 6\r\n\r\nHello!",
+            rest
+        );
+    }
+
+    #[test]
+    fn write_to_not_found() {
+        let response = Response::new(404)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(b"<h1>404</h1>".to_vec());
+        let (_, rest) = written(&response);
+        assert_eq!(
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 12\r\n\r\n<h1>404</h1>",
+            rest
+        );
+    }
+
+    #[test]
+    fn write_to_not_modified() {
+        let (_, rest) = written(&Response::new(304).header("ETag", "\"a\""));
+        assert_eq!("HTTP/1.1 304 NOT MODIFIED\r\nETag: \"a\"\r\n\r\n", rest);
+    }
+
+    #[test]
+    fn write_to_no_content() {
+        let (_, rest) = written(&Response::new(204).body("ignored"));
+        assert_eq!("HTTP/1.1 204 NO CONTENT\r\n\r\n", rest);
+        let (_, rest) = written(&Response::new(100));
+        assert_eq!("HTTP/1.1 100 CONTINUE\r\n\r\n", rest);
+    }
+
+    #[test]
+    fn write_head_only() {
+        let mut bytes = Vec::new();
+        Response::ok()
+            .body("Hello!")
+            .write(&mut bytes, true)
+            .unwrap();
+        assert!(bytes.ends_with(b"Content-Length: 6\r\n\r\n"));
+    }
+
+    #[test]
+    fn write_with_body() {
+        let mut bytes = Vec::new();
+        Response::ok()
+            .body("ignored")
+            .write_with(&mut bytes, b"Hello!", false)
+            .unwrap();
+        assert!(bytes.ends_with(b"Content-Length: 6\r\n\r\nHello!"));
+    }
+
+    #[test]
+    fn body_replaces() {
+        let response = Response::ok().body("one").body("two");
+        assert_eq!(b"two".to_vec(), response.body);
+    }
+}
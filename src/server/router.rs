@@ -0,0 +1,489 @@
+//! Mapping of requests to the files or handlers which answer them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    auth::AuthArea,
+    cache::FileCache,
+    cors::Cors,
+    handler::{BodyHandler, Handler},
+    middleware::Middleware,
+    request::{Request, RequestLimits},
+    response::Response,
+};
+
+/// A function building the response for requests which no route matches, given to
+/// [Router::set_not_found]
+pub type NotFoundHandler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// How long a connection is kept alive for between requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// The most requests answered on one connection. The last response says it's
+    /// closing the connection with `Connection: close`.
+    pub max_requests: usize,
+    /// How long to wait for the next request to start before closing the connection
+    pub idle_timeout: Duration,
+}
+impl Default for KeepAlive {
+    /// 100 requests, with up to 5 seconds between them.
+    fn default() -> KeepAlive {
+        KeepAlive {
+            max_requests: 100,
+            idle_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What answers the requests for a route
+enum Route {
+    File(PathBuf),
+    Handler(Box<dyn Handler>),
+    BodyHandler(Box<dyn BodyHandler>),
+}
+// Handlers aren't Debug, so leave them out
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Route::File(file) => f.debug_tuple("File").field(file).finish(),
+            Route::Handler(_) => write!(f, "Handler(..)"),
+            Route::BodyHandler(_) => write!(f, "BodyHandler(..)"),
+        }
+    }
+}
+
+/// A table of routes, each mapping a method and path to a file, a [Handler] or a
+/// [BodyHandler].
+#[derive(Default)]
+pub struct Router {
+    // Keyed by path, then by method. Methods are kept sorted so
+    // they're always listed in the same order.
+    routes: HashMap<String, BTreeMap<String, Route>>,
+    not_found: Option<NotFoundHandler>,
+    forbid_unindexed: bool,
+    file_cache: Option<FileCache>,
+    // Run around handlers, in order
+    middleware: Vec<Arc<dyn Middleware>>,
+    auth_areas: Vec<AuthArea>,
+    keep_alive: KeepAlive,
+    request_limits: RequestLimits,
+    cors: Option<Cors>,
+}
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes)
+            .field("not_found", &self.not_found.as_ref().map(|_| ".."))
+            .field("forbid_unindexed", &self.forbid_unindexed)
+            .field("file_cache", &self.file_cache)
+            .field(
+                "middleware",
+                &format_args!("[..; {}]", self.middleware.len()),
+            )
+            .field("auth_areas", &self.auth_areas)
+            .field("keep_alive", &self.keep_alive)
+            .field("request_limits", &self.request_limits)
+            .field("cors", &self.cors)
+            .finish()
+    }
+}
+impl Router {
+    /// Create a new, empty Router.
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Serve `file` for requests with the given method and path.
+    ///
+    /// Replaces any file or handler previously registered for the same method and path.
+    pub fn insert(&mut self, method: &str, path: &str, file: &str) {
+        self.insert_route(method, path, Route::File(PathBuf::from(file)));
+    }
+
+    /// Answer requests with the given method and path with `handler`.
+    ///
+    /// Replaces any file or handler previously registered for the same method and path.
+    pub fn insert_handler(&mut self, method: &str, path: &str, handler: impl Handler + 'static) {
+        self.insert_route(method, path, Route::Handler(Box::new(handler)));
+    }
+
+    /// Answer requests with the given method and path with `handler`, which reads each
+    /// request's body itself as it arrives.
+    ///
+    /// Replaces any file or handler previously registered for the same method and path.
+    pub fn insert_body_handler(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: impl BodyHandler + 'static,
+    ) {
+        self.insert_route(method, path, Route::BodyHandler(Box::new(handler)));
+    }
+
+    fn insert_route(&mut self, method: &str, path: &str, route: Route) {
+        self.routes
+            .entry(String::from(path))
+            .or_default()
+            .insert(String::from(method), route);
+    }
+
+    /// Find the file registered for a method and path.
+    ///
+    /// Returns `None` if no route matches, or the route is answered by a handler.
+    pub fn resolve(&self, method: &str, path: &str) -> Option<&Path> {
+        match self.routes.get(path)?.get(method)? {
+            Route::File(file) => Some(file),
+            Route::Handler(_) | Route::BodyHandler(_) => None,
+        }
+    }
+
+    /// Find the handler registered for a method and path.
+    ///
+    /// Returns `None` if no route matches, or the route is answered by a file or a
+    /// [BodyHandler].
+    pub fn resolve_handler(&self, method: &str, path: &str) -> Option<&dyn Handler> {
+        match self.routes.get(path)?.get(method)? {
+            Route::Handler(handler) => Some(handler.as_ref()),
+            Route::File(_) | Route::BodyHandler(_) => None,
+        }
+    }
+
+    /// Find the body handler registered for a method and path.
+    ///
+    /// Returns `None` if no route matches, or the route is answered by a file or a
+    /// [Handler].
+    pub fn resolve_body_handler(&self, method: &str, path: &str) -> Option<&dyn BodyHandler> {
+        match self.routes.get(path)?.get(method)? {
+            Route::BodyHandler(handler) => Some(handler.as_ref()),
+            Route::File(_) | Route::Handler(_) => None,
+        }
+    }
+
+    /// Answer requests which no route matches with `handler`, instead of the server's
+    /// `404.html` page.
+    ///
+    /// Useful for not found pages which depend on the request. Replaces any handler set
+    /// before.
+    pub fn set_not_found(&mut self, handler: NotFoundHandler) {
+        self.not_found = Some(handler);
+    }
+
+    /// Get the handler set with [Router::set_not_found], if there is one.
+    pub fn not_found(&self) -> Option<&NotFoundHandler> {
+        self.not_found.as_ref()
+    }
+
+    /// Answer requests for a directory with no `index.html` with `403 Forbidden` if
+    /// `forbid` is set, rather than as not found.
+    ///
+    /// Off by default, so requests don't reveal which directories exist.
+    pub fn set_forbid_unindexed(&mut self, forbid: bool) {
+        self.forbid_unindexed = forbid;
+    }
+
+    /// Check whether requests for a directory with no `index.html` are forbidden, as set
+    /// with [Router::set_forbid_unindexed].
+    pub fn forbids_unindexed(&self) -> bool {
+        self.forbid_unindexed
+    }
+
+    /// Keep the contents of the files served in `cache`, rather than reading them from
+    /// disk for every request.
+    ///
+    /// Replaces any cache set before. Files big enough to be streamed in chunks are
+    /// always read from disk.
+    pub fn set_file_cache(&mut self, cache: FileCache) {
+        self.file_cache = Some(cache);
+    }
+
+    /// Get the cache set with [Router::set_file_cache], if there is one.
+    pub fn file_cache(&self) -> Option<&FileCache> {
+        self.file_cache.as_ref()
+    }
+
+    /// Run `middleware` around every handler, after any middleware added before.
+    ///
+    /// Only requests answered by a [Handler] go through middleware; files are served as
+    /// they are. See [Middleware] for the order it's run in.
+    pub fn add_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Get the middleware added with [Router::add_middleware], in the order it runs.
+    pub fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    /// Only answer requests for `path`, and anything under it, if they give `username`
+    /// and `password` with HTTP Basic authentication.
+    ///
+    /// Other requests get `401 Unauthorized`, asking for credentials for `realm`. Call
+    /// again with the same path to let more users in; the realm given first is kept. If
+    /// areas overlap, the one added first decides.
+    pub fn require_basic_auth(&mut self, path: &str, realm: &str, username: &str, password: &str) {
+        let credentials = (String::from(username), String::from(password));
+        match self.auth_areas.iter_mut().find(|area| area.path == path) {
+            Some(area) => area.credentials.push(credentials),
+            None => self.auth_areas.push(AuthArea {
+                path: String::from(path),
+                realm: String::from(realm),
+                credentials: vec![credentials],
+            }),
+        }
+    }
+
+    /// Check a request for `path` with the given `Authorization` header against the
+    /// areas set up with [Router::require_basic_auth].
+    ///
+    /// Returns the `WWW-Authenticate` header to answer with if the request needs
+    /// credentials it doesn't have, or `None` if it may go ahead.
+    ///
+    /// `path` is matched as it is, so it should be normalized the way
+    /// [RequestLine::parse](super::request::RequestLine::parse) does, or a path such as
+    /// `//admin` could get past an area at `/admin`.
+    pub fn auth_challenge(&self, path: &str, authorization: Option<&str>) -> Option<String> {
+        let area = self.auth_areas.iter().find(|area| area.covers(path))?;
+        (!area.allows(authorization)).then(|| area.challenge())
+    }
+
+    /// Limit how long connections are kept alive for, so one client can't hold a worker
+    /// forever.
+    ///
+    /// Replaces the [default](KeepAlive::default) limits, or any set before.
+    pub fn set_keep_alive(&mut self, keep_alive: KeepAlive) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// Get the limits on keeping connections alive, as set with
+    /// [Router::set_keep_alive].
+    pub fn keep_alive(&self) -> &KeepAlive {
+        &self.keep_alive
+    }
+
+    /// Limit the size of requests, and how slowly they may be sent.
+    ///
+    /// Replaces the [default](RequestLimits::default) limits, or any set before.
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
+
+    /// Get the limits on requests, as set with [Router::set_request_limits].
+    pub fn request_limits(&self) -> &RequestLimits {
+        &self.request_limits
+    }
+
+    /// Let pages from other origins make requests to the server from a browser, as
+    /// allowed by `cors`.
+    ///
+    /// Preflight requests are answered before anything else, even protected areas, since
+    /// browsers never send credentials with them. Every other response to an allowed
+    /// origin says so with `Access-Control-Allow-Origin`. Replaces any settings given
+    /// before.
+    pub fn set_cors(&mut self, cors: Cors) {
+        self.cors = Some(cors);
+    }
+
+    /// Get the CORS settings given to [Router::set_cors], if any.
+    pub fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref()
+    }
+
+    /// Get the methods with a route registered for a path, in alphabetical order.
+    ///
+    /// Returns an empty list if the path is unknown.
+    pub fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        self.routes
+            .get(path)
+            .map(|methods| methods.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::read_request;
+    use pretty_assertions::assert_eq;
+
+    fn test_router() -> Router {
+        let mut router = Router::new();
+        router.insert("GET", "/", "welcome.html");
+        router.insert("GET", "/about", "about.html");
+        router
+    }
+
+    #[test]
+    fn resolve_ok() {
+        let router = test_router();
+        assert_eq!(Some(Path::new("welcome.html")), router.resolve("GET", "/"));
+        assert_eq!(
+            Some(Path::new("about.html")),
+            router.resolve("GET", "/about")
+        );
+    }
+
+    #[test]
+    fn resolve_unknown_path() {
+        assert_eq!(None, test_router().resolve("GET", "/nope"));
+    }
+
+    #[test]
+    fn resolve_wrong_method() {
+        assert_eq!(None, test_router().resolve("POST", "/"));
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut router = test_router();
+        router.insert("GET", "/", "other.html");
+        assert_eq!(Some(Path::new("other.html")), router.resolve("GET", "/"));
+    }
+
+    #[test]
+    fn resolve_handler_ok() {
+        let mut router = test_router();
+        router.insert_handler("POST", "/", |_: &Request| Response::new(204));
+        let handler = router.resolve_handler("POST", "/").unwrap();
+        let request = read_request(
+            &mut "POST / HTTP/1.1\r\n\r\n".as_bytes(),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(204, handler.handle(&request).status);
+        // Files and handlers are told apart
+        assert_eq!(None, router.resolve("POST", "/"));
+        assert!(router.resolve_handler("GET", "/").is_none());
+        assert_eq!(vec!["GET", "POST"], router.allowed_methods("/"));
+    }
+
+    #[test]
+    fn not_found_unset() {
+        assert!(test_router().not_found().is_none());
+    }
+
+    #[test]
+    fn set_not_found_replaces() {
+        let mut router = test_router();
+        router.set_not_found(Box::new(|_| Response::new(404).body("first")));
+        router.set_not_found(Box::new(|_| Response::new(404).body("second")));
+        let request = read_request(
+            &mut "GET /nope HTTP/1.1\r\n\r\n".as_bytes(),
+            &Default::default(),
+        )
+        .unwrap();
+        let not_found = router.not_found().unwrap();
+        assert_eq!(b"second".to_vec(), not_found(&request).body);
+    }
+
+    #[test]
+    fn set_forbid_unindexed() {
+        let mut router = test_router();
+        assert!(!router.forbids_unindexed());
+        router.set_forbid_unindexed(true);
+        assert!(router.forbids_unindexed());
+    }
+
+    #[test]
+    fn add_middleware_in_order() {
+        struct Named(&'static str);
+        impl Middleware for Named {
+            fn after(&self, _request: &Request, response: &mut Response) {
+                response.body.extend_from_slice(self.0.as_bytes());
+            }
+        }
+        let mut router = test_router();
+        assert!(router.middleware().is_empty());
+        router.add_middleware(Arc::new(Named("a")));
+        router.add_middleware(Arc::new(Named("b")));
+
+        let request = read_request(
+            &mut "GET / HTTP/1.1\r\n\r\n".as_bytes(),
+            &Default::default(),
+        )
+        .unwrap();
+        let mut response = Response::ok();
+        for middleware in router.middleware() {
+            middleware.after(&request, &mut response);
+        }
+        assert_eq!(b"ab".to_vec(), response.body);
+    }
+
+    #[test]
+    fn require_basic_auth_users() {
+        let mut router = test_router();
+        router.require_basic_auth("/about", "About", "alice", "s3cret");
+        router.require_basic_auth("/about", "Ignored", "bob", "hunter2");
+        let challenge = Some(String::from("Basic realm=\"About\""));
+
+        assert_eq!(challenge, router.auth_challenge("/about", None));
+        assert_eq!(challenge, router.auth_challenge("/about/team", None));
+        // alice:s3cret, then bob:hunter2
+        assert_eq!(
+            None,
+            router.auth_challenge("/about", Some("Basic YWxpY2U6czNjcmV0"))
+        );
+        assert_eq!(
+            None,
+            router.auth_challenge("/about", Some("Basic Ym9iOmh1bnRlcjI="))
+        );
+        // alice:hunter2
+        assert_eq!(
+            challenge,
+            router.auth_challenge("/about", Some("Basic YWxpY2U6aHVudGVyMg=="))
+        );
+        assert_eq!(None, router.auth_challenge("/", None));
+    }
+
+    #[test]
+    fn set_keep_alive_replaces() {
+        let mut router = test_router();
+        assert_eq!(&KeepAlive::default(), router.keep_alive());
+        let keep_alive = KeepAlive {
+            max_requests: 3,
+            idle_timeout: Duration::from_millis(500),
+        };
+        router.set_keep_alive(keep_alive.clone());
+        assert_eq!(&keep_alive, router.keep_alive());
+    }
+
+    #[test]
+    fn set_request_limits_replaces() {
+        let mut router = test_router();
+        assert_eq!(&RequestLimits::default(), router.request_limits());
+        let limits = RequestLimits {
+            min_data_rate: None,
+            ..RequestLimits::default()
+        };
+        router.set_request_limits(limits.clone());
+        assert_eq!(&limits, router.request_limits());
+    }
+
+    #[test]
+    fn set_cors_replaces() {
+        let mut router = test_router();
+        assert_eq!(None, router.cors());
+        router.set_cors(Cors::new().allow_origin("https://a.example.com"));
+        let cors = Cors::new().allow_origin("https://b.example.com");
+        router.set_cors(cors.clone());
+        assert_eq!(Some(&cors), router.cors());
+    }
+
+    #[test]
+    fn allowed_methods_known_path() {
+        let mut router = test_router();
+        router.insert("POST", "/", "submit.html");
+        assert_eq!(vec!["GET", "POST"], router.allowed_methods("/"));
+        assert_eq!(vec!["GET"], router.allowed_methods("/about"));
+    }
+
+    #[test]
+    fn allowed_methods_unknown_path() {
+        assert!(test_router().allowed_methods("/nope").is_empty());
+    }
+}
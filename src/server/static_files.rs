@@ -0,0 +1,226 @@
+//! Serving files from a directory on disk.
+
+use std::path::{Path, PathBuf};
+
+// Served for URL paths naming a directory, e.g. `/docs/`
+const INDEX_FILE: &str = "index.html";
+
+/// Find the file under `root` which a URL path such as `/css/style.css` refers to.
+///
+/// A URL path ending in `/` refers to the `index.html` file in that directory.
+///
+/// Returns `None` if the file doesn't exist, isn't a regular file, or lies outside of
+/// `root` once `..` components and symlinks are resolved.
+pub fn resolve_static(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let path = resolve_under(root, url_path)?;
+    let path = if url_path.ends_with('/') && path.is_dir() {
+        path.join(INDEX_FILE)
+    } else {
+        path
+    };
+    path.is_file().then_some(path)
+}
+
+/// Check whether a URL path ending in `/` names a directory under `root` which has no
+/// `index.html` to serve in its place.
+pub fn is_unindexed_dir(root: &Path, url_path: &str) -> bool {
+    url_path.ends_with('/')
+        && resolve_under(root, url_path)
+            .is_some_and(|path| path.is_dir() && !path.join(INDEX_FILE).is_file())
+}
+
+// Resolve a URL path to whatever it names under root, if
+// anything
+fn resolve_under(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+
+    // Strip leading slashes, otherwise join() would treat the
+    // URL path as absolute and replace root entirely
+    let relative = url_path.trim_start_matches('/');
+    let path = root.join(relative).canonicalize().ok()?;
+
+    // canonicalize() resolved any "..", so anything still
+    // under root is safe to serve
+    path.starts_with(&root).then_some(path)
+}
+
+/// Get the MIME type to send in the `Content-Type` header for a file.
+///
+/// The type is picked from the file extension, ignoring case. Unknown or missing
+/// extensions get `application/octet-stream`.
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{env, fs};
+
+    // Make a fresh directory tree for one test:
+    // <dir>/secret.txt
+    // <dir>/public/index.html
+    // <dir>/public/css/style.css
+    // <dir>/public/docs/index.html
+    // <dir>/public/docs/guide/index.html
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "rust-tutorial-webserver-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("public/css")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        fs::write(dir.join("public/index.html"), "index").unwrap();
+        fs::write(dir.join("public/css/style.css"), "style").unwrap();
+        fs::create_dir_all(dir.join("public/docs/guide")).unwrap();
+        fs::write(dir.join("public/docs/index.html"), "docs").unwrap();
+        fs::write(dir.join("public/docs/guide/index.html"), "guide").unwrap();
+        dir
+    }
+
+    #[test]
+    fn content_type_known() {
+        assert_eq!(
+            "text/html; charset=utf-8",
+            content_type_for(Path::new("welcome.html"))
+        );
+        assert_eq!(
+            "text/css; charset=utf-8",
+            content_type_for(Path::new("css/style.css"))
+        );
+        assert_eq!(
+            "text/javascript; charset=utf-8",
+            content_type_for(Path::new("app.js"))
+        );
+        assert_eq!("application/json", content_type_for(Path::new("a.json")));
+        assert_eq!("image/png", content_type_for(Path::new("a.png")));
+        assert_eq!("image/jpeg", content_type_for(Path::new("a.jpg")));
+        assert_eq!("image/svg+xml", content_type_for(Path::new("a.svg")));
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            content_type_for(Path::new("a.txt"))
+        );
+    }
+
+    #[test]
+    fn content_type_uppercase() {
+        assert_eq!("image/png", content_type_for(Path::new("LOGO.PNG")));
+        assert_eq!(
+            "text/html; charset=utf-8",
+            content_type_for(Path::new("Index.Html"))
+        );
+    }
+
+    #[test]
+    fn content_type_unknown() {
+        assert_eq!(
+            "application/octet-stream",
+            content_type_for(Path::new("Makefile"))
+        );
+        assert_eq!(
+            "application/octet-stream",
+            content_type_for(Path::new("archive.xyz"))
+        );
+    }
+
+    #[test]
+    fn resolve_nested() {
+        let dir = test_dir("nested");
+        let root = dir.join("public");
+        assert_eq!(
+            Some(root.join("css/style.css").canonicalize().unwrap()),
+            resolve_static(&root, "/css/style.css")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_traversal() {
+        let dir = test_dir("traversal");
+        let root = dir.join("public");
+        assert_eq!(None, resolve_static(&root, "/../secret.txt"));
+        assert_eq!(None, resolve_static(&root, "/css/../../secret.txt"));
+        assert_eq!(None, resolve_static(&root, "/../../../../etc/passwd"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_absolute() {
+        let dir = test_dir("absolute");
+        let root = dir.join("public");
+        let secret = dir.join("secret.txt");
+        assert_eq!(None, resolve_static(&root, secret.to_str().unwrap()));
+        assert_eq!(None, resolve_static(&root, "//etc/passwd"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_missing() {
+        let dir = test_dir("missing");
+        let root = dir.join("public");
+        assert_eq!(None, resolve_static(&root, "/nope.html"));
+        // Directories aren't files
+        assert_eq!(None, resolve_static(&root, "/css"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_index() {
+        let dir = test_dir("index");
+        let root = dir.join("public");
+        assert_eq!(
+            Some(root.join("docs/index.html").canonicalize().unwrap()),
+            resolve_static(&root, "/docs/")
+        );
+        assert_eq!(
+            Some(root.join("index.html").canonicalize().unwrap()),
+            resolve_static(&root, "/")
+        );
+        // Only paths ending in a slash name the directory
+        assert_eq!(None, resolve_static(&root, "/docs"));
+        assert!(!is_unindexed_dir(&root, "/docs/"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_index_nested() {
+        let dir = test_dir("index-nested");
+        let root = dir.join("public");
+        assert_eq!(
+            Some(root.join("docs/guide/index.html").canonicalize().unwrap()),
+            resolve_static(&root, "/docs/guide/")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_index_missing() {
+        let dir = test_dir("index-missing");
+        let root = dir.join("public");
+        assert_eq!(None, resolve_static(&root, "/css/"));
+        assert!(is_unindexed_dir(&root, "/css/"));
+        // Neither missing directories nor ones outside root
+        // count
+        assert!(!is_unindexed_dir(&root, "/nope/"));
+        assert!(!is_unindexed_dir(&root, "/../"));
+        assert!(!is_unindexed_dir(&root, "/css"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
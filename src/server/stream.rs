@@ -0,0 +1,56 @@
+//! The connections handlers are given.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// A connection to a client, plain or encrypted.
+///
+/// [ConnectionHandler](super::ConnectionHandler)s are given a `Box<dyn Stream>`, so the
+/// same handler works for [serve](super::serve), `serve_unix` on Unix and, with the
+/// `tls` feature, `serve_tls`.
+pub trait Stream: Read + Write + Send {
+    /// The address of the client, for logging.
+    fn peer(&self) -> String;
+
+    /// Give up on a read after waiting `timeout` for data. `None` waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| String::from("-"), |addr| addr.to_string())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    // Clients' sockets are usually unnamed
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+            .unwrap_or_else(|| String::from("-"))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn peer(&self) -> String {
+        (**self).peer()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
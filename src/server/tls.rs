@@ -0,0 +1,174 @@
+//! Serving HTTPS, with rustls doing the encryption.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::{LogLevel, ThreadPool};
+
+use super::{
+    error::ServerError,
+    listen::{accept_loop, ConnectionHandler, ServeOptions},
+    stream::Stream,
+};
+
+/// Like [serve](super::serve), but speaking TLS with the certificate and settings in
+/// `config`.
+///
+/// The handshake happens on the worker, the first time the handler reads or writes, so a
+/// slow client can't hold up the accept loop. Handlers see the decrypted stream. When the
+/// handler's done with it, the client is told the connection is closing before the
+/// socket is.
+///
+/// Returns an error if `addr` can't be bound.
+///
+/// Only available with the `tls` feature.
+pub fn serve_tls(
+    addr: &str,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+    config: ServerConfig,
+) -> Result<(), ServerError> {
+    let listener = TcpListener::bind(addr)?;
+    serve_tls_listener(listener, pool, handler, stop, options, config)
+}
+
+/// Like [serve_tls], but accepting connections from a listener which is already bound.
+pub fn serve_tls_listener(
+    listener: TcpListener,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+    config: ServerConfig,
+) -> Result<(), ServerError> {
+    // Every connection shares the one config
+    let config = Arc::new(config);
+    let logger = Arc::clone(&pool.logger);
+    Ok(accept_loop(
+        listener,
+        pool,
+        stop,
+        options,
+        move |stream| match ServerConnection::new(Arc::clone(&config)) {
+            Ok(conn) => handler(Box::new(TlsStream(StreamOwned::new(conn, stream)))),
+            Err(e) => logger(LogLevel::Warn, &format!("Error setting up TLS: {e}")),
+        },
+    )?)
+}
+
+/// A TCP connection wrapped in TLS
+struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+impl Stream for TlsStream {
+    fn peer(&self) -> String {
+        self.0.sock.peer()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+}
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // Without a close_notify, the client can't tell the
+        // connection closing from it being cut off
+        self.0.conn.send_close_notify();
+        let _ = self.0.conn.complete_io(&mut self.0.sock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{default_router, handle_connection};
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore,
+    };
+    use std::{sync::atomic::Ordering, thread};
+
+    // Server and client configs which trust a freshly made
+    // self-signed certificate for localhost
+    fn configs() -> (ServerConfig, ClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert.der().to_vec());
+        let key_der =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (server, client)
+    }
+
+    #[test]
+    fn serve_tls_round_trip() {
+        let (server_config, client_config) = configs();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Arc::new(default_router());
+        let quiet: crate::Logger = Arc::new(|_, _| ());
+        let handler: ConnectionHandler = Arc::new(move |stream| {
+            handle_connection(stream, &router, &quiet).unwrap();
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            serve_tls_listener(
+                listener,
+                ThreadPool::new(2),
+                handler,
+                server_stop,
+                ServeOptions::new(),
+                server_config,
+            )
+        });
+
+        let conn = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut client = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        let expected = std::fs::read_to_string("welcome.html").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&expected));
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+}
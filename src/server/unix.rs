@@ -0,0 +1,186 @@
+//! Serving over Unix domain sockets.
+
+use std::{
+    fs, io,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use crate::ThreadPool;
+
+use super::{
+    error::ServerError,
+    listen::{accept_streams, Accepted, ConnectionHandler, ServeOptions},
+};
+
+/// Like [serve](super::serve), but listening on a Unix domain socket at `path` instead
+/// of a TCP address.
+///
+/// Useful for sidecars and reverse proxies on the same machine. A socket file left
+/// behind at `path` by a server which has since stopped is removed first. The socket
+/// file is removed again once the server stops. [ServeOptions::nodelay] has no effect.
+///
+/// Returns an error of kind [io::ErrorKind::AddrInUse] if another server is still
+/// listening at `path`, or any other error from binding.
+///
+/// Only available on Unix.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{io::Write, path::Path, sync::{atomic::AtomicBool, Arc}};
+/// use rust_tutorial_webserver::{server::{serve_unix, ServeOptions}, ThreadPool};
+/// let stop = Arc::new(AtomicBool::new(false));
+/// serve_unix(
+///     Path::new("/tmp/webserver.sock"),
+///     ThreadPool::new(4),
+///     Arc::new(|mut stream| {
+///         let _ = stream.write_all(b"HTTP/1.1 204 NO CONTENT\r\n\r\n");
+///     }),
+///     stop,
+///     ServeOptions::new(),
+/// )
+/// .unwrap();
+/// ```
+pub fn serve_unix(
+    path: &Path,
+    pool: ThreadPool,
+    handler: ConnectionHandler,
+    stop: Arc<AtomicBool>,
+    options: ServeOptions,
+) -> Result<(), ServerError> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    // A blocking accept() would never get round to checking
+    // the stop flag while no one is connecting
+    listener.set_nonblocking(true)?;
+    let served = accept_streams(
+        || listener.accept().map(|(stream, _)| stream),
+        pool,
+        stop,
+        options,
+        move |stream| handler(Box::new(stream)),
+    );
+    let _ = fs::remove_file(path);
+    Ok(served?)
+}
+
+// Remove a socket file left behind by a server which didn't
+// clean up after itself, so it can be bound again. Anything
+// which isn't a socket is left alone, and so is a socket which
+// is still being listened on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+impl Accepted for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{
+        env,
+        io::{Read, Write},
+        path::PathBuf,
+        sync::atomic::Ordering,
+        thread,
+        time::Instant,
+    };
+
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "rust-tutorial-webserver-{}-{name}.sock",
+            std::process::id()
+        ))
+    }
+
+    // Wait for a server to start listening at path
+    fn connect(path: &Path) -> UnixStream {
+        let start = Instant::now();
+        loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => return stream,
+                Err(e) => {
+                    assert!(start.elapsed() < Duration::from_secs(5), "{e}");
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serve_unix_responds() {
+        let path = socket_path("responds");
+        // Left over from a server which crashed
+        drop(UnixListener::bind(&path).unwrap());
+        let handler: ConnectionHandler = Arc::new(|mut stream| {
+            let _ = stream.write_all(b"HTTP/1.1 204 NO CONTENT\r\n\r\n");
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = Arc::clone(&stop);
+        let server_path = path.clone();
+        let server = thread::spawn(move || {
+            serve_unix(
+                &server_path,
+                ThreadPool::new(2),
+                handler,
+                server_stop,
+                ServeOptions::new(),
+            )
+        });
+
+        let mut client = connect(&path);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!("HTTP/1.1 204 NO CONTENT\r\n\r\n", response);
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_stale_socket_in_use() {
+        let path = socket_path("in-use");
+        let _ = fs::remove_file(&path);
+        let _listener = UnixListener::bind(&path).unwrap();
+        let e = remove_stale_socket(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn remove_stale_socket_not_socket() {
+        let path = socket_path("not-socket");
+        fs::write(&path, "keep me").unwrap();
+        remove_stale_socket(&path).unwrap();
+        assert_eq!("keep me", fs::read_to_string(&path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! A timer thread which holds on to delayed jobs until they're due.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::Job;
+
+/// A job waiting for its due time
+struct Delayed {
+    due: Instant,
+    // Order the job was scheduled in, so jobs due at the same
+    // instant are handed over first-in, first-out
+    seq: u64,
+    job: Job,
+    // Run on the timer thread rather than dispatched
+    inline: bool,
+}
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.due
+            .cmp(&other.due)
+            .then_with(|| self.seq.cmp(&other.seq))
+    }
+}
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Delayed {}
+
+#[derive(Default)]
+struct State {
+    // Reverse makes the max-heap hand out the earliest due job
+    heap: BinaryHeap<Reverse<Delayed>>,
+    next_seq: u64,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when a job is scheduled or the timer is closed
+    changed: Condvar,
+}
+
+/// A thread which passes each scheduled job to `dispatch` once it's due.
+pub(crate) struct Timer {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+impl Timer {
+    /// Spawn the timer thread, named `name`.
+    pub fn new<D>(name: String, dispatch: D) -> io::Result<Timer>
+    where
+        D: Fn(Job) + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name(name)
+            .spawn(move || Timer::run(&thread_shared, dispatch))?;
+        Ok(Timer {
+            shared,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Schedule `job` to be dispatched at `due`.
+    ///
+    /// Hands the job back if the timer has been shut down.
+    pub fn schedule(&self, due: Instant, job: Job) -> Result<(), Job> {
+        self.push(due, job, false)
+    }
+
+    /// Schedule `job` to be run on the timer thread itself at `due`, rather than
+    /// dispatched.
+    ///
+    /// Only for quick jobs, since every other job waits while it runs. Hands the job back
+    /// if the timer has been shut down.
+    pub fn schedule_inline(&self, due: Instant, job: Job) -> Result<(), Job> {
+        self.push(due, job, true)
+    }
+
+    fn push(&self, due: Instant, job: Job, inline: bool) -> Result<(), Job> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(job);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Reverse(Delayed {
+            due,
+            seq,
+            job,
+            inline,
+        }));
+        // The new job may be due before whatever the timer is
+        // currently sleeping until
+        self.shared.changed.notify_one();
+        Ok(())
+    }
+
+    /// Stop the timer thread, dropping any jobs which aren't due yet.
+    pub fn shutdown(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap();
+        }
+    }
+
+    fn run<D: Fn(Job)>(shared: &Shared, dispatch: D) {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.closed {
+                // Pending jobs are dropped along with the heap
+                state.heap.clear();
+                return;
+            }
+            let now = Instant::now();
+            match state.heap.peek() {
+                Some(Reverse(next)) if next.due <= now => {
+                    let Reverse(delayed) = state.heap.pop().unwrap();
+                    // Don't hold the lock while dispatching, which
+                    // can block on a full queue
+                    drop(state);
+                    if delayed.inline {
+                        (delayed.job)();
+                    } else {
+                        dispatch(delayed.job);
+                    }
+                    state = shared.state.lock().unwrap();
+                }
+                Some(Reverse(next)) => {
+                    let timeout = next.due - now;
+                    state = shared.changed.wait_timeout(state, timeout).unwrap().0;
+                }
+                None => {
+                    state = shared.changed.wait(state).unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{sync::mpsc, time::Duration};
+
+    fn send_job(tx: &mpsc::Sender<u8>, value: u8) -> Job {
+        let tx = tx.clone();
+        Box::new(move || tx.send(value).unwrap())
+    }
+
+    #[test]
+    fn dispatch_in_due_order() {
+        let timer = Timer::new(String::from("timer"), |job: Job| job()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let now = Instant::now();
+        timer
+            .schedule(now + Duration::from_millis(40), send_job(&tx, 2))
+            .ok()
+            .unwrap();
+        timer
+            .schedule(now + Duration::from_millis(20), send_job(&tx, 1))
+            .ok()
+            .unwrap();
+
+        let order: Vec<u8> = (0..2).map(|_| rx.recv().unwrap()).collect();
+        assert_eq!(vec![1, 2], order);
+    }
+
+    #[test]
+    fn inline_not_dispatched() {
+        let timer = Timer::new(String::from("timer"), |_: Job| panic!("dispatched")).unwrap();
+        let (tx, rx) = mpsc::channel();
+        timer
+            .schedule_inline(Instant::now(), send_job(&tx, 1))
+            .ok()
+            .unwrap();
+        assert_eq!(1, rx.recv().unwrap());
+        timer.shutdown();
+    }
+
+    #[test]
+    fn shutdown_drops_pending() {
+        let timer = Timer::new(String::from("timer"), |job: Job| job()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let due = Instant::now() + Duration::from_secs(3600);
+        timer.schedule(due, send_job(&tx, 1)).ok().unwrap();
+        drop(tx);
+
+        timer.shutdown();
+        // The pending job, and its sender, were dropped unrun
+        assert!(rx.recv().is_err());
+        assert!(timer.schedule(due, Box::new(|| ())).is_err());
+    }
+}
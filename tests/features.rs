@@ -0,0 +1,20 @@
+//! Checks that the crate builds with its optional features turned off.
+
+use std::{path::Path, process::Command};
+
+#[test]
+fn builds_without_default_features() {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    // A separate target directory, so this doesn't wait on the
+    // lock held by the build running this test
+    let target_dir = Path::new(manifest_dir)
+        .join("target")
+        .join("no-default-features");
+    let status = Command::new(env!("CARGO"))
+        .args(["check", "--lib", "--no-default-features", "--target-dir"])
+        .arg(target_dir)
+        .current_dir(manifest_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}